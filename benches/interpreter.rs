@@ -0,0 +1,89 @@
+// Compares decoding every opcode as it runs, the way the interpreter loop
+// used to work, with the cache of decoded instructions in Cpu and, on x86-64
+// Linux, with the JIT. Nothing is drawn to a window, this is the loop a
+// headless batch run spends its time in, run with
+// `cargo bench --bench interpreter`.
+extern crate rusty_chip8;
+
+use rusty_chip8::cpu::Cpu;
+use rusty_chip8::engine::{Engine, EngineKind};
+use rusty_chip8::instruction::Instruction;
+
+use std::time::{Duration, Instant};
+
+const CYCLES: u32 = 20_000_000;
+const CYCLES_PER_FRAME: u32 = 10_000; // how often the JIT hands back to its caller
+
+// A counting loop of arithmetic, BCD and register stores into memory, a skip
+// and jumps
+const ARITHMETIC: [u16; 12] = [
+    0xA300, 0x6000, 0x6100, 0x7001, 0x8104, 0x8206,
+    0xF233, 0xF155, 0x8120, 0x3000, 0x1206, 0x1202
+];
+
+// The same loop drawing a sprite every time round, where the time spent
+// drawing hides most of what decoding costs
+const DRAWING: [u16; 12] = [
+    0xA300, 0x6000, 0x6100, 0x7001, 0x8104, 0x8206,
+    0xF233, 0xF155, 0xD125, 0x3000, 0x1206, 0x1202
+];
+
+fn cpu(program: &[u16]) -> Cpu {
+    let mut cpu = Cpu::new();
+    for (index, opcode) in program.iter().enumerate() {
+        let address = 0x200 + index as u16 * 2;
+        cpu.set_memory(address, (opcode >> 8) as u8);
+        cpu.set_memory(address + 1, *opcode as u8);
+    }
+    cpu
+}
+
+// Fetching and decoding the opcode at PC every cycle
+fn bench_uncached(program: &[u16]) -> Duration {
+    let mut cpu = cpu(program);
+    let start = Instant::now();
+    for _ in 0..CYCLES {
+        let memory = cpu.get_memory();
+        let pc = cpu.get_pc() as usize;
+        let opcode = (memory[pc] as u16) << 8 | memory[pc + 1] as u16;
+        cpu.execute(Instruction::decode(opcode)).unwrap();
+    }
+    start.elapsed()
+}
+
+fn bench_cached(program: &[u16]) -> Duration {
+    let mut cpu = cpu(program);
+    let start = Instant::now();
+    for _ in 0..CYCLES {
+        cpu.emulate_cycle().unwrap();
+    }
+    start.elapsed()
+}
+
+fn bench_jit(program: &[u16]) -> Option<Duration> {
+    let mut engine = Engine::new(EngineKind::Jit).ok()?;
+    let mut cpu = cpu(program);
+    let start = Instant::now();
+    for _ in 0..CYCLES / CYCLES_PER_FRAME {
+        engine.run(&mut cpu, CYCLES_PER_FRAME).unwrap();
+    }
+    Some(start.elapsed())
+}
+
+fn report(name: &str, elapsed: Duration) {
+    let seconds = elapsed.as_secs_f64();
+    println!("{:<22} {:>8.3} s {:>8.1} million instructions/s", name, seconds, CYCLES as f64 / seconds / 1_000_000.0);
+}
+
+fn main() {
+    report("arithmetic, uncached", bench_uncached(&ARITHMETIC));
+    report("arithmetic, cached", bench_cached(&ARITHMETIC));
+    if let Some(elapsed) = bench_jit(&ARITHMETIC) {
+        report("arithmetic, jit", elapsed);
+    }
+    report("drawing, uncached", bench_uncached(&DRAWING));
+    report("drawing, cached", bench_cached(&DRAWING));
+    if let Some(elapsed) = bench_jit(&DRAWING) {
+        report("drawing, jit", elapsed);
+    }
+}
@@ -0,0 +1,82 @@
+// Compares the old per-pixel fill_rect renderer with the streaming texture
+// renderer in Display. Both use SDL's software renderer so the numbers are not
+// hidden behind a GPU, run with `cargo bench --bench render`.
+// On a machine without a display, SDL_VIDEODRIVER=dummy works too.
+extern crate rusty_chip8;
+extern crate sdl2;
+
+use rusty_chip8::cpu::DisplayData;
+use rusty_chip8::display::{Display, DisplayOptions};
+use rusty_chip8::{WIDTH, HEIGHT};
+
+use sdl2::pixels::Color;
+use sdl2::rect::Rect;
+
+use std::time::{Duration, Instant};
+
+const FRAMES: u32 = 600;
+const SCALE: u32 = 24;
+
+// A checkerboard that shifts every frame, so every pixel changes colour
+fn framebuffer(frame: u32) -> [[u8; WIDTH]; HEIGHT] {
+    let mut display = [[0u8; WIDTH]; HEIGHT];
+    for (y, row) in display.iter_mut().enumerate() {
+        for (x, pixel) in row.iter_mut().enumerate() {
+            *pixel = ((x + y + frame as usize) % 2) as u8;
+        }
+    }
+    display
+}
+
+// The renderer as it was before the streaming texture
+fn bench_fill_rect() -> Duration {
+    let sdl_context = sdl2::init().unwrap();
+    let video_subsystem = sdl_context.video().unwrap();
+    let window = video_subsystem.window("fill_rect", SCALE * WIDTH as u32, SCALE * HEIGHT as u32)
+        .build()
+        .unwrap();
+    let mut canvas = window.into_canvas().software().build().unwrap();
+    let start = Instant::now();
+    for frame in 0..FRAMES {
+        let display = framebuffer(frame);
+        for (y, row) in display.iter().enumerate() {
+            for (x, color) in row.iter().enumerate() {
+                if *color != 0 {
+                    canvas.set_draw_color(Color::RGB(255,255,255));
+                } else {
+                    canvas.set_draw_color(Color::RGB(0,0,0));
+                }
+                let rect = Rect::new((x as u32 * SCALE) as i32, (y as u32 * SCALE) as i32, SCALE, SCALE);
+                let _ = canvas.fill_rect(rect);
+            }
+        }
+        canvas.present();
+    }
+    start.elapsed()
+}
+
+fn bench_texture() -> Duration {
+    let mut options = DisplayOptions::new();
+    options.scale = SCALE;
+    options.vsync = false;
+    options.software = true;
+    let sdl_context = sdl2::init().unwrap();
+    let video_subsystem = sdl_context.video().unwrap();
+    let mut display = Display::new(&video_subsystem, &options);
+    let start = Instant::now();
+    for frame in 0..FRAMES {
+        let buffer = framebuffer(frame);
+        display.draw(DisplayData { display: &buffer });
+    }
+    start.elapsed()
+}
+
+fn report(name: &str, elapsed: Duration) {
+    let frame_time = elapsed.as_secs_f64() * 1000.0 / FRAMES as f64;
+    println!("{:<10} {:>8.3} ms/frame {:>8.1} fps", name, frame_time, 1000.0 / frame_time);
+}
+
+fn main() {
+    report("fill_rect", bench_fill_rect());
+    report("texture", bench_texture());
+}
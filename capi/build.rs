@@ -0,0 +1,141 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+
+/*
+ * Writes include/chip8.h from the declarations in src/lib.rs: the constants,
+ * the opaque Chip8 type and every extern "C" function, each with the comment
+ * above it. Only the handful of types the API uses are understood, anything
+ * else stops the build so the header can never fall behind.
+ */
+
+const PREAMBLE: &str = "\
+/* Generated by capi/build.rs from capi/src/lib.rs, do not edit */
+
+#ifndef CHIP8_H
+#define CHIP8_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern \"C\" {
+#endif
+";
+
+const POSTSCRIPT: &str = "
+#ifdef __cplusplus
+}
+#endif
+
+#endif
+";
+
+fn c_type(rust: &str) -> String {
+    let rust = rust.trim();
+    if let Some(pointee) = rust.strip_prefix("*const ") {
+        return format!("const {} *", c_type(pointee));
+    }
+    if let Some(pointee) = rust.strip_prefix("*mut ") {
+        return format!("{} *", c_type(pointee));
+    }
+    match rust {
+        "" => "void",
+        "bool" => "bool",
+        "c_char" => "char",
+        "u8" => "uint8_t",
+        "u32" => "uint32_t",
+        "u64" => "uint64_t",
+        "i32" => "int32_t",
+        "usize" => "size_t",
+        "Chip8" => "Chip8",
+        _ => panic!("build.rs does not know the C for {}", rust)
+    }.to_string()
+}
+
+// e.g. "const uint8_t *rom" or "size_t length"
+fn declaration(c_type: &str, name: &str) -> String {
+    let separator = if c_type.ends_with('*') { "" } else { " " };
+    format!("{}{}{}", c_type, separator, name)
+}
+
+// e.g. "fn chip8_set_key(chip8: *mut Chip8, key: u8, pressed: bool) -> i32 {"
+fn function(signature: &str) -> String {
+    let signature = &signature[signature.find("fn ").unwrap() + 3..signature.rfind('{').unwrap()];
+    let (name, rest) = signature.split_at(signature.find('(').unwrap());
+    let (arguments, returned) = rest.split_at(rest.rfind(')').unwrap());
+    let arguments: Vec<String> = arguments[1..].split(',')
+        .filter(|argument| !argument.trim().is_empty())
+        .map(|argument| {
+            let (name, rust) = argument.split_at(argument.find(':').unwrap());
+            declaration(&c_type(&rust[1..]), name.trim())
+        })
+        .collect();
+    let returned = c_type(returned[1..].trim().trim_start_matches("->"));
+    let arguments = if arguments.is_empty() { "void".to_string() } else { arguments.join(", ") };
+    format!("{}({});", declaration(&returned, name.trim()), arguments)
+}
+
+fn header(source: &str) -> String {
+    let mut header = PREAMBLE.to_string();
+    let mut comments = Vec::new();
+    let mut gap = true;
+    let mut lines = source.lines();
+    while let Some(line) = lines.next() {
+        let line = line.trim();
+        let item = if line.starts_with("//") {
+            comments.push(line.to_string());
+            continue;
+        } else if let Some(constant) = line.strip_prefix("pub const ") {
+            let (name, value) = constant.split_at(constant.find(':').unwrap());
+            let value = value[value.find('=').unwrap() + 1..].trim_end_matches(';').trim();
+            // negative numbers in brackets, so e.g. x-CHIP8_ERROR_NULL still subtracts
+            if value.starts_with('-') {
+                format!("#define {} ({})", name, value)
+            } else {
+                format!("#define {} {}", name, value)
+            }
+        } else if line == "pub struct Chip8 {" {
+            "typedef struct Chip8 Chip8;".to_string()
+        } else if line.starts_with("pub extern \"C\" fn") || line.starts_with("pub unsafe extern \"C\" fn") {
+            let mut signature = line.to_string();
+            while !signature.ends_with('{') {
+                signature = format!("{} {}", signature, lines.next().unwrap().trim());
+            }
+            function(&signature)
+        } else if line.starts_with("#[") {
+            continue;
+        } else {
+            // comments only go with the item right below them
+            gap = true;
+            comments.clear();
+            continue;
+        };
+        if gap || !comments.is_empty() {
+            header.push('\n');
+        }
+        for comment in comments.drain(..) {
+            header.push_str(&comment);
+            header.push('\n');
+        }
+        header.push_str(&item);
+        header.push('\n');
+        gap = false;
+    }
+    header.push_str(POSTSCRIPT);
+    header
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed=src/lib.rs");
+    let directory = Path::new(&env::var("CARGO_MANIFEST_DIR").unwrap()).to_path_buf();
+    let source = fs::read_to_string(directory.join("src/lib.rs")).unwrap();
+    let header = header(&source);
+    let path = directory.join("include/chip8.h");
+    // rewritten only when it changes, so C builds depending on it stay put
+    if fs::read_to_string(&path).ok().as_ref() != Some(&header) {
+        fs::create_dir_all(directory.join("include")).unwrap();
+        fs::write(&path, header).unwrap();
+    }
+}
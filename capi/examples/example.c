@@ -0,0 +1,82 @@
+/*
+ * Runs a few frames of a tiny built in ROM through the C API, prints the
+ * screen, checks a save state round trip and shows how errors come back.
+ *
+ *   cargo build -p rusty-chip8-capi
+ *   cc -I capi/include capi/examples/example.c -L target/debug -lchip8 -o example
+ *   LD_LIBRARY_PATH=target/debug ./example
+ */
+
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+#include "chip8.h"
+
+/* Draws the font's 5 in the middle of the screen and beeps for a second */
+static const uint8_t ROM[] = {
+    0x60, 0x05, /* V0 = 5 */
+    0xF0, 0x29, /* I = sprite of V0 */
+    0x61, 0x1E, /* V1 = 30 */
+    0x62, 0x0D, /* V2 = 13 */
+    0xD1, 0x25, /* draw 5 rows at V1, V2 */
+    0x63, 0x3C, /* V3 = 60 */
+    0xF3, 0x18, /* sound timer = V3 */
+    0x12, 0x0E  /* loop here */
+};
+
+static int check(Chip8 *chip8, int32_t code, const char *what) {
+    if (code != CHIP8_OK) {
+        const char *error = chip8_last_error(chip8);
+        printf("%s failed with %d: %s\n", what, code, error ? error : "no message");
+    }
+    return code == CHIP8_OK;
+}
+
+static void print_screen(const uint8_t *pixels, int first_row, int last_row) {
+    for (int y = first_row; y <= last_row; y++) {
+        for (int x = 0; x < CHIP8_WIDTH; x++) {
+            putchar(pixels[y * CHIP8_WIDTH + x] ? '#' : '.');
+        }
+        putchar('\n');
+    }
+}
+
+int main(void) {
+    Chip8 *chip8 = chip8_new();
+    if (!check(chip8, chip8_set_quirks(chip8, "vip"), "chip8_set_quirks")
+        || !check(chip8, chip8_set_seed(chip8, 1), "chip8_set_seed")
+        || !check(chip8, chip8_load_rom(chip8, ROM, sizeof ROM), "chip8_load_rom")
+        || !check(chip8, chip8_run_frame(chip8, 10), "chip8_run_frame")) {
+        return 1;
+    }
+    print_screen(chip8_framebuffer(chip8), 12, 18);
+    printf("sound %s\n", chip8_sound_active(chip8) ? "on" : "off");
+
+    size_t size = chip8_state_size();
+    uint8_t *state = malloc(size);
+    uint8_t before[CHIP8_WIDTH * CHIP8_HEIGHT];
+    memcpy(before, chip8_framebuffer(chip8), sizeof before);
+    if (!check(chip8, chip8_save_state(chip8, state, size), "chip8_save_state")) {
+        return 1;
+    }
+    for (int frame = 0; frame < 60; frame++) {
+        chip8_run_frame(chip8, 10);
+    }
+    printf("sound after a second %s\n", chip8_sound_active(chip8) ? "on" : "off");
+    if (!check(chip8, chip8_load_state(chip8, state, size), "chip8_load_state")) {
+        return 1;
+    }
+    int same = memcmp(before, chip8_framebuffer(chip8), sizeof before) == 0 && chip8_sound_active(chip8);
+    printf("save state %s\n", same ? "restored" : "differs");
+
+    /* 0000 is no instruction, the machine stops instead of taking the program down */
+    static const uint8_t BAD_ROM[] = { 0x00, 0x00 };
+    chip8_load_rom(chip8, BAD_ROM, sizeof BAD_ROM);
+    check(chip8, chip8_run_frame(chip8, 10), "running 0000");
+    printf("state of %zu bytes into 16: %d\n", size, chip8_save_state(chip8, state, 16));
+
+    free(state);
+    chip8_free(chip8);
+    return same ? 0 : 1;
+}
@@ -0,0 +1,92 @@
+/* Generated by capi/build.rs from capi/src/lib.rs, do not edit */
+
+#ifndef CHIP8_H
+#define CHIP8_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define CHIP8_OK 0
+
+// A handle, ROM, state or buffer pointer was null
+#define CHIP8_ERROR_NULL (-1)
+
+// The ROM does not fit in memory at 0x200
+#define CHIP8_ERROR_ROM_TOO_LARGE (-2)
+
+// The quirks preset is not one of modern, vip, schip or xochip
+#define CHIP8_ERROR_UNKNOWN_QUIRKS (-3)
+
+// The buffer is smaller than chip8_save_state needs
+#define CHIP8_ERROR_BUFFER_TOO_SMALL (-4)
+
+// The save state is damaged or from another format
+#define CHIP8_ERROR_BAD_STATE (-5)
+
+// The program stopped on an invalid opcode, or a return or call the stack
+// cannot take, until a ROM or state is loaded
+#define CHIP8_ERROR_HALTED (-6)
+
+// The framebuffer is a byte per pixel, 0 or 1, a row after another
+#define CHIP8_WIDTH 64
+#define CHIP8_HEIGHT 32
+#define CHIP8_KEY_COUNT 16
+
+// Opaque to C, made by chip8_new
+typedef struct Chip8 Chip8;
+
+// A new machine with modern quirks and nothing loaded, free it with chip8_free
+Chip8 *chip8_new(void);
+
+// Takes effect at once and stays for later ROMs
+int32_t chip8_set_quirks(Chip8 *chip8, const char *preset);
+
+// Makes the random numbers of CXKK repeatable, from now and for later ROMs
+int32_t chip8_set_seed(Chip8 *chip8, uint64_t seed);
+
+// Resets the machine and loads length bytes of ROM at 0x200, the quirks and
+// seed stay as they were set
+int32_t chip8_load_rom(Chip8 *chip8, const uint8_t *rom, size_t length);
+
+// Key is 0x0 to 0xF, any other bits are ignored
+int32_t chip8_set_key(Chip8 *chip8, uint8_t key, bool pressed);
+
+// Runs one 60th of a second: cycles instructions, e.g. 10 for 600 a second,
+// then a tick of the timers
+int32_t chip8_run_frame(Chip8 *chip8, uint32_t cycles);
+
+// CHIP8_WIDTH * CHIP8_HEIGHT bytes, valid until the machine runs again or is
+// freed, null for a null handle
+const uint8_t *chip8_framebuffer(const Chip8 *chip8);
+
+// Whether the buzzer should sound this frame
+bool chip8_sound_active(const Chip8 *chip8);
+
+// The size of a save state in bytes, the same for every machine
+size_t chip8_state_size(void);
+
+// Writes a save state of chip8_state_size() bytes into buffer, which can be
+// written to a file and read back on any platform
+int32_t chip8_save_state(const Chip8 *chip8, uint8_t *buffer, size_t length);
+
+// Puts the machine back as chip8_save_state found it, a halted one can run
+// again from there
+int32_t chip8_load_state(Chip8 *chip8, const uint8_t *state, size_t length);
+
+// What the last error on this handle was about, or null. Valid until the
+// next call with the handle.
+const char *chip8_last_error(const Chip8 *chip8);
+
+// Frees a machine from chip8_new, null is ignored
+void chip8_free(Chip8 *chip8);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
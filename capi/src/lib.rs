@@ -0,0 +1,245 @@
+// The safety rules are the same for every function, see below
+#![allow(clippy::missing_safety_doc)]
+
+extern crate rusty_chip8;
+
+use std::collections::hash_map::RandomState;
+use std::ffi::{CStr, CString};
+use std::hash::{BuildHasher, Hasher};
+use std::os::raw::c_char;
+use std::ptr;
+use std::slice;
+
+use rusty_chip8::cpu::{Cpu, STATE_SIZE};
+use rusty_chip8::quirks::Quirks;
+
+/*
+ * The C API, for the parts of the toolchain written in C or driven from
+ * Python through ctypes. A Chip8 is an opaque handle around a Cpu, built
+ * from the no_std core. Functions return one of the CHIP8_* codes and
+ * chip8_last_error says what went wrong. build.rs writes include/chip8.h from
+ * this file, so the header follows whatever is declared here.
+ *
+ * Handles must be null or come from chip8_new and not be freed yet, and
+ * may not be used from two threads at once. Buffers must hold at least the
+ * length passed with them, strings must end in a nul byte.
+ */
+
+pub const CHIP8_OK: i32 = 0;
+// A handle, ROM, state or buffer pointer was null
+pub const CHIP8_ERROR_NULL: i32 = -1;
+// The ROM does not fit in memory at 0x200
+pub const CHIP8_ERROR_ROM_TOO_LARGE: i32 = -2;
+// The quirks preset is not one of modern, vip, schip or xochip
+pub const CHIP8_ERROR_UNKNOWN_QUIRKS: i32 = -3;
+// The buffer is smaller than chip8_save_state needs
+pub const CHIP8_ERROR_BUFFER_TOO_SMALL: i32 = -4;
+// The save state is damaged or from another format
+pub const CHIP8_ERROR_BAD_STATE: i32 = -5;
+// The program stopped on an invalid opcode, or a return or call the stack
+// cannot take, until a ROM or state is loaded
+pub const CHIP8_ERROR_HALTED: i32 = -6;
+
+// The framebuffer is a byte per pixel, 0 or 1, a row after another
+pub const CHIP8_WIDTH: i32 = 64;
+pub const CHIP8_HEIGHT: i32 = 32;
+pub const CHIP8_KEY_COUNT: i32 = 16;
+
+const _: () = assert!(CHIP8_WIDTH as usize == rusty_chip8::WIDTH && CHIP8_HEIGHT as usize == rusty_chip8::HEIGHT
+    && CHIP8_KEY_COUNT as usize == rusty_chip8::KEYPAD_SIZE);
+
+// Opaque to C, made by chip8_new
+pub struct Chip8 {
+    cpu: Cpu,
+    seed: Option<u64>, // reapplied when a ROM is loaded
+    error: Option<CString>, // for chip8_last_error
+    halted: bool
+}
+
+impl Chip8 {
+    fn fail(&mut self, code: i32, message: String) -> i32 {
+        self.error = CString::new(message).ok();
+        code
+    }
+}
+
+// The core has no std to seed CXKK from, so unless chip8_set_seed fixed a
+// seed each ROM gets one from the hash keys std picks at random
+fn random_seed() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+// A new machine with modern quirks and nothing loaded, free it with chip8_free
+#[no_mangle]
+pub extern "C" fn chip8_new() -> *mut Chip8 {
+    let mut cpu = Cpu::new();
+    cpu.set_seed(random_seed());
+    Box::into_raw(Box::new(Chip8 {
+        cpu: cpu,
+        seed: None,
+        error: None,
+        halted: false
+    }))
+}
+
+// Takes effect at once and stays for later ROMs
+#[no_mangle]
+pub unsafe extern "C" fn chip8_set_quirks(chip8: *mut Chip8, preset: *const c_char) -> i32 {
+    let chip8 = match chip8.as_mut() {
+        Some(chip8) => chip8,
+        None => return CHIP8_ERROR_NULL
+    };
+    if preset.is_null() {
+        return chip8.fail(CHIP8_ERROR_NULL, "no quirks preset given".to_string());
+    }
+    let name = CStr::from_ptr(preset).to_string_lossy();
+    match Quirks::from_preset(&name) {
+        Some(quirks) => {
+            chip8.cpu.set_quirks(quirks);
+            CHIP8_OK
+        }
+        None => chip8.fail(CHIP8_ERROR_UNKNOWN_QUIRKS, format!("unknown quirks preset '{}'", name))
+    }
+}
+
+// Makes the random numbers of CXKK repeatable, from now and for later ROMs
+#[no_mangle]
+pub unsafe extern "C" fn chip8_set_seed(chip8: *mut Chip8, seed: u64) -> i32 {
+    let chip8 = match chip8.as_mut() {
+        Some(chip8) => chip8,
+        None => return CHIP8_ERROR_NULL
+    };
+    chip8.seed = Some(seed);
+    chip8.cpu.set_seed(seed);
+    CHIP8_OK
+}
+
+// Resets the machine and loads length bytes of ROM at 0x200, the quirks and
+// seed stay as they were set
+#[no_mangle]
+pub unsafe extern "C" fn chip8_load_rom(chip8: *mut Chip8, rom: *const u8, length: usize) -> i32 {
+    let chip8 = match chip8.as_mut() {
+        Some(chip8) => chip8,
+        None => return CHIP8_ERROR_NULL
+    };
+    if rom.is_null() {
+        return chip8.fail(CHIP8_ERROR_NULL, "no ROM given".to_string());
+    }
+    let mut cpu = Cpu::new();
+    cpu.set_quirks(chip8.cpu.get_quirks());
+    cpu.set_seed(chip8.seed.unwrap_or_else(random_seed));
+    if let Err(error) = cpu.load_rom_at(slice::from_raw_parts(rom, length), rusty_chip8::INITIAL_PC) {
+        return chip8.fail(CHIP8_ERROR_ROM_TOO_LARGE, error.to_string());
+    }
+    chip8.cpu = cpu;
+    chip8.halted = false;
+    CHIP8_OK
+}
+
+// Key is 0x0 to 0xF, any other bits are ignored
+#[no_mangle]
+pub unsafe extern "C" fn chip8_set_key(chip8: *mut Chip8, key: u8, pressed: bool) -> i32 {
+    let chip8 = match chip8.as_mut() {
+        Some(chip8) => chip8,
+        None => return CHIP8_ERROR_NULL
+    };
+    chip8.cpu.set_key(key, pressed);
+    CHIP8_OK
+}
+
+// Runs one 60th of a second: cycles instructions, e.g. 10 for 600 a second,
+// then a tick of the timers
+#[no_mangle]
+pub unsafe extern "C" fn chip8_run_frame(chip8: *mut Chip8, cycles: u32) -> i32 {
+    let chip8 = match chip8.as_mut() {
+        Some(chip8) => chip8,
+        None => return CHIP8_ERROR_NULL
+    };
+    if chip8.halted {
+        return CHIP8_ERROR_HALTED;
+    }
+    for _ in 0..cycles {
+        if let Err(error) = chip8.cpu.emulate_cycle() {
+            chip8.halted = true;
+            return chip8.fail(CHIP8_ERROR_HALTED, error.to_string());
+        }
+    }
+    chip8.cpu.update_timer();
+    CHIP8_OK
+}
+
+// CHIP8_WIDTH * CHIP8_HEIGHT bytes, valid until the machine runs again or is
+// freed, null for a null handle
+#[no_mangle]
+pub unsafe extern "C" fn chip8_framebuffer(chip8: *const Chip8) -> *const u8 {
+    match chip8.as_ref() {
+        Some(chip8) => chip8.cpu.get_display().display.as_ptr() as *const u8,
+        None => ptr::null()
+    }
+}
+
+// Whether the buzzer should sound this frame
+#[no_mangle]
+pub unsafe extern "C" fn chip8_sound_active(chip8: *const Chip8) -> bool {
+    chip8.as_ref().is_some_and(|chip8| chip8.cpu.is_sound_active())
+}
+
+// The size of a save state in bytes, the same for every machine
+#[no_mangle]
+pub extern "C" fn chip8_state_size() -> usize {
+    STATE_SIZE
+}
+
+// Writes a save state of chip8_state_size() bytes into buffer, which can be
+// written to a file and read back on any platform
+#[no_mangle]
+pub unsafe extern "C" fn chip8_save_state(chip8: *const Chip8, buffer: *mut u8, length: usize) -> i32 {
+    let chip8 = match chip8.as_ref() {
+        Some(chip8) => chip8,
+        None => return CHIP8_ERROR_NULL
+    };
+    if buffer.is_null() {
+        return CHIP8_ERROR_NULL;
+    }
+    if length < STATE_SIZE {
+        return CHIP8_ERROR_BUFFER_TOO_SMALL;
+    }
+    slice::from_raw_parts_mut(buffer, STATE_SIZE).copy_from_slice(&chip8.cpu.save_state());
+    CHIP8_OK
+}
+
+// Puts the machine back as chip8_save_state found it, a halted one can run
+// again from there
+#[no_mangle]
+pub unsafe extern "C" fn chip8_load_state(chip8: *mut Chip8, state: *const u8, length: usize) -> i32 {
+    let chip8 = match chip8.as_mut() {
+        Some(chip8) => chip8,
+        None => return CHIP8_ERROR_NULL
+    };
+    if state.is_null() {
+        return chip8.fail(CHIP8_ERROR_NULL, "no save state given".to_string());
+    }
+    if let Err(error) = chip8.cpu.load_state(slice::from_raw_parts(state, length)) {
+        return chip8.fail(CHIP8_ERROR_BAD_STATE, error.to_string());
+    }
+    chip8.halted = false;
+    CHIP8_OK
+}
+
+// What the last error on this handle was about, or null. Valid until the
+// next call with the handle.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_last_error(chip8: *const Chip8) -> *const c_char {
+    match chip8.as_ref().and_then(|chip8| chip8.error.as_ref()) {
+        Some(error) => error.as_ptr(),
+        None => ptr::null()
+    }
+}
+
+// Frees a machine from chip8_new, null is ignored
+#[no_mangle]
+pub unsafe extern "C" fn chip8_free(chip8: *mut Chip8) {
+    if !chip8.is_null() {
+        drop(Box::from_raw(chip8));
+    }
+}
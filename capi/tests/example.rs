@@ -0,0 +1,56 @@
+use std::env;
+use std::path::Path;
+use std::process::Command;
+
+// Builds examples/example.c against the library and the generated header
+// with the system C compiler ($CC or cc), then runs it
+#[cfg(unix)]
+#[test]
+fn c_example() {
+    let manifest = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let scratch = Path::new(env!("CARGO_TARGET_TMPDIR"));
+    // cargo test does not build the cdylib, so build it here, in a target
+    // directory of its own to stay clear of the one cargo test is using
+    let build = Command::new(env!("CARGO"))
+        .arg("build")
+        .arg("--manifest-path").arg(manifest.join("Cargo.toml"))
+        .arg("--target-dir").arg(scratch.join("target"))
+        .output()
+        .unwrap_or_else(|error| panic!("could not run cargo: {}", error));
+    assert!(build.status.success(), "cargo could not build the library:\n{}", String::from_utf8_lossy(&build.stderr));
+    let library_directory = scratch.join("target/debug");
+    let example = scratch.join("example");
+    let compiler = env::var("CC").unwrap_or_else(|_| "cc".to_string());
+    let status = Command::new(&compiler)
+        .arg("-std=c99")
+        .arg("-Wall")
+        .arg("-Werror")
+        .arg("-I").arg(manifest.join("include"))
+        .arg(manifest.join("examples/example.c"))
+        .arg("-L").arg(&library_directory)
+        .arg("-lchip8")
+        .arg(format!("-Wl,-rpath,{}", library_directory.display()))
+        .arg("-o").arg(&example)
+        .status()
+        .unwrap_or_else(|error| panic!("could not run {}: {}", compiler, error));
+    assert!(status.success(), "{} could not build the example", compiler);
+
+    let output = Command::new(&example).output().unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "the example failed:\n{}", stdout);
+    let expected = "\
+................................................................
+..............................####..............................
+..............................#.................................
+..............................####..............................
+.................................#..............................
+..............................####..............................
+................................................................
+sound on
+sound after a second off
+save state restored
+running 0000 failed with -6: invalid opcode 0000 at 0x200
+";
+    assert!(stdout.starts_with(expected), "unexpected output:\n{}", stdout);
+    assert!(stdout.contains("into 16: -4"), "unexpected output:\n{}", stdout);
+}
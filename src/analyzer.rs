@@ -0,0 +1,367 @@
+use std::collections::HashSet;
+
+use super::config::Settings;
+
+/*
+ * Guesses which interpreter a ROM was written for when the ROM database does
+ * not know it. The code is traced from the entry point, following jumps,
+ * calls and skips, so sprite data is not mistaken for instructions. What the
+ * traced code uses decides the platform:
+ *
+ *   - XO-CHIP only: F000 NNNN, FN01, F002, FX3A, 5XY2, 5XY3, 00DN
+ *   - SUPER-CHIP (and XO-CHIP): 00CN, 00FB to 00FF, DXY0, FX30, FX75, FX85
+ *
+ * Plain CHIP-8 programs can only be told apart by how they use the quirky
+ * instructions, e.g. 8XY6 with X and Y different only makes sense when VY is
+ * shifted, and storing registers twice in a row without setting I again
+ * relies on FX55 moving I along.
+ *
+ * The cpu only runs CHIP-8 instructions, so for the other platforms the
+ * analysis says the ROM will stop at the first one it reaches, and the quirks
+ * are still picked from the CHIP-8 signs alone.
+ */
+
+// Which CHIP-8 variant a ROM was written for
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Platform {
+    Chip8,
+    SuperChip,
+    XoChip
+}
+
+impl Platform {
+    pub fn from_name(name: &str) -> Option<Platform> {
+        match name.to_lowercase().as_str() {
+            "chip8" | "chip-8" => Some(Platform::Chip8),
+            "schip" | "superchip" => Some(Platform::SuperChip),
+            "xochip" | "xo-chip" => Some(Platform::XoChip),
+            _ => None
+        }
+    }
+
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            Platform::Chip8 => "CHIP-8",
+            Platform::SuperChip => "SCHIP",
+            Platform::XoChip => "XO-CHIP"
+        }
+    }
+
+    // Whether the cpu implements the platform's instructions
+    pub fn is_supported(&self) -> bool {
+        *self == Platform::Chip8
+    }
+}
+
+pub struct Analysis {
+    pub platform: Platform,
+    pub quirks: &'static str, // a preset for Quirks::from_preset, always one for CHIP-8
+    pub confidence: u32, // in percent, of the platform when it is not CHIP-8
+    pub reasons: Vec<String>
+}
+
+impl Analysis {
+    // The recommended quirks as a settings layer
+    pub fn settings(&self) -> Settings {
+        Settings {
+            quirks: Some(self.quirks.to_string()),
+            ..Settings::default()
+        }
+    }
+
+    pub fn summary(&self) -> String {
+        if self.platform.is_supported() {
+            format!("looks like {} with {} quirks ({}% sure)", self.platform.get_name(), self.quirks, self.confidence)
+        } else {
+            format!("looks like {} ({}% sure), which is not supported, trying CHIP-8 with {} quirks",
+                    self.platform.get_name(), self.confidence, self.quirks)
+        }
+    }
+}
+
+fn word(rom: &[u8], offset: usize) -> Option<u16> {
+    if offset + 1 < rom.len() {
+        Some((rom[offset] as u16) << 8 | rom[offset + 1] as u16)
+    } else {
+        None
+    }
+}
+
+// F000 NNNN is the only instruction taking four bytes
+fn length(rom: &[u8], offset: usize) -> usize {
+    if word(rom, offset) == Some(0xF000) { 4 } else { 2 }
+}
+
+fn is_skip(opcode: u16) -> bool {
+    match opcode >> 12 {
+        0x3 | 0x4 | 0x5 | 0x9 => true,
+        0xE => opcode & 0x00FF == 0x9E || opcode & 0x00FF == 0xA1,
+        _ => false
+    }
+}
+
+// Offsets into the ROM where traced instructions start, plus whether a BNNN
+// jump was found that could lead to code the trace never saw
+fn trace(rom: &[u8], load_address: u16) -> (Vec<bool>, bool) {
+    let mut visited = vec![false; rom.len()];
+    let mut indirect = false;
+    let mut pending = vec![0];
+    let to_offset = |address: u16| (address as usize).checked_sub(load_address as usize);
+    while let Some(mut offset) = pending.pop() {
+        while let Some(opcode) = word(rom, offset) {
+            if visited[offset] {
+                break;
+            }
+            visited[offset] = true;
+            let nnn = opcode & 0x0FFF;
+            match opcode >> 12 {
+                0x0 if opcode == 0x00EE || opcode == 0x00FD => break,
+                0x1 => {
+                    pending.extend(to_offset(nnn));
+                    break;
+                }
+                0x2 => pending.extend(to_offset(nnn)),
+                0xB => {
+                    indirect = true;
+                    break;
+                }
+                _ if is_skip(opcode) => {
+                    let next = offset + 2;
+                    pending.push(next + length(rom, next));
+                }
+                _ => {}
+            }
+            offset += length(rom, offset);
+        }
+    }
+    (visited, indirect)
+}
+
+// Instructions that only exist on the extended interpreters
+fn platform_feature(opcode: u16) -> Option<(Platform, &'static str)> {
+    let (x, n) = ((opcode >> 8) & 0xF, opcode & 0xF);
+    match (opcode >> 12, opcode & 0x00FF) {
+        (0x0, _) if opcode & 0xFFF0 == 0x00C0 && n != 0 => Some((Platform::SuperChip, "00CN scroll down")),
+        (0x0, _) if opcode & 0xFFF0 == 0x00D0 && n != 0 => Some((Platform::XoChip, "00DN scroll up")),
+        (0x0, _) if opcode == 0x00FB || opcode == 0x00FC => Some((Platform::SuperChip, "00FB/00FC scroll sideways")),
+        (0x0, _) if opcode == 0x00FD => Some((Platform::SuperChip, "00FD exit")),
+        (0x0, _) if opcode == 0x00FE || opcode == 0x00FF => Some((Platform::SuperChip, "00FE/00FF resolution switch")),
+        (0x5, _) if n == 0x2 || n == 0x3 => Some((Platform::XoChip, "5XY2/5XY3 register ranges")),
+        (0xD, _) if n == 0 => Some((Platform::SuperChip, "DXY0 16x16 sprite")),
+        (0xF, 0x00) if x == 0 => Some((Platform::XoChip, "F000 long I load")),
+        (0xF, 0x01) => Some((Platform::XoChip, "FN01 plane select")),
+        (0xF, 0x02) if x == 0 => Some((Platform::XoChip, "F002 audio pattern")),
+        (0xF, 0x3A) => Some((Platform::XoChip, "FX3A pitch")),
+        (0xF, 0x30) => Some((Platform::SuperChip, "FX30 big font")),
+        (0xF, 0x75) | (0xF, 0x85) => Some((Platform::SuperChip, "FX75/FX85 flag registers")),
+        _ => None
+    }
+}
+
+// What the code after an FX55/FX65 does with I: Some(true) when it stores or
+// loads again straight away, Some(false) when it moves I itself
+fn uses_incremented_i(rom: &[u8], load_address: u16, start: usize) -> Option<bool> {
+    let mut offset = start + 2;
+    for _ in 0..8 {
+        let opcode = word(rom, offset)?;
+        match (opcode >> 12, opcode & 0x00FF) {
+            (0xA, _) => return None,
+            (0xF, 0x1E) => return Some(false),
+            (0xF, 0x55) | (0xF, 0x65) => return Some(true),
+            (0x1, _) => offset = (opcode & 0x0FFF).checked_sub(load_address)? as usize,
+            (0x0, 0xEE) | (0x2, _) | (0xB, _) => return None,
+            _ => offset += length(rom, offset)
+        }
+    }
+    None
+}
+
+// The ROM as it would be loaded at load_address
+pub fn analyze(rom: &[u8], load_address: u16) -> Analysis {
+    let mut reasons = Vec::new();
+    if rom.len() > super::RAM_SIZE.saturating_sub(load_address as usize) {
+        reasons.push(format!("{} bytes only fit in XO-CHIP's 64K of memory", rom.len()));
+        reasons.push("XO-CHIP is not supported".to_string());
+        return Analysis { platform: Platform::XoChip, quirks: "modern", confidence: 80, reasons: reasons };
+    }
+
+    let (visited, indirect) = trace(rom, load_address);
+    let mut seen = HashSet::new();
+    let mut traced_platform = Platform::Chip8;
+    let mut traced_features = 0;
+    let mut untraced_platform = Platform::Chip8;
+    let (mut shift_vy, mut increments_i, mut keeps_i, mut jumps) = (0, 0, 0, 0);
+    for offset in 0..rom.len() {
+        let opcode = match word(rom, offset) {
+            Some(opcode) => opcode,
+            None => break
+        };
+        let address = offset + load_address as usize;
+        if !visited[offset] {
+            // data, or code the trace missed when there are BNNN jumps, only worth a weak hint
+            if offset % 2 == 0 {
+                if let Some((platform, _)) = platform_feature(opcode) {
+                    untraced_platform = untraced_platform.max(platform);
+                }
+            }
+            continue;
+        }
+        if let Some((platform, name)) = platform_feature(opcode) {
+            traced_platform = traced_platform.max(platform);
+            if seen.insert(name) {
+                traced_features += 1;
+                reasons.push(format!("uses {} at {:#05x}", name, address));
+            }
+        }
+        let (x, y) = ((opcode >> 8) & 0xF, (opcode >> 4) & 0xF);
+        match (opcode >> 12, opcode & 0x000F, opcode & 0x00FF) {
+            (0x8, 0x6, _) | (0x8, 0xE, _) if x != y => shift_vy += 1,
+            (0xF, _, 0x55) | (0xF, _, 0x65) => match uses_incremented_i(rom, load_address, offset) {
+                Some(true) => increments_i += 1,
+                Some(false) => keeps_i += 1,
+                None => {}
+            },
+            (0xB, _, _) => jumps += 1,
+            _ => {}
+        }
+    }
+
+    if shift_vy > 0 {
+        reasons.push(format!("{} shifts with X and Y different, as if VY is shifted", shift_vy));
+    }
+    if increments_i > 0 {
+        reasons.push(format!("{} register stores/loads followed by another without setting I", increments_i));
+    }
+    if keeps_i > 0 {
+        reasons.push(format!("{} register stores/loads followed by moving I with FX1E", keeps_i));
+    }
+    if jumps > 0 {
+        reasons.push(format!("{} BNNN jumps, V0 or VX is used depending on the interpreter", jumps));
+    }
+    if indirect {
+        reasons.push("BNNN jumps hide some code from the trace".to_string());
+    }
+
+    let (platform, confidence) = if traced_platform != Platform::Chip8 {
+        (traced_platform, if traced_features > 1 { 90 } else { 75 })
+    } else if indirect && untraced_platform != Platform::Chip8 {
+        reasons.push(format!("{} instructions outside the traced code", untraced_platform.get_name()));
+        (untraced_platform, 55)
+    } else {
+        (Platform::Chip8, 0)
+    };
+    if !platform.is_supported() {
+        reasons.push(format!("{} is not supported, the ROM stops at the first instruction only it has", platform.get_name()));
+    }
+    // the quirks come from the CHIP-8 signs whatever the platform, the cpu
+    // runs nothing else
+    let (quirks, quirks_confidence) = if shift_vy + increments_i > keeps_i {
+        ("vip", (60 + 10 * (shift_vy + increments_i - keeps_i)).min(85))
+    } else if keeps_i > 0 {
+        ("modern", 70)
+    } else {
+        if platform.is_supported() {
+            reasons.push("nothing points to a particular interpreter".to_string());
+        }
+        ("modern", 50)
+    };
+    let confidence = if platform.is_supported() { quirks_confidence } else { confidence };
+    Analysis {
+        platform: platform,
+        quirks: quirks,
+        confidence: confidence,
+        reasons: reasons
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn program(opcodes: &[u16]) -> Vec<u8> {
+        opcodes.iter().flat_map(|opcode| vec![(opcode >> 8) as u8, *opcode as u8]).collect()
+    }
+
+    fn analyzed(opcodes: &[u16]) -> Analysis {
+        analyze(&program(opcodes), super::super::INITIAL_PC)
+    }
+
+    #[test]
+    fn plain_chip8() {
+        let analysis = analyzed(&[0x6001, 0x1202]);
+        assert_eq!((analysis.platform, analysis.quirks, analysis.confidence), (Platform::Chip8, "modern", 50));
+        assert_eq!(analysis.reasons, vec!["nothing points to a particular interpreter"]);
+        assert_eq!(analysis.summary(), "looks like CHIP-8 with modern quirks (50% sure)");
+    }
+
+    #[test]
+    fn shifts_vy() {
+        let analysis = analyzed(&[0x8016, 0x1202]);
+        assert_eq!((analysis.platform, analysis.quirks, analysis.confidence), (Platform::Chip8, "vip", 70));
+        // the same register on both sides says nothing
+        assert_eq!(analyzed(&[0x8006, 0x1202]).quirks, "modern");
+    }
+
+    #[test]
+    fn store_and_load_move_i() {
+        // stores twice, relying on I having moved past V2
+        let analysis = analyzed(&[0xA300, 0xF255, 0xF255, 0x1206]);
+        assert_eq!((analysis.quirks, analysis.confidence), ("vip", 70));
+        // moves I itself, so it must stay put
+        let analysis = analyzed(&[0xA300, 0xF255, 0xF31E, 0xF255, 0x1208]);
+        assert_eq!((analysis.quirks, analysis.confidence), ("modern", 70));
+        // setting I again in between says nothing
+        assert_eq!(analyzed(&[0xA300, 0xF255, 0xA310, 0xF255, 0x1208]).confidence, 50);
+    }
+
+    #[test]
+    fn jumps_with_offset() {
+        let analysis = analyzed(&[0xB300]);
+        assert_eq!(analysis.platform, Platform::Chip8);
+        assert!(analysis.reasons.iter().any(|reason| reason.contains("1 BNNN jumps")));
+        assert!(analysis.reasons.iter().any(|reason| reason.contains("hide some code")));
+        // what the trace could not reach counts, but only weakly
+        let analysis = analyzed(&[0xB300, 0x00FF]);
+        assert_eq!((analysis.platform, analysis.confidence), (Platform::SuperChip, 55));
+    }
+
+    #[test]
+    fn untraced_data() {
+        // after a jump to itself, 00FF can only be data
+        let analysis = analyzed(&[0x1200, 0x00FF]);
+        assert_eq!(analysis.platform, Platform::Chip8);
+    }
+
+    #[test]
+    fn superchip_not_supported() {
+        let analysis = analyzed(&[0x00FF, 0x1202]);
+        assert_eq!((analysis.platform, analysis.quirks, analysis.confidence), (Platform::SuperChip, "modern", 75));
+        assert_eq!(analysis.reasons[0], "uses 00FE/00FF resolution switch at 0x200");
+        assert_eq!(analysis.summary(), "looks like SCHIP (75% sure), which is not supported, trying CHIP-8 with modern quirks");
+        assert_eq!(analysis.settings().quirks, Some("modern".to_string()));
+        // more features make it surer, the quirks still come from the CHIP-8 signs
+        let analysis = analyzed(&[0x00FF, 0xF030, 0x8016, 0x1206]);
+        assert_eq!((analysis.platform, analysis.quirks, analysis.confidence), (Platform::SuperChip, "vip", 90));
+    }
+
+    #[test]
+    fn xochip_not_supported() {
+        // F000 NNNN takes four bytes, so 0300 is not traced as an instruction
+        let analysis = analyzed(&[0xF000, 0x0300, 0x1204]);
+        assert_eq!((analysis.platform, analysis.quirks), (Platform::XoChip, "modern"));
+        assert!(!analysis.platform.is_supported());
+        let analysis = analyze(&[0; 0x1000 - 0x200 + 1], super::super::INITIAL_PC);
+        assert_eq!(analysis.platform, Platform::XoChip);
+    }
+
+    #[test]
+    fn load_address() {
+        // at 0x300 the jump skips the 00FF data and reaches the shift
+        let rom = program(&[0x1304, 0x00FF, 0x8016, 0x1306]);
+        let analysis = analyze(&rom, 0x300);
+        assert_eq!((analysis.platform, analysis.quirks), (Platform::Chip8, "vip"));
+        // at 0x200 the jump leaves the ROM straight away
+        let analysis = analyze(&rom, 0x200);
+        assert_eq!((analysis.platform, analysis.quirks), (Platform::Chip8, "modern"));
+    }
+}
@@ -0,0 +1,59 @@
+extern crate sdl2;
+
+use sdl2::AudioSubsystem;
+use sdl2::audio::{AudioCallback, AudioDevice, AudioSpecDesired};
+
+const FREQUENCY: i32 = 44100;
+const TONE: f32 = 440.0;
+const VOLUME: f32 = 0.15;
+
+// The CHIP-8 buzzer is a single tone, a square wave gets close enough
+struct SquareWave {
+    phase_increment: f32,
+    phase: f32,
+    playing: bool
+}
+
+impl AudioCallback for SquareWave {
+    type Channel = f32;
+
+    fn callback(&mut self, out: &mut [f32]) {
+        for sample in out.iter_mut() {
+            *sample = if !self.playing {
+                0.0
+            } else if self.phase < 0.5 {
+                VOLUME
+            } else {
+                -VOLUME
+            };
+            self.phase = (self.phase + self.phase_increment) % 1.0;
+        }
+    }
+}
+
+pub struct Audio {
+    device: AudioDevice<SquareWave>
+}
+
+impl Audio {
+    pub fn new(audio_subsystem: &AudioSubsystem) -> Result<Audio, String> {
+        let spec = AudioSpecDesired {
+            freq: Some(FREQUENCY),
+            channels: Some(1),
+            samples: None
+        };
+        let device = audio_subsystem.open_playback(None, &spec, |spec| SquareWave {
+            phase_increment: TONE / spec.freq as f32,
+            phase: 0.0,
+            playing: false
+        })?;
+        device.resume();
+        Ok(Audio {
+            device: device
+        })
+    }
+
+    pub fn set_playing(&mut self, playing: bool) {
+        self.device.lock().playing = playing;
+    }
+}
@@ -0,0 +1,289 @@
+extern crate rusty_chip8;
+extern crate sha1;
+
+use rusty_chip8::cpu::Cpu;
+use rusty_chip8::engine::{Engine, EngineKind};
+use rusty_chip8::menu;
+use rusty_chip8::profiler::json_string;
+use rusty_chip8::quirks::Quirks;
+use rusty_chip8::rom_loader::RomLoader;
+
+use std::fs;
+use std::path::Path;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Instant;
+
+const USAGE: &str = "\
+Usage: chip8-bench [OPTIONS] <ROM or DIRECTORY>...
+
+Runs ROMs without a window and as fast as they go for a number of frames,
+then reports the speed and a hash of the final screen for each. ROMs in a
+directory are shared out between threads. Runs are repeatable: the random
+numbers are seeded and no keys are pressed, so the hashes of two runs only
+differ when the emulator does.
+
+Options:
+  --frames <N>        Frames to run each ROM for [default: 600]
+  --ips <N>           Instructions per second of emulated time [default: 600]
+  --quirks <PRESET>   auto, modern, vip, schip or xochip, auto takes what the
+                      analyzer makes of each ROM [default: auto]
+  --seed <N>          Seed for the random number generator [default: 0]
+  --engine <NAME>     interpreter, jit or lockstep [default: interpreter]
+  --threads <N>       ROMs run at once [default: one per CPU]
+  --json <FILE>       Write a summary as JSON, - for standard output
+  -h, --help          Print this help
+
+Exits with status 1 when a ROM could not be loaded or stopped with an error,
+2 on bad arguments.
+";
+
+const FRAME_RATE: u32 = 60;
+
+// What every ROM is run with
+#[derive(Clone, Copy)]
+struct Run {
+    frames: u64,
+    ips: u32,
+    quirks: Option<Quirks>, // None picks them by ROM
+    seed: u64,
+    engine: EngineKind
+}
+
+// How one ROM did
+struct Outcome {
+    path: String,
+    quirks: Option<&'static str>,
+    frames: u64, // run before finishing or failing
+    instructions: u64,
+    seconds: f64,
+    screen_hash: Option<String>, // SHA-1 of the final screen, one byte a pixel
+    error: Option<String>
+}
+
+impl Outcome {
+    fn mips(&self) -> f64 {
+        if self.seconds > 0.0 { self.instructions as f64 / self.seconds / 1_000_000.0 } else { 0.0 }
+    }
+
+    fn fps(&self) -> f64 {
+        if self.seconds > 0.0 { self.frames as f64 / self.seconds } else { 0.0 }
+    }
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("error: {}\n\n{}", message, USAGE);
+    process::exit(2);
+}
+
+fn number(option: &str, value: Option<String>) -> u64 {
+    value.and_then(|value| value.parse().ok())
+        .unwrap_or_else(|| fail(&format!("{} expects a number", option)))
+}
+
+// The most memory the process has held at once, as Linux reports it
+fn peak_memory_kb() -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmHWM:"))?;
+    line.split_whitespace().nth(1)?.parse().ok()
+}
+
+fn screen_hash(cpu: &Cpu) -> String {
+    let pixels: Vec<u8> = cpu.get_display().display.iter().flat_map(|row| row.iter().cloned()).collect();
+    sha1::Sha1::from(&pixels[..]).digest().to_string()
+}
+
+fn run_rom(path: &str, run: Run) -> Outcome {
+    let mut outcome = Outcome {
+        path: path.to_string(),
+        quirks: None,
+        frames: 0,
+        instructions: 0,
+        seconds: 0.0,
+        screen_hash: None,
+        error: None
+    };
+    let rom_loader = match RomLoader::new(path.to_string()) {
+        Ok(rom_loader) => rom_loader,
+        Err(error) => {
+            outcome.error = Some(error);
+            return outcome;
+        }
+    };
+    let preset = rom_loader.analyze(rusty_chip8::INITIAL_PC).quirks;
+    outcome.quirks = if run.quirks.is_none() { Some(preset) } else { None };
+    let mut cpu = Cpu::new();
+    cpu.set_quirks(run.quirks.unwrap_or_else(|| Quirks::from_preset(preset).unwrap()));
+    cpu.set_seed(run.seed);
+    if let Err(error) = cpu.load_program_at(&rom_loader, rusty_chip8::INITIAL_PC) {
+        outcome.error = Some(error);
+        return outcome;
+    }
+    let mut engine = match Engine::new(run.engine) {
+        Ok(engine) => engine,
+        Err(error) => {
+            outcome.error = Some(error);
+            return outcome;
+        }
+    };
+
+    let cycles_per_frame = (run.ips / FRAME_RATE).max(1);
+    let mut frames = 0;
+    let start = Instant::now();
+    let result = (0..run.frames).try_for_each(|_| -> Result<(), String> {
+        engine.run(&mut cpu, cycles_per_frame)?;
+        cpu.update_timer();
+        frames += 1;
+        Ok(())
+    });
+    outcome.seconds = start.elapsed().as_secs_f64();
+    outcome.frames = frames;
+    outcome.instructions = engine.get_executed();
+    outcome.error = result.err().map(|error| format!("frame {}: {}", frames, error));
+    outcome.screen_hash = Some(screen_hash(&cpu));
+    outcome
+}
+
+// Files as given, directories replaced by the ROMs in them
+fn collect_roms(arguments: &[String]) -> Vec<String> {
+    let mut roms = Vec::new();
+    for argument in arguments {
+        let path = Path::new(argument);
+        if !path.is_dir() {
+            roms.push(argument.clone());
+            continue;
+        }
+        let entries = fs::read_dir(path).unwrap_or_else(|error| {
+            eprintln!("error: could not read {}: {}", argument, error);
+            process::exit(2);
+        });
+        let mut found: Vec<String> = entries.filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.is_file() && menu::is_rom(path))
+            .map(|path| path.to_string_lossy().into_owned())
+            .collect();
+        found.sort();
+        roms.extend(found);
+    }
+    roms
+}
+
+fn outcome_json(outcome: &Outcome) -> String {
+    let optional = |value: &Option<String>| value.as_ref().map_or("null".to_string(), |value| json_string(value));
+    format!("{{\"path\": {}, \"quirks\": {}, \"frames\": {}, \"instructions\": {}, \"seconds\": {:.6}, \
+             \"mips\": {:.3}, \"fps\": {:.1}, \"screen_sha1\": {}, \"error\": {}}}",
+            json_string(&outcome.path), optional(&outcome.quirks.map(|quirks| quirks.to_string())),
+            outcome.frames, outcome.instructions, outcome.seconds, outcome.mips(), outcome.fps(),
+            optional(&outcome.screen_hash), optional(&outcome.error))
+}
+
+fn main() {
+    let mut run = Run {
+        frames: 600,
+        ips: 600,
+        quirks: None,
+        seed: 0,
+        engine: EngineKind::Interpreter
+    };
+    let mut engine_name = "interpreter".to_string();
+    let mut threads = thread::available_parallelism().map(|threads| threads.get()).unwrap_or(1);
+    let mut json = None;
+    let mut arguments = Vec::new();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => {
+                print!("{}", USAGE);
+                return;
+            }
+            "--frames" => run.frames = number(&arg, args.next()),
+            "--ips" => run.ips = number(&arg, args.next()).max(1) as u32,
+            "--quirks" => {
+                let name = args.next().unwrap_or_default();
+                run.quirks = match name.as_str() {
+                    "auto" => None,
+                    _ => Some(Quirks::from_preset(&name).unwrap_or_else(|| {
+                        fail(&format!("unknown quirks preset '{}', expected auto or one of {}", name, Quirks::preset_names().join(", ")))
+                    }))
+                };
+            }
+            "--seed" => run.seed = number(&arg, args.next()),
+            "--engine" => {
+                engine_name = args.next().unwrap_or_default();
+                run.engine = EngineKind::from_name(&engine_name).unwrap_or_else(|| {
+                    fail(&format!("unknown engine '{}', expected one of {}", engine_name, EngineKind::names().join(", ")))
+                });
+            }
+            "--threads" => threads = number(&arg, args.next()).max(1) as usize,
+            "--json" => json = Some(args.next().unwrap_or_else(|| fail("--json needs a file"))),
+            option if option.starts_with('-') => fail(&format!("unknown option '{}'", option)),
+            _ => arguments.push(arg)
+        }
+    }
+    let roms = collect_roms(&arguments);
+    if roms.is_empty() {
+        fail("no ROM given");
+    }
+    if let Err(error) = Engine::new(run.engine) {
+        eprintln!("error: {}", error);
+        process::exit(2);
+    }
+    let start = Instant::now();
+    let roms = Arc::new(roms);
+    let next = Arc::new(AtomicUsize::new(0));
+    let (sender, receiver) = mpsc::channel();
+    for _ in 0..threads.min(roms.len()) {
+        let (roms, next, sender) = (roms.clone(), next.clone(), sender.clone());
+        thread::spawn(move || loop {
+            let index = next.fetch_add(1, Ordering::SeqCst);
+            if index >= roms.len() || sender.send((index, run_rom(&roms[index], run))).is_err() {
+                break;
+            }
+        });
+    }
+    drop(sender);
+
+    let mut outcomes: Vec<Option<Outcome>> = roms.iter().map(|_| None).collect();
+    for (done, (index, outcome)) in receiver.iter().enumerate() {
+        match &outcome.error {
+            Some(error) => println!("[{}/{}] {}: error at {}", done + 1, roms.len(), outcome.path, error),
+            None => println!("[{}/{}] {}: {:.1} MIPS, {:.0} fps, screen {}",
+                             done + 1, roms.len(), outcome.path, outcome.mips(), outcome.fps(),
+                             outcome.screen_hash.as_ref().map_or("", |hash| &hash[..12]))
+        }
+        outcomes[index] = Some(outcome);
+    }
+    let outcomes: Vec<Outcome> = outcomes.into_iter().map(|outcome| outcome.unwrap()).collect();
+
+    let seconds = start.elapsed().as_secs_f64();
+    let instructions: u64 = outcomes.iter().map(|outcome| outcome.instructions).sum();
+    let errors = outcomes.iter().filter(|outcome| outcome.error.is_some()).count();
+    let mips = instructions as f64 / seconds / 1_000_000.0;
+    let peak_memory = peak_memory_kb();
+    println!("\n{} ROMs, {} with errors, {} instructions in {:.2}s on {} threads: {:.1} MIPS",
+             outcomes.len(), errors, instructions, seconds, threads.min(roms.len()), mips);
+    if let Some(peak_memory) = peak_memory {
+        println!("peak memory {} kB", peak_memory);
+    }
+
+    if let Some(json_path) = json {
+        let roms_json: Vec<String> = outcomes.iter().map(outcome_json).collect();
+        let summary = format!("{{\n  \"frames\": {},\n  \"ips\": {},\n  \"seed\": {},\n  \"engine\": {},\n  \
+                               \"threads\": {},\n  \"seconds\": {:.6},\n  \"instructions\": {},\n  \"mips\": {:.3},\n  \
+                               \"peak_memory_kb\": {},\n  \"errors\": {},\n  \"roms\": [\n    {}\n  ]\n}}\n",
+                              run.frames, run.ips, run.seed, json_string(&engine_name), threads.min(roms.len()),
+                              seconds, instructions, mips, peak_memory.map_or("null".to_string(), |kb| kb.to_string()),
+                              errors, roms_json.join(",\n    "));
+        if json_path == "-" {
+            print!("{}", summary);
+        } else if let Err(error) = fs::write(&json_path, summary) {
+            eprintln!("error: could not write {}: {}", json_path, error);
+            process::exit(1);
+        }
+    }
+    if errors > 0 {
+        process::exit(1);
+    }
+}
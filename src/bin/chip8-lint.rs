@@ -0,0 +1,80 @@
+extern crate rusty_chip8;
+
+use rusty_chip8::lint::{self, Severity};
+use rusty_chip8::quirks::Quirks;
+use rusty_chip8::rom_loader::RomLoader;
+
+use std::process;
+
+const USAGE: &str = "\
+Usage: chip8-lint [OPTIONS] <ROM>...
+
+Walks the code reachable from 0x200 and reports problems this emulator would
+run into: unsupported opcodes, jumps and calls out of the ROM, too deeply nested
+calls, I ranges past the end of memory, code overwriting itself and bytes that
+are never used.
+
+Options:
+  --quirks <PRESET>   modern, vip, schip or xochip [default: modern]
+  --notes             Also print notes, e.g. unused byte ranges
+  -h, --help          Print this help
+
+Exits with status 1 when an error was found, 2 on bad arguments.
+";
+
+fn main() {
+    let mut quirks = Quirks::new();
+    let mut notes = false;
+    let mut roms = Vec::new();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => {
+                print!("{}", USAGE);
+                return;
+            }
+            "--quirks" => {
+                let name = args.next().unwrap_or_default();
+                quirks = Quirks::from_preset(&name).unwrap_or_else(|| {
+                    eprintln!("error: unknown quirks preset '{}', expected one of {}\n\n{}",
+                              name, Quirks::preset_names().join(", "), USAGE);
+                    process::exit(2);
+                });
+            }
+            "--notes" => notes = true,
+            option if option.starts_with('-') => {
+                eprintln!("error: unknown option '{}'\n\n{}", option, USAGE);
+                process::exit(2);
+            }
+            _ => roms.push(arg)
+        }
+    }
+    if roms.is_empty() {
+        eprintln!("error: no ROM given\n\n{}", USAGE);
+        process::exit(2);
+    }
+
+    let mut failed = false;
+    for path in roms {
+        let rom_loader = match RomLoader::new(path.clone()) {
+            Ok(rom_loader) => rom_loader,
+            Err(error) => {
+                eprintln!("{}: error: {}", path, error);
+                failed = true;
+                continue;
+            }
+        };
+        let data = rom_loader.get_data();
+        // like compiler output, so editors can jump to the address
+        for diagnostic in lint::lint(&data[..rom_loader.get_length()], quirks) {
+            if diagnostic.severity == Severity::Note && !notes {
+                continue;
+            }
+            failed |= diagnostic.severity == Severity::Error;
+            println!("{}:{:#05x}: {}: {}", path, diagnostic.address, diagnostic.severity.get_name(), diagnostic.message);
+        }
+    }
+    if failed {
+        process::exit(1);
+    }
+}
@@ -0,0 +1,495 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use super::cpu::Cpu;
+
+// Candidates listed at most, the count is always shown
+const LIST_LIMIT: usize = 32;
+
+pub const HELP: &str = "\
+new                 start a search with every byte of memory and every register
+equal               keep what is the same as at the last search step
+changed             keep what changed since the last search step
+increased           keep what went up since the last search step
+decreased           keep what went down since the last search step
+value <N>           keep what is N now
+list                show the candidates left
+freeze <WHERE> [N]  keep 0xADDR or VX at N, or at what it is now, every frame
+unfreeze <WHERE>    stop freezing 0xADDR or VX, or everything with 'all'
+freezes             show what is frozen
+codes               show the codes from the game's cheat file
+code <N|all>        turn code N, or every code, on or off
+help                show this list";
+
+/*
+ * Finds where a game keeps things like lives or the score, the way cheat
+ * engines do: take a snapshot, play a bit, then keep the bytes that changed
+ * the way the lives did, e.g. went down when one was lost. Each step compares
+ * against the snapshot the step before took. What is found can be frozen,
+ * which writes it back before every frame.
+ *
+ * Codes can also come from a cheat file next to the ROM, e.g. PONG.cht for
+ * PONG.ch8, one per line:
+ *
+ *   [load] ADDRESS:VALUE[:COMPARE] [NAME]
+ *
+ * The numbers are hex. VALUE is written to ADDRESS before every frame, or just
+ * once after the ROM is loaded (and on reset) with "load", which suits codes
+ * changing the program itself. With COMPARE the byte is only written while it
+ * holds that value, so a code made for one version of a ROM leaves others
+ * alone. Lines starting with # are comments.
+ */
+
+// A byte the search can look at
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Target {
+    Memory(u16),
+    Register(u8)
+}
+
+impl Target {
+    // 0x prefixed addresses or V0 to VF
+    pub fn parse(text: &str) -> Option<Target> {
+        let lower = text.to_lowercase();
+        if lower.starts_with("0x") {
+            u16::from_str_radix(&lower[2..], 16).ok()
+                .filter(|address| (*address as usize) < super::RAM_SIZE)
+                .map(Target::Memory)
+        } else if lower.starts_with('v') && lower.len() == 2 {
+            u8::from_str_radix(&lower[1..], 16).ok().map(Target::Register)
+        } else {
+            None
+        }
+    }
+
+    pub fn read(&self, cpu: &Cpu) -> u8 {
+        match self {
+            Target::Memory(address) => cpu.get_memory()[*address as usize],
+            Target::Register(register) => cpu.get_registers()[*register as usize]
+        }
+    }
+
+    pub fn write(&self, cpu: &mut Cpu, value: u8) {
+        match self {
+            Target::Memory(address) => cpu.set_memory(*address, value),
+            Target::Register(register) => cpu.set_register(*register, value)
+        }
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Target::Memory(address) => write!(formatter, "{:#05x}", address),
+            Target::Register(register) => write!(formatter, "V{:X}", register)
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Filter {
+    Equal,
+    Changed,
+    Increased,
+    Decreased,
+    Value(u8)
+}
+
+impl Filter {
+    fn keeps(&self, previous: u8, current: u8) -> bool {
+        match self {
+            Filter::Equal => current == previous,
+            Filter::Changed => current != previous,
+            Filter::Increased => current > previous,
+            Filter::Decreased => current < previous,
+            Filter::Value(value) => current == *value
+        }
+    }
+}
+
+// One line of a cheat file
+#[derive(Clone, Debug)]
+pub struct CheatCode {
+    pub address: u16,
+    pub value: u8,
+    pub compare: Option<u8>,
+    pub on_load: bool, // written once when the game starts, not every frame
+    pub name: String,
+    pub enabled: bool
+}
+
+fn parse_hex(text: &str, what: &str) -> Result<u32, String> {
+    let digits = text.trim_start_matches("0x").trim_start_matches("0X");
+    u32::from_str_radix(digits, 16).map_err(|_| format!("{} '{}' is not a hex number", what, text))
+}
+
+impl CheatCode {
+    pub fn parse(line: &str) -> Result<CheatCode, String> {
+        let mut line = line.trim();
+        let on_load = line.starts_with("load ");
+        if on_load {
+            line = line["load ".len()..].trim_start();
+        }
+        let (code, name) = match line.find(char::is_whitespace) {
+            Some(end) => (&line[..end], line[end..].trim()),
+            None => (line, "")
+        };
+        let parts: Vec<&str> = code.split(':').collect();
+        if parts.len() < 2 || parts.len() > 3 {
+            return Err(format!("expected ADDRESS:VALUE[:COMPARE], got '{}'", code));
+        }
+        let address = parse_hex(parts[0], "address")?;
+        if address as usize >= super::RAM_SIZE {
+            return Err(format!("address {:#x} is past the end of memory", address));
+        }
+        let value = parse_hex(parts[1], "value")?;
+        let compare = match parts.get(2) {
+            Some(compare) => Some(parse_hex(compare, "compare value")?),
+            None => None
+        };
+        if value > 0xFF || compare.map_or(false, |compare| compare > 0xFF) {
+            return Err(format!("values are single bytes, got '{}'", code));
+        }
+        Ok(CheatCode {
+            address: address as u16,
+            value: value as u8,
+            compare: compare.map(|compare| compare as u8),
+            on_load: on_load,
+            name: if name.is_empty() { code.to_string() } else { name.to_string() },
+            enabled: true
+        })
+    }
+
+    fn write(&self, cpu: &mut Cpu) {
+        let current = cpu.get_memory()[self.address as usize];
+        if current != self.value && self.compare.map_or(true, |compare| compare == current) {
+            cpu.set_memory(self.address, self.value);
+        }
+    }
+}
+
+// The cheat file that goes with a ROM, if there is one
+pub fn find_cheat_file(rom_path: &Path) -> Option<PathBuf> {
+    Some(rom_path.with_extension("cht")).filter(|path| path.is_file())
+}
+
+pub fn load_codes(path: &Path) -> Result<Vec<CheatCode>, String> {
+    let text = fs::read_to_string(path)
+        .map_err(|error| format!("could not read {}: {}", path.display(), error))?;
+    let mut codes = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let code = CheatCode::parse(line)
+            .map_err(|error| format!("{} line {}: {}", path.display(), number + 1, error))?;
+        codes.push(code);
+    }
+    Ok(codes)
+}
+
+// Every candidate with the value it had at the last search step, plus what
+// is being kept at a value
+pub struct CheatSearch {
+    candidates: Option<Vec<(Target, u8)>>, // None before the first search
+    freezes: BTreeMap<Target, u8>,
+    codes: Vec<CheatCode> // from the cheat file
+}
+
+fn every_target() -> Vec<Target> {
+    let memory = (0..super::RAM_SIZE as u16).map(Target::Memory);
+    let registers = (0..super::REGISTER_COUNT as u8).map(Target::Register);
+    memory.chain(registers).collect()
+}
+
+fn parse_value(text: &str) -> Result<u8, String> {
+    let parsed = if text.starts_with("0x") || text.starts_with("0X") {
+        u8::from_str_radix(&text[2..], 16)
+    } else {
+        text.parse()
+    };
+    parsed.map_err(|_| format!("expected a value from 0 to 255, got '{}'", text))
+}
+
+fn parse_target(text: Option<&str>) -> Result<Target, String> {
+    let text = text.ok_or("expected an address like 0x2a0 or a register like V3")?;
+    Target::parse(text).ok_or_else(|| format!("expected an address like 0x2a0 or a register like V3, got '{}'", text))
+}
+
+impl CheatSearch {
+    pub fn new() -> CheatSearch {
+        CheatSearch {
+            candidates: None,
+            freezes: BTreeMap::new(),
+            codes: Vec::new()
+        }
+    }
+
+    pub fn set_codes(&mut self, codes: Vec<CheatCode>) {
+        self.codes = codes;
+    }
+
+    pub fn get_codes(&self) -> &[CheatCode] {
+        &self.codes
+    }
+
+    // Turns every code off when any is on, otherwise all of them on.
+    // Returns whether they are on now.
+    pub fn toggle_codes(&mut self) -> bool {
+        let enable = !self.codes.iter().any(|code| code.enabled);
+        for code in self.codes.iter_mut() {
+            code.enabled = enable;
+        }
+        enable
+    }
+
+    // Called once the ROM is in memory, again after a reset
+    pub fn apply_on_load(&self, cpu: &mut Cpu) {
+        for code in self.codes.iter().filter(|code| code.enabled && code.on_load) {
+            code.write(cpu);
+        }
+    }
+
+    // Starts over with every byte as a candidate, at its value right now
+    pub fn start(&mut self, cpu: &Cpu) -> usize {
+        let candidates: Vec<(Target, u8)> = every_target().into_iter()
+            .map(|target| (target, target.read(cpu)))
+            .collect();
+        let count = candidates.len();
+        self.candidates = Some(candidates);
+        count
+    }
+
+    // Keeps the candidates that pass, remembering their values for the next step
+    pub fn filter(&mut self, cpu: &Cpu, filter: Filter) -> Result<usize, String> {
+        let candidates = self.candidates.as_mut().ok_or("no search yet, start one with 'new'")?;
+        candidates.retain(|(target, previous)| filter.keeps(*previous, target.read(cpu)));
+        for (target, value) in candidates.iter_mut() {
+            *value = target.read(cpu);
+        }
+        Ok(candidates.len())
+    }
+
+    pub fn get_candidates(&self) -> &[(Target, u8)] {
+        self.candidates.as_ref().map_or(&[], |candidates| candidates.as_slice())
+    }
+
+    pub fn freeze(&mut self, target: Target, value: u8) {
+        self.freezes.insert(target, value);
+    }
+
+    pub fn unfreeze(&mut self, target: Target) -> bool {
+        self.freezes.remove(&target).is_some()
+    }
+
+    pub fn get_freezes(&self) -> &BTreeMap<Target, u8> {
+        &self.freezes
+    }
+
+    // Called before every frame, so the game always sees the frozen values.
+    // Only bytes the game changed are written back, since every write to
+    // memory throws away the decoded and compiled code around it.
+    pub fn apply(&self, cpu: &mut Cpu) {
+        for (target, value) in self.freezes.iter() {
+            if target.read(cpu) != *value {
+                target.write(cpu, *value);
+            }
+        }
+        for code in self.codes.iter().filter(|code| code.enabled && !code.on_load) {
+            code.write(cpu);
+        }
+    }
+
+    // Runs one line typed at the cheat console, returning what to print
+    pub fn execute(&mut self, cpu: &Cpu, line: &str) -> Result<String, String> {
+        let mut words = line.split_whitespace();
+        let command = match words.next() {
+            Some(command) => command.to_lowercase(),
+            None => return Ok(String::new())
+        };
+        let filter = match command.as_str() {
+            "equal" => Some(Filter::Equal),
+            "changed" => Some(Filter::Changed),
+            "increased" => Some(Filter::Increased),
+            "decreased" => Some(Filter::Decreased),
+            "value" => Some(Filter::Value(parse_value(words.next().ok_or("value expects a number")?)?)),
+            _ => None
+        };
+        if let Some(filter) = filter {
+            let count = self.filter(cpu, filter)?;
+            return Ok(if count > 0 && count <= LIST_LIMIT { self.list() } else { format!("{} candidates", count) });
+        }
+        match command.as_str() {
+            "new" => Ok(format!("{} candidates", self.start(cpu))),
+            "list" => Ok(self.list()),
+            "freeze" => {
+                let target = parse_target(words.next())?;
+                let value = match words.next() {
+                    Some(value) => parse_value(value)?,
+                    None => target.read(cpu)
+                };
+                self.freeze(target, value);
+                Ok(format!("{} frozen at {}", target, value))
+            }
+            "unfreeze" => match words.next() {
+                Some("all") => {
+                    self.freezes.clear();
+                    Ok("nothing frozen".to_string())
+                }
+                text => {
+                    let target = parse_target(text)?;
+                    if self.unfreeze(target) {
+                        Ok(format!("{} no longer frozen", target))
+                    } else {
+                        Err(format!("{} is not frozen", target))
+                    }
+                }
+            },
+            "freezes" if self.freezes.is_empty() => Ok("nothing frozen".to_string()),
+            "freezes" => Ok(self.freezes.iter()
+                .map(|(target, value)| format!("{} = {}", target, value))
+                .collect::<Vec<String>>()
+                .join("\n")),
+            "codes" => Ok(self.list_codes()),
+            "code" => {
+                let which = words.next().ok_or("code expects a number from 'codes' or 'all'")?;
+                if which == "all" {
+                    let enabled = self.toggle_codes();
+                    return Ok(format!("every code {}", if enabled { "on" } else { "off" }));
+                }
+                let index = which.parse::<usize>().ok()
+                    .filter(|index| *index >= 1 && *index <= self.codes.len())
+                    .ok_or_else(|| format!("no code {}, see 'codes'", which))?;
+                let code = &mut self.codes[index - 1];
+                code.enabled = !code.enabled;
+                let state = if code.enabled { "on" } else { "off" };
+                if code.on_load {
+                    Ok(format!("{} {}, from the next reset", code.name, state))
+                } else {
+                    Ok(format!("{} {}", code.name, state))
+                }
+            }
+            "help" => Ok(HELP.to_string()),
+            _ => Err(format!("unknown command '{}', try 'help'", command))
+        }
+    }
+
+    fn list_codes(&self) -> String {
+        if self.codes.is_empty() {
+            return "no cheat file for this game".to_string();
+        }
+        self.codes.iter().enumerate()
+            .map(|(index, code)| {
+                format!("{:2} [{}] {}{}", index + 1, if code.enabled { "x" } else { " " },
+                        code.name, if code.on_load { " (on load)" } else { "" })
+            })
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+
+    fn list(&self) -> String {
+        let candidates = self.get_candidates();
+        let mut lines: Vec<String> = candidates.iter()
+            .take(LIST_LIMIT)
+            .map(|(target, value)| format!("{} = {} ({:#04x})", target, value, value))
+            .collect();
+        if candidates.len() > LIST_LIMIT {
+            lines.push(format!("... {} more", candidates.len() - LIST_LIMIT));
+        }
+        if lines.is_empty() {
+            lines.push("no candidates".to_string());
+        }
+        lines.join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_codes() {
+        let code = CheatCode::parse("2A0:09 Infinite lives").unwrap();
+        assert_eq!((code.address, code.value, code.compare, code.on_load), (0x2A0, 0x09, None, false));
+        assert_eq!(code.name, "Infinite lives");
+        assert!(code.enabled);
+
+        let code = CheatCode::parse("load 0x300:12:6A").unwrap();
+        assert_eq!((code.address, code.value, code.compare, code.on_load), (0x300, 0x12, Some(0x6A), true));
+        // without a name the code itself is shown
+        assert_eq!(code.name, "0x300:12:6A");
+    }
+
+    #[test]
+    fn rejects_bad_codes() {
+        let error = CheatCode::parse("2G0:09").unwrap_err();
+        assert!(error.contains("address '2G0'"), "{}", error);
+        let error = CheatCode::parse("1000:09").unwrap_err();
+        assert!(error.contains("past the end of memory"), "{}", error);
+        let error = CheatCode::parse("2A0:x9").unwrap_err();
+        assert!(error.contains("value 'x9'"), "{}", error);
+        let error = CheatCode::parse("2A0:100").unwrap_err();
+        assert!(error.contains("single bytes"), "{}", error);
+        let error = CheatCode::parse("2A0:09:1FF").unwrap_err();
+        assert!(error.contains("single bytes"), "{}", error);
+        assert!(CheatCode::parse("2A0").is_err());
+        assert!(CheatCode::parse("2A0:09:00:00").is_err());
+    }
+
+    #[test]
+    fn parses_targets() {
+        assert_eq!(Target::parse("0x2A0"), Some(Target::Memory(0x2A0)));
+        assert_eq!(Target::parse("0XFFF"), Some(Target::Memory(0xFFF)));
+        assert_eq!(Target::parse("vb"), Some(Target::Register(0xB)));
+        assert_eq!(Target::parse("0x1000"), None);
+        assert_eq!(Target::parse("0x"), None);
+        assert_eq!(Target::parse("0xZZ"), None);
+        assert_eq!(Target::parse("2A0"), None);
+        assert_eq!(Target::parse("VG"), None);
+        assert_eq!(Target::parse("V10"), None);
+        let error = parse_target(Some("W3")).unwrap_err();
+        assert!(error.contains("got 'W3'"), "{}", error);
+        assert!(parse_target(None).is_err());
+    }
+
+    #[test]
+    fn filters_narrow_the_search() {
+        let mut search = CheatSearch::new();
+        let mut cpu = Cpu::new();
+        assert!(search.filter(&cpu, Filter::Equal).is_err());
+        cpu.set_memory(0x300, 3);
+        cpu.set_memory(0x301, 7);
+        cpu.set_register(2, 1);
+        assert_eq!(search.start(&cpu), super::super::RAM_SIZE + super::super::REGISTER_COUNT);
+
+        cpu.set_memory(0x300, 2);
+        cpu.set_memory(0x301, 8);
+        cpu.set_register(2, 2);
+        assert_eq!(search.filter(&cpu, Filter::Changed), Ok(3));
+        cpu.set_memory(0x301, 9);
+        assert_eq!(search.filter(&cpu, Filter::Equal), Ok(2));
+        // each step compares against the values the last one saw
+        cpu.set_register(2, 5);
+        assert_eq!(search.filter(&cpu, Filter::Increased), Ok(1));
+        assert_eq!(search.get_candidates(), &[(Target::Register(2), 5)]);
+        assert_eq!(search.filter(&cpu, Filter::Value(4)), Ok(0));
+    }
+
+    #[test]
+    fn freezes_write_only_changes() {
+        let mut search = CheatSearch::new();
+        let mut cpu = Cpu::new();
+        search.freeze(Target::Memory(0x300), 9);
+        search.freeze(Target::Register(3), 4);
+        search.apply(&mut cpu);
+        assert_eq!(cpu.get_memory()[0x300], 9);
+        assert_eq!(cpu.get_registers()[3], 4);
+        assert_eq!(cpu.take_written(), Some((0x300, 0x300)));
+        // nothing changed, so the cached code around 0x300 stays
+        search.apply(&mut cpu);
+        assert_eq!(cpu.take_written(), None);
+    }
+}
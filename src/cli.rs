@@ -0,0 +1,321 @@
+use rusty_chip8::config::Settings;
+use rusty_chip8::display::{self, Palette, RenderMode};
+use rusty_chip8::engine::EngineKind;
+use rusty_chip8::keys::KeyMode;
+use rusty_chip8::quirks::Quirks;
+
+use std::convert::TryFrom;
+
+pub const USAGE: &str = "\
+Usage: rusty-chip8 [OPTIONS] [ROM]
+
+Without a ROM a menu lists the ROMs in rom_dir from the config file, or in
+the current directory. Giving a directory instead of a ROM lists that one.
+
+Defaults for most options can be set in ~/.config/rusty-chip8/config.toml,
+options given here take precedence.
+
+Options:
+  --ips <N>               Instructions per second [default: 600]
+  --quirks <PRESET>       modern, vip, schip or xochip [default: modern]
+  --seed <N>              Seed for the random number generator (CXKK)
+  --load-address <ADDR>   Where the ROM is loaded and started [default: 0x200]
+  --engine <NAME>         interpreter, jit (x86-64 Linux) or lockstep, which
+                          checks the JIT against the interpreter as it runs
+                          [default: interpreter]
+  --frontend <NAME>       sdl or headless [default: sdl]
+  --headless              Run without a window, same as --frontend headless
+  --frames <N>            Stop after N frames, required when headless
+  --mute                  Turn off sound
+  --scale <N>             Initial window size as a multiple of 64x32
+  --palette <NAME>        classic, amber, green, lcd or paper
+  --fg <RRGGBB>           Foreground colour
+  --bg <RRGGBB>           Background colour
+  --render <MODE>         normal, phosphor or frame-or
+  --decay <N>             Phosphor rendering keeping N (0 to 1) of the brightness per frame
+  --resizable             Allow resizing the window
+  --fullscreen            Start in fullscreen
+  --keymap <MODE>         Match keys by scancode (position) or keycode (label)
+  --pause-unfocused       Pause while the window is in the background
+  --console               Read cheat search commands from standard input,
+                          type 'help' there for the list
+  --profile               Print where the ROM spends its instructions on exit
+  --profile-top <N>       Hot addresses listed by --profile [default: 20]
+  --profile-json <FILE>   Also write the profile as JSON, implies --profile
+  -h, --help              Print this help
+  -V, --version           Print the version
+";
+
+pub struct Options {
+    pub rom_path: Option<String>, // a ROM or a directory for the menu
+    pub settings: Settings, // laid over the config file
+    pub seed: Option<u64>,
+    pub load_address: u16,
+    pub headless: bool,
+    pub frames: Option<u64>,
+    pub engine: EngineKind,
+    pub console: bool, // cheat commands on stdin
+    pub profile: bool,
+    pub profile_top: usize,
+    pub profile_json: Option<String>
+}
+
+pub enum Command {
+    Run(Options),
+    Help,
+    Version
+}
+
+// Accepts decimal or 0x prefixed hexadecimal
+fn parse_number(option: &str, value: &str) -> Result<u64, String> {
+    let parsed = if value.starts_with("0x") || value.starts_with("0X") {
+        u64::from_str_radix(&value[2..], 16)
+    } else {
+        value.parse()
+    };
+    parsed.map_err(|_| format!("{} expects a number, got '{}'", option, value))
+}
+
+pub fn parse(args: &[String]) -> Result<Command, String> {
+    let mut settings = Settings::default();
+    let mut seed = None;
+    let mut load_address = rusty_chip8::INITIAL_PC;
+    let mut headless = false;
+    let mut frames = None;
+    let mut engine = EngineKind::Interpreter;
+    let mut console = false;
+    let mut profile = false;
+    let mut profile_top = 20;
+    let mut profile_json = None;
+    let mut rom_path = None;
+
+    let mut args = args.iter().skip(1);
+    while let Some(arg) = args.next() {
+        let option = arg.as_str();
+        // every option except the flags takes the next argument as its value
+        let mut value = || args.next().map(|value| value.as_str())
+            .ok_or_else(|| format!("{} needs a value", option));
+        match option {
+            "-h" | "--help" => return Ok(Command::Help),
+            "-V" | "--version" => return Ok(Command::Version),
+            "--ips" => {
+                let ips = parse_number(option, value()?)?;
+                settings.ips = Some(u32::try_from(ips).ok().filter(|ips| *ips > 0)
+                    .ok_or_else(|| format!("--ips must be between 1 and {}", u32::MAX))?);
+            }
+            "--quirks" => {
+                let name = value()?;
+                if Quirks::from_preset(name).is_none() {
+                    return Err(format!("unknown quirks preset '{}', expected one of {}", name, Quirks::preset_names().join(", ")));
+                }
+                settings.quirks = Some(name.to_string());
+            }
+            "--seed" => seed = Some(parse_number(option, value()?)?),
+            "--load-address" => {
+                let address = parse_number(option, value()?)?;
+                if address >= rusty_chip8::RAM_SIZE as u64 {
+                    return Err(format!("--load-address {:#x} is outside of memory", address));
+                }
+                load_address = address as u16;
+            }
+            "--frontend" => match value()? {
+                "sdl" => headless = false,
+                "headless" => headless = true,
+                name => return Err(format!("unknown frontend '{}', expected sdl or headless", name))
+            },
+            "--headless" => headless = true,
+            "--frames" => frames = Some(parse_number(option, value()?)?),
+            "--engine" => {
+                let name = value()?;
+                engine = EngineKind::from_name(name)
+                    .ok_or_else(|| format!("unknown engine '{}', expected one of {}", name, EngineKind::names().join(", ")))?;
+            }
+            "--console" => console = true,
+            "--profile" => profile = true,
+            "--profile-top" => profile_top = parse_number(option, value()?)? as usize,
+            "--profile-json" => {
+                profile = true;
+                profile_json = Some(value()?.to_string());
+            }
+            "--mute" => settings.mute = Some(true),
+            "--scale" => {
+                let scale = parse_number(option, value()?)?;
+                settings.scale = Some(u32::try_from(scale).ok().filter(|scale| *scale > 0)
+                    .ok_or_else(|| format!("--scale must be between 1 and {}", u32::MAX))?);
+            }
+            "--palette" => {
+                let name = value()?;
+                if Palette::from_name(name).is_none() {
+                    return Err(format!("unknown palette '{}', expected one of {}", name, Palette::preset_names().join(", ")));
+                }
+                settings.palette = Some(name.to_string());
+            }
+            "--fg" | "--bg" => {
+                let color = value()?;
+                if display::parse_color(color).is_none() {
+                    return Err(format!("{} expects a colour as RRGGBB", option));
+                }
+                if option == "--fg" {
+                    settings.foreground = Some(color.to_string());
+                } else {
+                    settings.background = Some(color.to_string());
+                }
+            }
+            "--render" => {
+                let name = value()?;
+                if RenderMode::from_name(name).is_none() {
+                    return Err("--render expects normal, phosphor or frame-or".to_string());
+                }
+                settings.render = Some(name.to_string());
+            }
+            "--decay" => {
+                // contains also turns away NaN, which parses fine
+                let decay = value()?.parse::<f32>().ok()
+                    .filter(|decay| (0.0..=1.0).contains(decay))
+                    .ok_or_else(|| "--decay expects a number between 0 and 1".to_string())?;
+                settings.decay = Some(decay);
+            }
+            "--resizable" => settings.resizable = Some(true),
+            "--fullscreen" => settings.fullscreen = Some(true),
+            "--keymap" => {
+                let name = value()?;
+                if KeyMode::from_name(name).is_none() {
+                    return Err("--keymap expects scancode or keycode".to_string());
+                }
+                settings.keymap = Some(name.to_string());
+            }
+            "--pause-unfocused" => settings.pause_unfocused = Some(true),
+            _ if option.starts_with('-') => return Err(format!("unknown option '{}'", option)),
+            _ => {
+                if rom_path.is_some() {
+                    return Err(format!("unexpected argument '{}', only one ROM can be given", option));
+                }
+                rom_path = Some(arg.clone());
+            }
+        }
+    }
+
+    if headless && rom_path.is_none() {
+        return Err("--headless needs a ROM".to_string());
+    }
+    if headless && frames.is_none() {
+        return Err("--headless needs --frames to know when to stop".to_string());
+    }
+    if headless && console {
+        return Err("--console needs the window, headless runs do not wait for commands".to_string());
+    }
+    if profile && engine != EngineKind::Interpreter {
+        return Err("--profile counts single instructions, it needs the interpreter engine".to_string());
+    }
+    Ok(Command::Run(Options {
+        rom_path: rom_path,
+        settings: settings,
+        seed: seed,
+        load_address: load_address,
+        headless: headless,
+        frames: frames,
+        engine: engine,
+        console: console,
+        profile: profile,
+        profile_top: profile_top,
+        profile_json: profile_json
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parsed(args: &[&str]) -> Result<Command, String> {
+        let args: Vec<String> = std::iter::once("rusty-chip8").chain(args.iter().cloned()).map(String::from).collect();
+        parse(&args)
+    }
+
+    fn options(args: &[&str]) -> Options {
+        match parsed(args) {
+            Ok(Command::Run(options)) => options,
+            Ok(_) => panic!("{:?} did not run a game", args),
+            Err(error) => panic!("{:?}: {}", args, error)
+        }
+    }
+
+    fn error(args: &[&str]) -> String {
+        match parsed(args) {
+            Err(error) => error,
+            Ok(_) => panic!("{:?} was accepted", args)
+        }
+    }
+
+    #[test]
+    fn defaults() {
+        let options = options(&[]);
+        assert_eq!(options.rom_path, None);
+        assert_eq!(options.load_address, rusty_chip8::INITIAL_PC);
+        assert_eq!(options.engine, EngineKind::Interpreter);
+        assert!(!options.headless && !options.profile && !options.console);
+        assert_eq!(options.profile_top, 20);
+        assert_eq!(options.settings.ips, None);
+    }
+
+    #[test]
+    fn flags_and_values() {
+        let options = options(&[
+            "--ips", "0x300", "--quirks", "vip", "--seed", "7", "--load-address", "0x600",
+            "--headless", "--frames", "60", "--mute", "--scale", "8", "--palette", "amber",
+            "--fg", "ff8000", "--render", "phosphor", "--decay", "0.5", "--keymap", "keycode",
+            "--profile-json", "profile.json", "game.ch8"
+        ]);
+        assert_eq!(options.rom_path.as_deref(), Some("game.ch8"));
+        assert_eq!(options.settings.ips, Some(0x300));
+        assert_eq!(options.settings.quirks.as_deref(), Some("vip"));
+        assert_eq!(options.seed, Some(7));
+        assert_eq!(options.load_address, 0x600);
+        assert!(options.headless);
+        assert_eq!(options.frames, Some(60));
+        assert_eq!(options.settings.mute, Some(true));
+        assert_eq!(options.settings.scale, Some(8));
+        assert_eq!(options.settings.palette.as_deref(), Some("amber"));
+        assert_eq!(options.settings.foreground.as_deref(), Some("ff8000"));
+        assert_eq!(options.settings.render.as_deref(), Some("phosphor"));
+        assert_eq!(options.settings.decay, Some(0.5));
+        assert_eq!(options.settings.keymap.as_deref(), Some("keycode"));
+        // the JSON file implies the profile
+        assert!(options.profile);
+        assert_eq!(options.profile_json.as_deref(), Some("profile.json"));
+    }
+
+    #[test]
+    fn help_and_version() {
+        assert!(match parsed(&["--ips", "700", "-h"]) { Ok(Command::Help) => true, _ => false });
+        assert!(match parsed(&["--version"]) { Ok(Command::Version) => true, _ => false });
+    }
+
+    #[test]
+    fn bad_values() {
+        assert_eq!(error(&["--ips"]), "--ips needs a value");
+        assert_eq!(error(&["--ips", "fast"]), "--ips expects a number, got 'fast'");
+        assert_eq!(error(&["--ips", "0"]), format!("--ips must be between 1 and {}", u32::MAX));
+        assert_eq!(error(&["--ips", "4294967296"]), format!("--ips must be between 1 and {}", u32::MAX));
+        assert_eq!(error(&["--scale", "0"]), format!("--scale must be between 1 and {}", u32::MAX));
+        assert!(error(&["--quirks", "cosmac"]).starts_with("unknown quirks preset 'cosmac'"));
+        assert_eq!(error(&["--load-address", "0x1000"]), "--load-address 0x1000 is outside of memory");
+        assert!(error(&["--engine", "fast"]).starts_with("unknown engine 'fast'"));
+        assert!(error(&["--palette", "pink"]).starts_with("unknown palette 'pink'"));
+        assert_eq!(error(&["--bg", "black"]), "--bg expects a colour as RRGGBB");
+        assert_eq!(error(&["--render", "crt"]), "--render expects normal, phosphor or frame-or");
+        assert_eq!(error(&["--keymap", "position"]), "--keymap expects scancode or keycode");
+        for decay in &["NaN", "1.5", "-0.1", "half"] {
+            assert_eq!(error(&["--decay", decay]), "--decay expects a number between 0 and 1");
+        }
+    }
+
+    #[test]
+    fn bad_combinations() {
+        assert_eq!(error(&["--bogus"]), "unknown option '--bogus'");
+        assert_eq!(error(&["a.ch8", "b.ch8"]), "unexpected argument 'b.ch8', only one ROM can be given");
+        assert_eq!(error(&["--headless", "--frames", "1"]), "--headless needs a ROM");
+        assert_eq!(error(&["--frontend", "headless", "a.ch8"]), "--headless needs --frames to know when to stop");
+        assert!(error(&["--headless", "--frames", "1", "--console", "a.ch8"]).starts_with("--console needs the window"));
+        assert!(error(&["--profile", "--engine", "jit", "a.ch8"]).starts_with("--profile counts single instructions"));
+    }
+}
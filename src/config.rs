@@ -0,0 +1,409 @@
+extern crate serde;
+extern crate toml;
+
+use serde::Deserialize;
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use super::display::{self, Palette, RenderMode};
+use super::frontend::FrontendOptions;
+use super::keys::{BindingsFile, KeyMode};
+use super::quirks::Quirks;
+
+pub const DEFAULT_IPS: u32 = 600;
+
+/*
+ * Settings live in $XDG_CONFIG_HOME/rusty-chip8/config.toml, e.g.
+ *
+ *   ips = 700
+ *   palette = "amber"
+ *   save_rom_settings = true
+ *   rom_dir = "~/chip8"
+ *   detect_quirks = false
+ *
+ *   [bindings.hotkeys]
+ *   pause = ["Space"]
+ *
+ *   [rom.0123456789abcdef0123456789abcdef01234567]
+ *   quirks = "vip"
+ *   palette = "green"
+ *
+ * The top level holds the defaults, [rom.<sha1>] sections override them for
+ * one ROM and command-line options override both. Quirks and speed from the
+ * ROM database (romdb.rs), or the quirks analyzer.rs picks for ROMs it does
+ * not know, sit below the top level, so a speed the user set everywhere wins
+ * over one recommended for the ROM.
+ *
+ * Settings changed while playing go to rom-settings.toml next to it, laid out
+ * the same way, so config.toml is only ever written by the user and keeps
+ * its comments. Those are applied last, after the ROM's section.
+ *
+ * Keys that are not settings, e.g. a misspelt "ipss", are reported and
+ * otherwise ignored.
+ */
+
+// Every field is optional so layers can be stacked on top of each other
+#[derive(Deserialize, Default, Clone)]
+pub struct Settings {
+    pub ips: Option<u32>,
+    pub quirks: Option<String>,
+    pub palette: Option<String>,
+    pub foreground: Option<String>,
+    pub background: Option<String>,
+    pub scale: Option<u32>,
+    pub render: Option<String>,
+    pub decay: Option<f32>,
+    pub resizable: Option<bool>,
+    pub fullscreen: Option<bool>,
+    pub pause_unfocused: Option<bool>,
+    pub mute: Option<bool>,
+    pub keymap: Option<String>,
+    pub bindings: Option<BindingsFile>,
+    // Whatever else was in the table, only kept to be reported
+    #[serde(flatten)]
+    pub unknown: HashMap<String, toml::Value>
+}
+
+// Everything the emulator needs once all the layers are combined
+pub struct Resolved {
+    pub frontend: FrontendOptions,
+    pub ips: u32,
+    pub quirks: Quirks,
+    pub key_mode: Option<KeyMode>,
+    pub palette_name: Option<String> // the preset picked, if any
+}
+
+impl Settings {
+    // Settings from `other` win. Picking a palette also drops colours set below it.
+    pub fn overlay(&self, other: &Settings) -> Settings {
+        let (foreground, background) = if other.palette.is_some() {
+            (other.foreground.clone(), other.background.clone())
+        } else {
+            (other.foreground.clone().or_else(|| self.foreground.clone()),
+             other.background.clone().or_else(|| self.background.clone()))
+        };
+        Settings {
+            ips: other.ips.or(self.ips),
+            quirks: other.quirks.clone().or_else(|| self.quirks.clone()),
+            palette: other.palette.clone().or_else(|| self.palette.clone()),
+            foreground: foreground,
+            background: background,
+            scale: other.scale.or(self.scale),
+            render: other.render.clone().or_else(|| self.render.clone()),
+            decay: other.decay.or(self.decay),
+            resizable: other.resizable.or(self.resizable),
+            fullscreen: other.fullscreen.or(self.fullscreen),
+            pause_unfocused: other.pause_unfocused.or(self.pause_unfocused),
+            mute: other.mute.or(self.mute),
+            keymap: other.keymap.clone().or_else(|| self.keymap.clone()),
+            // bindings are applied layer by layer, see KeyBindings::for_rom
+            bindings: other.bindings.clone().or_else(|| self.bindings.clone()),
+            unknown: HashMap::new()
+        }
+    }
+
+    pub fn resolve(&self) -> Result<Resolved, String> {
+        let mut frontend = FrontendOptions::new();
+        if let Some(name) = &self.palette {
+            frontend.display.palette = Palette::from_name(name)
+                .ok_or_else(|| format!("unknown palette '{}', expected one of {}", name, Palette::preset_names().join(", ")))?;
+        }
+        if let Some(color) = &self.foreground {
+            frontend.display.palette.foreground = display::parse_color(color)
+                .ok_or_else(|| format!("foreground '{}' is not a colour, expected RRGGBB", color))?;
+        }
+        if let Some(color) = &self.background {
+            frontend.display.palette.background = display::parse_color(color)
+                .ok_or_else(|| format!("background '{}' is not a colour, expected RRGGBB", color))?;
+        }
+        if let Some(scale) = self.scale {
+            frontend.display.scale = scale.max(1);
+        }
+        if let Some(decay) = self.decay {
+            // written this way round so NaN is refused too
+            if !(0.0..=1.0).contains(&decay) {
+                return Err(format!("decay {} is not between 0 and 1", decay));
+            }
+        }
+        frontend.display.render_mode = match (&self.render, self.decay) {
+            (Some(name), decay) => match RenderMode::from_name(name) {
+                Some(RenderMode::Phosphor { .. }) if decay.is_some() => RenderMode::Phosphor { decay: decay.unwrap() },
+                Some(mode) => mode,
+                None => return Err(format!("unknown render mode '{}', expected normal, phosphor or frame-or", name))
+            },
+            (None, Some(decay)) => RenderMode::Phosphor { decay: decay },
+            (None, None) => RenderMode::Normal
+        };
+        frontend.display.resizable = self.resizable.unwrap_or(false);
+        frontend.display.fullscreen = self.fullscreen.unwrap_or(false);
+        frontend.pause_on_focus_loss = self.pause_unfocused.unwrap_or(false);
+        frontend.mute = self.mute.unwrap_or(false);
+
+        let quirks = match &self.quirks {
+            Some(name) => Quirks::from_preset(name)
+                .ok_or_else(|| format!("unknown quirks preset '{}', expected one of {}", name, Quirks::preset_names().join(", ")))?,
+            None => Quirks::new()
+        };
+        let key_mode = match &self.keymap {
+            Some(name) => Some(KeyMode::from_name(name)
+                .ok_or_else(|| format!("unknown keymap '{}', expected scancode or keycode", name))?),
+            None => None
+        };
+        Ok(Resolved {
+            frontend: frontend,
+            ips: self.ips.unwrap_or(DEFAULT_IPS).max(1),
+            quirks: quirks,
+            key_mode: key_mode,
+            palette_name: self.palette.clone()
+        })
+    }
+}
+
+#[derive(Deserialize, Default, Clone)]
+pub struct Config {
+    #[serde(flatten)]
+    pub defaults: Settings,
+    // Write settings changed while playing, e.g. the palette, to rom-settings.toml
+    #[serde(default)]
+    pub save_rom_settings: bool,
+    // Where the ROM menu looks for games
+    pub rom_dir: Option<String>,
+    // Pick quirks for ROMs missing from the ROM database by looking at their code
+    pub detect_quirks: Option<bool>,
+    #[serde(default)]
+    pub rom: HashMap<String, Settings>,
+    // What save_rom_setting wrote on earlier runs, from rom-settings.toml
+    #[serde(skip)]
+    pub saved: HashMap<String, Settings>
+}
+
+// The layout of rom-settings.toml, [rom.<sha1>] sections only
+#[derive(Deserialize, Default)]
+struct SavedSettings {
+    #[serde(default)]
+    rom: HashMap<String, Settings>
+}
+
+impl Config {
+    // A missing file is fine, a broken one is reported and ignored
+    pub fn load() -> Config {
+        let path = match config_file() {
+            Some(path) if path.is_file() => path,
+            _ => return Config {
+                saved: load_saved_settings(),
+                ..Config::default()
+            }
+        };
+        let mut config = match read_toml::<Config>(&path) {
+            Ok(config) => config,
+            Err(error) => {
+                eprintln!("Ignoring {}: {}", path.display(), error);
+                Config::default()
+            }
+        };
+        for key in config.unknown_keys() {
+            eprintln!("Ignoring unknown setting {} in {}", key, path.display());
+        }
+        config.saved = load_saved_settings();
+        config
+    }
+
+    // Keys that are not settings, e.g. "ipss" or "rom.<sha1>.ipss", sorted
+    pub fn unknown_keys(&self) -> Vec<String> {
+        let mut keys: Vec<String> = self.defaults.unknown.keys().cloned().collect();
+        for (hash, settings) in self.rom.iter() {
+            keys.extend(settings.unknown.keys().map(|key| format!("rom.{}.{}", hash, key)));
+        }
+        keys.sort();
+        keys
+    }
+
+    // What the ROM database or the analyzer recommends, then the defaults from
+    // the top of the file, then the ROM's own section and last what was saved
+    // for it while playing
+    pub fn for_rom(&self, hash: &str, recommended: &Settings) -> Settings {
+        let mut settings = recommended.overlay(&self.defaults);
+        if let Some(rom_settings) = self.rom.get(hash) {
+            settings = settings.overlay(rom_settings);
+        }
+        if let Some(saved) = self.saved.get(hash) {
+            settings = settings.overlay(saved);
+        }
+        settings
+    }
+
+    // The configured ROM directory, the current one if there is none
+    pub fn get_rom_dir(&self) -> PathBuf {
+        match &self.rom_dir {
+            Some(directory) if directory.starts_with("~/") => match std::env::var_os("HOME") {
+                Some(home) => PathBuf::from(home).join(&directory[2..]),
+                None => PathBuf::from(directory)
+            },
+            Some(directory) => PathBuf::from(directory),
+            None => PathBuf::from(".")
+        }
+    }
+
+    pub fn rom_bindings(&self, hash: &str) -> Option<&BindingsFile> {
+        self.rom.get(hash).and_then(|settings| settings.bindings.as_ref())
+    }
+
+    // Stores one setting for the ROM in rom-settings.toml, only if the user asked for it.
+    // That file belongs to the emulator, config.toml is never rewritten.
+    pub fn save_rom_setting(&self, hash: &str, key: &str, value: toml::Value) -> Result<(), String> {
+        if !self.save_rom_settings {
+            return Ok(());
+        }
+        let path = saved_settings_file().ok_or_else(|| "no config directory".to_string())?;
+        let mut document = match fs::read_to_string(&path) {
+            Ok(text) => text.parse::<toml::Value>().map_err(|error| format!("{}: {}", path.display(), error))?,
+            Err(_) => toml::Value::Table(toml::value::Table::new())
+        };
+        let root = document.as_table_mut().ok_or_else(|| "saved settings are not a table".to_string())?;
+        let roms = root.entry("rom".to_string())
+            .or_insert_with(|| toml::Value::Table(toml::value::Table::new()))
+            .as_table_mut()
+            .ok_or_else(|| "[rom] is not a table".to_string())?;
+        let section = roms.entry(hash.to_string())
+            .or_insert_with(|| toml::Value::Table(toml::value::Table::new()))
+            .as_table_mut()
+            .ok_or_else(|| format!("[rom.{}] is not a table", hash))?;
+        section.insert(key.to_string(), value);
+
+        if let Some(directory) = path.parent() {
+            fs::create_dir_all(directory).map_err(|error| error.to_string())?;
+        }
+        let text = toml::to_string(&document).map_err(|error| error.to_string())?;
+        fs::write(&path, text).map_err(|error| format!("{}: {}", path.display(), error))
+    }
+}
+
+fn read_toml<T: serde::de::DeserializeOwned>(path: &Path) -> Result<T, String> {
+    fs::read_to_string(path)
+        .map_err(|error| error.to_string())
+        .and_then(|text| toml::from_str(&text).map_err(|error| error.to_string()))
+}
+
+// A missing file means nothing was saved yet, a broken one is reported and ignored
+fn load_saved_settings() -> HashMap<String, Settings> {
+    let path = match saved_settings_file() {
+        Some(path) if path.is_file() => path,
+        _ => return HashMap::new()
+    };
+    match read_toml::<SavedSettings>(&path) {
+        Ok(saved) => {
+            for (hash, settings) in saved.rom.iter() {
+                for key in settings.unknown.keys() {
+                    eprintln!("Ignoring unknown setting rom.{}.{} in {}", hash, key, path.display());
+                }
+            }
+            saved.rom
+        }
+        Err(error) => {
+            eprintln!("Ignoring {}: {}", path.display(), error);
+            HashMap::new()
+        }
+    }
+}
+
+// $XDG_CONFIG_HOME/rusty-chip8, falling back to ~/.config/rusty-chip8
+pub fn config_dir() -> Option<PathBuf> {
+    let base = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|directory| !directory.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(base.join("rusty-chip8"))
+}
+
+pub fn config_file() -> Option<PathBuf> {
+    config_dir().map(|directory| directory.join("config.toml"))
+}
+
+pub fn saved_settings_file() -> Option<PathBuf> {
+    config_dir().map(|directory| directory.join("rom-settings.toml"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(text: &str) -> Config {
+        toml::from_str(text).unwrap()
+    }
+
+    #[test]
+    fn layers_in_order() {
+        let mut config = config(r#"
+            ips = 700
+            palette = "amber"
+            scale = 4
+
+            [rom.abc]
+            palette = "green"
+            quirks = "schip"
+        "#);
+        config.saved.insert("abc".to_string(), Settings { palette: Some("lcd".to_string()), ..Settings::default() });
+        let recommended = Settings {
+            ips: Some(900),
+            quirks: Some("vip".to_string()),
+            scale: Some(3),
+            mute: Some(true),
+            ..Settings::default()
+        };
+        let command_line = Settings { ips: Some(1000), ..Settings::default() };
+
+        let settings = config.for_rom("abc", &recommended);
+        assert_eq!(settings.ips, Some(700)); // config over the database
+        assert_eq!(settings.scale, Some(4));
+        assert_eq!(settings.mute, Some(true)); // only the database has it
+        assert_eq!(settings.quirks.as_deref(), Some("schip")); // ROM section over the database
+        assert_eq!(settings.palette.as_deref(), Some("lcd")); // saved over the ROM section
+        let resolved = settings.overlay(&command_line).resolve().unwrap();
+        assert_eq!(resolved.ips, 1000);
+        assert_eq!(resolved.quirks, Quirks::from_preset("schip").unwrap());
+
+        // another ROM only shares the top level
+        let settings = config.for_rom("def", &recommended);
+        assert_eq!(settings.quirks.as_deref(), Some("vip"));
+        assert_eq!(settings.palette.as_deref(), Some("amber"));
+
+        // nothing set anywhere leaves the built-in defaults
+        let resolved = Config::default().for_rom("abc", &Settings::default()).resolve().unwrap();
+        assert_eq!(resolved.ips, DEFAULT_IPS);
+        assert_eq!(resolved.quirks, Quirks::new());
+        assert_eq!(resolved.palette_name, None);
+    }
+
+    #[test]
+    fn unknown_keys() {
+        let config = config(r#"
+            ipss = 900
+            save_rom_settings = true
+            rom_dir = "~/chip8"
+            detect_quirks = false
+
+            [bindings.hotkeys]
+            pause = ["Space"]
+
+            [rom.abc]
+            palete = "green"
+            ips = 800
+        "#);
+        assert_eq!(config.unknown_keys(), vec!["ipss", "rom.abc.palete"]);
+        assert_eq!(config.defaults.ips, None);
+        assert_eq!(config.rom["abc"].ips, Some(800));
+        assert!(config.defaults.bindings.is_some());
+    }
+
+    #[test]
+    fn decay_range() {
+        let decay = |decay: f32| Settings { decay: Some(decay), ..Settings::default() }.resolve();
+        assert!(decay(0.0).is_ok());
+        assert!(decay(1.0).is_ok());
+        assert!(decay(1.5).is_err());
+        assert!(decay(-0.1).is_err());
+        assert_eq!(decay(f32::NAN).err(), Some("decay NaN is not between 0 and 1".to_string()));
+    }
+}
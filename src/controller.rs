@@ -0,0 +1,209 @@
+extern crate sdl2;
+extern crate serde;
+
+use sdl2::controller::{Axis, Button};
+use serde::Deserialize;
+
+use std::collections::HashMap;
+
+// How far a stick has to be pushed (out of 32767) before it counts as a press
+const DEFAULT_THRESHOLD: i16 = 16000;
+
+// The D-pad covers the 2/4/6/8 directions most games use, A is the usual
+// "fire" key 5 and the other face buttons take a few common extras
+const DEFAULT_BUTTONS: [(Button, u8); 8] = [
+    (Button::DPadUp, 0x2),
+    (Button::DPadDown, 0x8),
+    (Button::DPadLeft, 0x4),
+    (Button::DPadRight, 0x6),
+    (Button::A, 0x5),
+    (Button::B, 0x0),
+    (Button::X, 0xa),
+    (Button::Y, 0xb)
+];
+
+const DEFAULT_AXES: [(Axis, Direction, u8); 4] = [
+    (Axis::LeftX, Direction::Negative, 0x4),
+    (Axis::LeftX, Direction::Positive, 0x6),
+    (Axis::LeftY, Direction::Negative, 0x2),
+    (Axis::LeftY, Direction::Positive, 0x8)
+];
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Direction {
+    Negative,
+    Positive
+}
+
+/*
+ * The [controller] table of a bindings file, e.g.
+ *
+ *   [controller]
+ *   threshold = 12000
+ *
+ *   [controller.buttons]
+ *   a = "5"
+ *   start = "F"
+ *
+ *   [controller.axes]
+ *   "leftx-" = "4"
+ *   "leftx+" = "6"
+ *
+ * Names are SDL's game controller names. An empty string unbinds a control.
+ */
+#[derive(Deserialize, Default, Clone)]
+pub struct ControllerFile {
+    pub threshold: Option<i16>,
+    #[serde(default)]
+    pub buttons: HashMap<String, String>,
+    #[serde(default)]
+    pub axes: HashMap<String, String>
+}
+
+#[derive(Clone)]
+pub struct ControllerBindings {
+    buttons: HashMap<Button, u8>,
+    axes: HashMap<(Axis, Direction), u8>,
+    threshold: i16
+}
+
+impl ControllerBindings {
+    pub fn new() -> ControllerBindings {
+        ControllerBindings {
+            buttons: DEFAULT_BUTTONS.iter().cloned().collect(),
+            axes: DEFAULT_AXES.iter().map(|(axis, direction, key)| ((*axis, *direction), *key)).collect(),
+            threshold: DEFAULT_THRESHOLD
+        }
+    }
+
+    pub fn apply(&mut self, file: &ControllerFile) -> Result<(), String> {
+        if let Some(threshold) = file.threshold {
+            self.threshold = threshold.max(1);
+        }
+        for (name, key) in file.buttons.iter() {
+            let button = Button::from_string(name)
+                .ok_or_else(|| format!("{} is not a controller button", name))?;
+            match parse_key(key)? {
+                Some(key) => self.buttons.insert(button, key),
+                None => self.buttons.remove(&button)
+            };
+        }
+        for (name, key) in file.axes.iter() {
+            let (axis_name, direction) = if name.ends_with('+') {
+                (name.trim_end_matches('+'), Direction::Positive)
+            } else if name.ends_with('-') {
+                (name.trim_end_matches('-'), Direction::Negative)
+            } else {
+                return Err(format!("{} needs a + or - direction", name));
+            };
+            let axis = Axis::from_string(axis_name)
+                .ok_or_else(|| format!("{} is not a controller axis", axis_name))?;
+            match parse_key(key)? {
+                Some(key) => self.axes.insert((axis, direction), key),
+                None => self.axes.remove(&(axis, direction))
+            };
+        }
+        Ok(())
+    }
+
+    pub fn button_key(&self, button: Button) -> Option<u8> {
+        self.buttons.get(&button).cloned()
+    }
+
+    pub fn axis_key(&self, axis: Axis, direction: Direction) -> Option<u8> {
+        self.axes.get(&(axis, direction)).cloned()
+    }
+
+    // Which directions of the axis are pushed past the threshold
+    pub fn axis_state(&self, value: i16) -> [(Direction, bool); 2] {
+        [
+            (Direction::Negative, value < -self.threshold),
+            (Direction::Positive, value > self.threshold)
+        ]
+    }
+}
+
+fn parse_key(key: &str) -> Result<Option<u8>, String> {
+    if key.is_empty() {
+        return Ok(None);
+    }
+    u8::from_str_radix(key, 16).ok()
+        .filter(|key| (*key as usize) < super::KEYPAD_SIZE)
+        .map(Some)
+        .ok_or_else(|| format!("{} is not a CHIP-8 key, expected 0 to F", key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bindings(text: &str) -> Result<ControllerBindings, String> {
+        let file: ControllerFile = toml::from_str(text).unwrap();
+        let mut bindings = ControllerBindings::new();
+        bindings.apply(&file)?;
+        Ok(bindings)
+    }
+
+    #[test]
+    fn parses_keys() {
+        assert_eq!(parse_key("5"), Ok(Some(0x5)));
+        assert_eq!(parse_key("f"), Ok(Some(0xf)));
+        assert_eq!(parse_key("F"), Ok(Some(0xf)));
+        assert_eq!(parse_key(""), Ok(None));
+        assert!(parse_key("10").is_err());
+        assert!(parse_key("g").is_err());
+    }
+
+    #[test]
+    fn defaults() {
+        let bindings = ControllerBindings::new();
+        assert_eq!(bindings.button_key(Button::DPadUp), Some(0x2));
+        assert_eq!(bindings.button_key(Button::A), Some(0x5));
+        assert_eq!(bindings.button_key(Button::Start), None);
+        assert_eq!(bindings.axis_key(Axis::LeftY, Direction::Negative), Some(0x2));
+        assert_eq!(bindings.axis_key(Axis::LeftX, Direction::Positive), Some(0x6));
+    }
+
+    #[test]
+    fn applies_buttons_and_axes() {
+        let bindings = bindings("
+            [buttons]
+            start = \"F\"
+            a = \"\"
+            [axes]
+            \"leftx-\" = \"1\"
+            \"righty+\" = \"c\"
+        ").unwrap();
+        assert_eq!(bindings.button_key(Button::Start), Some(0xf));
+        assert_eq!(bindings.button_key(Button::A), None);
+        assert_eq!(bindings.button_key(Button::B), Some(0x0));
+        assert_eq!(bindings.axis_key(Axis::LeftX, Direction::Negative), Some(0x1));
+        assert_eq!(bindings.axis_key(Axis::RightY, Direction::Positive), Some(0xc));
+        assert_eq!(bindings.axis_key(Axis::LeftX, Direction::Positive), Some(0x6));
+    }
+
+    #[test]
+    fn rejects_bad_bindings() {
+        assert!(bindings("[buttons]\nturbo = \"1\"").is_err());
+        assert!(bindings("[buttons]\na = \"x\"").is_err());
+        assert!(bindings("[axes]\nleftx = \"1\"").is_err());
+        assert!(bindings("[axes]\n\"sideways+\" = \"1\"").is_err());
+    }
+
+    #[test]
+    fn axis_threshold() {
+        let bindings = ControllerBindings::new();
+        assert_eq!(bindings.axis_state(0), [(Direction::Negative, false), (Direction::Positive, false)]);
+        assert_eq!(bindings.axis_state(DEFAULT_THRESHOLD), [(Direction::Negative, false), (Direction::Positive, false)]);
+        assert_eq!(bindings.axis_state(DEFAULT_THRESHOLD + 1), [(Direction::Negative, false), (Direction::Positive, true)]);
+        assert_eq!(bindings.axis_state(-DEFAULT_THRESHOLD - 1), [(Direction::Negative, true), (Direction::Positive, false)]);
+        assert_eq!(bindings.axis_state(i16::MIN), [(Direction::Negative, true), (Direction::Positive, false)]);
+
+        let lowered = self::bindings("threshold = 12000").unwrap();
+        assert_eq!(lowered.axis_state(12001), [(Direction::Negative, false), (Direction::Positive, true)]);
+        // a threshold of 0 would count a stick at rest as pushed
+        let zero = self::bindings("threshold = 0").unwrap();
+        assert_eq!(zero.axis_state(0), [(Direction::Negative, false), (Direction::Positive, false)]);
+        assert_eq!(zero.axis_state(2), [(Direction::Negative, false), (Direction::Positive, true)]);
+    }
+}
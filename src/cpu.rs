@@ -1,19 +1,100 @@
-extern crate rand;
-extern crate device_query;
-
-use rand::Rng;
-use device_query::{DeviceQuery, DeviceState, Keycode};
+use core::fmt;
 
 use super::font::FONT_SET as FONT_SET;
-use super::RomLoader;
-use super::keys::convert_input;
+use super::instruction::Instruction;
+use super::quirks::Quirks;
+use super::rng::XorShift;
+#[cfg(feature = "std")]
+use super::rom_loader::RomLoader;
 
 const OPCODE_SIZE: u16 = 2;
 
+// A ROM that does not fit in memory where it was meant to go
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct LoadError {
+    pub length: usize,
+    pub address: u16
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a {} byte ROM does not fit in memory at {:#05x}", self.length, self.address)
+    }
+}
+
+// Why the program cannot go on, with the address of the instruction that
+// stopped it. The cpu is left at that instruction.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CpuError {
+    InvalidOpcode(u16, u16),
+    StackUnderflow(u16), // 00EE with nothing to return to
+    StackOverflow(u16) // 2NNN with every stack entry in use
+}
+
+impl fmt::Display for CpuError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CpuError::InvalidOpcode(address, opcode) => write!(f, "invalid opcode {:04x} at {:#05x}", opcode, address),
+            CpuError::StackUnderflow(address) => write!(f, "return at {:#05x} with nothing on the stack", address),
+            CpuError::StackOverflow(address) => write!(f, "call at {:#05x} with all {} stack entries in use", address, super::STACK_SIZE)
+        }
+    }
+}
+
+// A save state is always this many bytes, see Cpu::save_state
+pub const STATE_SIZE: usize = STATE_MAGIC.len() + 1 + super::REGISTER_COUNT + 2 + 2 + 1 + super::STACK_SIZE * 2 + 2
+    + super::RAM_SIZE + super::WIDTH * super::HEIGHT / 8 + 2 + 1 + 1 + 16;
+const STATE_MAGIC: &[u8; 4] = b"C8ST";
+const STATE_VERSION: u8 = 1;
+
+// Why a save state was turned down
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum StateError {
+    WrongSize(usize),
+    NotAState,
+    UnknownVersion(u8),
+    Corrupt
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateError::WrongSize(size) => write!(f, "a save state is {} bytes, not {}", STATE_SIZE, size),
+            StateError::NotAState => write!(f, "not a save state"),
+            StateError::UnknownVersion(version) => write!(f, "save state version {} is not supported", version),
+            StateError::Corrupt => write!(f, "the save state is damaged")
+        }
+    }
+}
+
+// Reads or writes a save state front to back
+struct StateCursor<'a> {
+    state: &'a mut [u8],
+    position: usize
+}
+
+impl<'a> StateCursor<'a> {
+    fn put(&mut self, bytes: &[u8]) {
+        self.state[self.position..self.position + bytes.len()].copy_from_slice(bytes);
+        self.position += bytes.len();
+    }
+
+    fn take(&mut self, length: usize) -> &[u8] {
+        self.position += length;
+        &self.state[self.position - length..self.position]
+    }
+
+    fn take_u16(&mut self) -> u16 {
+        let bytes = self.take(2);
+        u16::from_le_bytes([bytes[0], bytes[1]])
+    }
+}
+
 pub struct DisplayData<'a> {
     pub display: &'a [[u8; super::WIDTH]; super::HEIGHT]
 }
 
+#[derive(Clone)]
 pub struct Cpu {
     v: [u8; super::REGISTER_COUNT], 
     i: u16, // range 0x000 - 0xFFF
@@ -23,10 +104,16 @@ pub struct Cpu {
     stack: [u16; super::STACK_SIZE],
     sp: u16, // stack pointer
     memory: [u8; super::RAM_SIZE],
+    // instructions decoded at each address, cleared when that memory changes
+    #[cfg(feature = "decode-cache")]
+    cache: [Option<Instruction>; super::RAM_SIZE],
+    written: Option<(usize, usize)>, // first and last address written since the JIT last looked
     display: [[u8; super::WIDTH]; super::HEIGHT],
     draw_flag: bool,
     keypad: [bool; super::KEYPAD_SIZE],
-    device_state: DeviceState
+    key_wait: Option<u8>, // register FX0A is waiting to fill
+    quirks: Quirks,
+    rng: XorShift // for CXKK, seedable so runs can be repeated
 }
 
 impl Cpu {
@@ -45,13 +132,42 @@ impl Cpu {
             stack : [0; super::STACK_SIZE],
             sp: 0,
             memory: memory,
+            #[cfg(feature = "decode-cache")]
+            cache: [None; super::RAM_SIZE],
+            written: None,
             display: [[0; super::WIDTH]; super::HEIGHT],
             draw_flag: false,
             keypad: [false; super::KEYPAD_SIZE],
-            device_state: DeviceState::new()
+            key_wait: None,
+            quirks: Quirks::new(),
+            rng: Cpu::unseeded_rng()
         }
     }
 
+    #[cfg(feature = "std")]
+    fn unseeded_rng() -> XorShift {
+        XorShift::from_entropy()
+    }
+
+    // Without std there is nothing to seed from, call set_seed with whatever
+    // the platform has, e.g. a timer read when a key is first pressed
+    #[cfg(not(feature = "std"))]
+    fn unseeded_rng() -> XorShift {
+        XorShift::from_seed(0)
+    }
+
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.quirks = quirks;
+    }
+
+    pub fn get_quirks(&self) -> Quirks {
+        self.quirks
+    }
+
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = XorShift::from_seed(seed);
+    }
+
     pub fn get_draw_flag(&self) -> bool {
         self.draw_flag.clone()
     }
@@ -62,13 +178,253 @@ impl Cpu {
         }
     }
 
+    pub fn get_memory(&self) -> &[u8; super::RAM_SIZE] {
+        &self.memory
+    }
+
+    // For debugging tools, e.g. the memory viewer
+    pub fn set_memory(&mut self, address: u16, value: u8) {
+        self.write_memory(address as usize % super::RAM_SIZE, value);
+    }
+
+    // Every write goes through here so no stale instruction stays cached,
+    // including the one starting a byte earlier
+    fn write_memory(&mut self, address: usize, value: u8) {
+        self.memory[address] = value;
+        self.mark_written(address, address);
+    }
+
+    // For the JIT, after compiled code wrote from first to last
+    pub fn mark_written(&mut self, first: usize, last: usize) {
+        #[cfg(feature = "decode-cache")]
+        {
+            for address in first..=last {
+                self.cache[address] = None;
+            }
+            self.cache[first.checked_sub(1).unwrap_or(super::RAM_SIZE - 1)] = None;
+        }
+        self.written = Some(match self.written {
+            Some((written_first, written_last)) => (written_first.min(first), written_last.max(last)),
+            None => (first, last)
+        });
+    }
+
+    // For the JIT: the addresses written since the last call, so compiled
+    // code there can be thrown away
+    pub fn take_written(&mut self) -> Option<(usize, usize)> {
+        self.written.take()
+    }
+
+    pub fn get_registers(&self) -> &[u8; super::REGISTER_COUNT] {
+        &self.v
+    }
+
+    // For debugging tools, e.g. freezing a register with the cheat console
+    pub fn set_register(&mut self, register: u8, value: u8) {
+        self.v[register as usize % super::REGISTER_COUNT] = value;
+    }
+
+    pub fn get_pc(&self) -> u16 {
+        self.pc
+    }
+
+    // For debugging tools, wrapped into memory like every other jump
+    pub fn set_pc(&mut self, pc: u16) {
+        self.pc = pc & 0x0FFF;
+    }
+
+    pub fn get_i(&self) -> u16 {
+        self.i
+    }
+
+    // I is a full 16 bits, only the addresses made from it wrap
+    pub fn set_i(&mut self, i: u16) {
+        self.i = i;
+    }
+
+    // For the JIT: the V registers, I, the timers and memory, which compiled
+    // code reads and writes in place
+    pub fn get_jit_state(&mut self) -> (&mut [u8; super::REGISTER_COUNT], &mut u16, &mut u8, &mut u8, &mut [u8; super::RAM_SIZE]) {
+        (&mut self.v, &mut self.i, &mut self.delay_timer, &mut self.sound_timer, &mut self.memory)
+    }
+
+    // For the JIT: carries on at pc after a compiled block, which never draws
+    pub fn end_block(&mut self, pc: u16) {
+        self.pc = pc & 0x0FFF;
+        self.draw_flag = false;
+    }
+
+    // The first difference from another cpu, for checking one engine against
+    // another. The random number generator and caches are left out.
+    #[cfg(feature = "std")]
+    pub fn compare(&self, other: &Cpu) -> Option<String> {
+        if let Some(register) = (0..super::REGISTER_COUNT).find(|register| self.v[*register] != other.v[*register]) {
+            return Some(format!("V{:X} is {:#04x}, expected {:#04x}", register, self.v[register], other.v[register]));
+        }
+        if self.pc != other.pc {
+            return Some(format!("PC is {:#05x}, expected {:#05x}", self.pc, other.pc));
+        }
+        if self.i != other.i {
+            return Some(format!("I is {:#05x}, expected {:#05x}", self.i, other.i));
+        }
+        if self.sp != other.sp || self.stack != other.stack {
+            return Some(format!("the stack is {:x?}, expected {:x?}", &self.stack[..self.sp as usize], &other.stack[..other.sp as usize]));
+        }
+        if self.delay_timer != other.delay_timer || self.sound_timer != other.sound_timer {
+            return Some(format!("the timers are {} and {}, expected {} and {}", self.delay_timer, self.sound_timer, other.delay_timer, other.sound_timer));
+        }
+        if self.key_wait != other.key_wait {
+            return Some(format!("waiting for a key into {:?}, expected {:?}", self.key_wait, other.key_wait));
+        }
+        if let Some(address) = (0..super::RAM_SIZE).find(|address| self.memory[*address] != other.memory[*address]) {
+            return Some(format!("memory at {:#05x} is {:#04x}, expected {:#04x}", address, self.memory[address], other.memory[address]));
+        }
+        if self.display != other.display {
+            return Some("the screen differs".to_string());
+        }
+        None
+    }
+
+    #[cfg(feature = "std")]
     pub fn load_program(&mut self, rom_loader: &RomLoader) {
-        let program = rom_loader.get_data();
-        let length = rom_loader.get_length();
-        for i in 0..length {
-            // println!("testing byte: {}", program[i]); // DEBUG
-            self.memory[i + super::INITIAL_PC as usize] = program[i];
+        self.load_program_at(rom_loader, super::INITIAL_PC)
+            .expect("ROM does not fit in memory");
+    }
+
+    // Some ROMs, e.g. ETI 660 ones, expect to be loaded somewhere other than 0x200
+    #[cfg(feature = "std")]
+    pub fn load_program_at(&mut self, rom_loader: &RomLoader, address: u16) -> Result<(), String> {
+        self.load_rom_at(&rom_loader.get_data()[..rom_loader.get_length()], address)
+            .map_err(|error| error.to_string())
+    }
+
+    // For when there is no file to load from, e.g. a ROM baked into the
+    // firmware or handed over by JavaScript
+    pub fn load_rom_at(&mut self, rom: &[u8], address: u16) -> Result<(), LoadError> {
+        let start = address as usize;
+        if start < FONT_SET.len() || start + rom.len() > super::RAM_SIZE {
+            return Err(LoadError {
+                length: rom.len(),
+                address: address
+            });
+        }
+        for (i, &byte) in rom.iter().enumerate() {
+            self.write_memory(i + start, byte);
+        }
+        self.pc = address;
+        Ok(())
+    }
+
+    // Everything that makes up the machine, the same on every platform and
+    // version: registers, stack, timers, memory, the screen at a bit a pixel,
+    // the keypad, quirks and the random number generator, little endian
+    pub fn save_state(&self) -> [u8; STATE_SIZE] {
+        let mut state = [0u8; STATE_SIZE];
+        let mut cursor = StateCursor {
+            state: &mut state,
+            position: 0
+        };
+        cursor.put(STATE_MAGIC);
+        cursor.put(&[STATE_VERSION]);
+        cursor.put(&self.v);
+        cursor.put(&self.i.to_le_bytes());
+        cursor.put(&self.pc.to_le_bytes());
+        cursor.put(&[self.sp as u8]);
+        for address in self.stack.iter() {
+            cursor.put(&address.to_le_bytes());
         }
+        cursor.put(&[self.delay_timer, self.sound_timer]);
+        cursor.put(&self.memory);
+        for row in self.display.iter() {
+            for pixels in row.chunks(8) {
+                cursor.put(&[pixels.iter().fold(0, |byte, pixel| byte << 1 | pixel)]);
+            }
+        }
+        let keys = (0..super::KEYPAD_SIZE).fold(0u16, |keys, key| keys | (self.keypad[key] as u16) << key);
+        cursor.put(&keys.to_le_bytes());
+        cursor.put(&[self.key_wait.unwrap_or(0xFF), self.quirks.to_bits()]);
+        for word in self.rng.get_state().iter() {
+            cursor.put(&word.to_le_bytes());
+        }
+        state
+    }
+
+    // Nothing changes unless the whole state is good
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), StateError> {
+        if state.len() != STATE_SIZE {
+            return Err(StateError::WrongSize(state.len()));
+        }
+        if &state[..STATE_MAGIC.len()] != STATE_MAGIC {
+            return Err(StateError::NotAState);
+        }
+        if state[STATE_MAGIC.len()] != STATE_VERSION {
+            return Err(StateError::UnknownVersion(state[STATE_MAGIC.len()]));
+        }
+        let mut copy = [0u8; STATE_SIZE];
+        copy.copy_from_slice(state);
+        let mut cursor = StateCursor {
+            state: &mut copy,
+            position: STATE_MAGIC.len() + 1
+        };
+        let mut v = [0u8; super::REGISTER_COUNT];
+        v.copy_from_slice(cursor.take(super::REGISTER_COUNT));
+        let i = cursor.take_u16();
+        let pc = cursor.take_u16();
+        let sp = cursor.take(1)[0] as u16;
+        let mut stack = [0u16; super::STACK_SIZE];
+        for address in stack.iter_mut() {
+            *address = cursor.take_u16();
+        }
+        let timers = cursor.take(2);
+        let (delay_timer, sound_timer) = (timers[0], timers[1]);
+        let mut memory = [0u8; super::RAM_SIZE];
+        memory.copy_from_slice(cursor.take(super::RAM_SIZE));
+        let mut display = [[0u8; super::WIDTH]; super::HEIGHT];
+        for row in display.iter_mut() {
+            for pixels in row.chunks_mut(8) {
+                let byte = cursor.take(1)[0];
+                for (bit, pixel) in pixels.iter_mut().enumerate() {
+                    *pixel = byte >> (7 - bit) & 1;
+                }
+            }
+        }
+        let keys = cursor.take_u16();
+        let flags = cursor.take(2);
+        let (key_wait, quirks) = (flags[0], Quirks::from_bits(flags[1]));
+        let mut words = [0u32; 4];
+        for word in words.iter_mut() {
+            let bytes = cursor.take(4);
+            *word = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+        let rng = match XorShift::from_state(words) {
+            Some(rng) if sp as usize <= super::STACK_SIZE && (pc as usize) < super::RAM_SIZE
+                && ((key_wait as usize) < super::REGISTER_COUNT || key_wait == 0xFF) => rng,
+            _ => return Err(StateError::Corrupt)
+        };
+
+        self.v = v;
+        self.i = i;
+        self.pc = pc;
+        self.sp = sp;
+        self.stack = stack;
+        self.delay_timer = delay_timer;
+        self.sound_timer = sound_timer;
+        self.memory = memory;
+        self.display = display;
+        for key in 0..super::KEYPAD_SIZE {
+            self.keypad[key] = keys >> key & 1 != 0;
+        }
+        self.key_wait = if key_wait == 0xFF { None } else { Some(key_wait) };
+        self.quirks = quirks;
+        self.rng = rng;
+        // all of memory may have changed and the screen needs drawing
+        self.mark_written(0, super::RAM_SIZE - 1);
+        self.draw_flag = true;
+        Ok(())
+    }
+
+    pub fn is_sound_active(&self) -> bool {
+        self.sound_timer > 0
     }
 
     pub fn update_timer(&mut self) {
@@ -76,113 +432,121 @@ impl Cpu {
             self.delay_timer -= 1;
         }
         if self.sound_timer > 0  {
-            if self.sound_timer == 1  {
-                println!("this would be a SOUND");
-            }
             self.sound_timer -= 1;
         }
     }
 
-    pub fn get_input(&mut self) -> Option<u8> {
-        self.keypad = [false; super::KEYPAD_SIZE];
-        let keys:Vec<Keycode> = self.device_state.get_keys();
-        for key in keys.iter() {
-            match convert_input(key) {
-                Some(keycode) => {
-                    // self.v[reg_x as usize] = keycode;
-                    if keycode == 0x10 {
-                        std::process::exit(0);
-                    }
-                    self.keypad[keycode as usize] = true;
-                    println!("keypad input");
-                    for key in self.keypad.iter() {
-                        print!("{}", key);
-                    }
-                    println!();
-                    return Some(keycode);
-                }
-                None => {
-                    println!("invalid key");
-                }
+    // Keypad state comes from whichever frontend is driving the cpu
+    pub fn set_key(&mut self, key: u8, pressed: bool) {
+        let key = key as usize & 0xF;
+        let was_pressed = self.keypad[key];
+        self.keypad[key] = pressed;
+        // Like the COSMAC VIP, FX0A finishes once the key is released again
+        if was_pressed && !pressed {
+            if let Some(register) = self.key_wait.take() {
+                self.v[register as usize] = key as u8;
             }
         }
-        return None;
     }
 
-    pub fn emulate_cycle(&mut self) {
+    pub fn clear_keypad(&mut self) {
+        self.keypad = [false; super::KEYPAD_SIZE];
+    }
+
+    pub fn is_waiting_for_key(&self) -> bool {
+        self.key_wait.is_some()
+    }
+
+    pub fn emulate_cycle(&mut self) -> Result<(), CpuError> {
         // fetch -> decode -> execute -> update -> repeat
-        self.get_input();
         self.draw_flag = false;
+        if self.is_waiting_for_key() {
+            return Ok(());
+        }
+        let instruction = self.fetch();
+        self.execute(instruction)
+    }
+
+    // Decoding only happens the first time an address runs, after that the
+    // cached instruction is used until the memory under it is written
+    #[cfg(feature = "decode-cache")]
+    fn fetch(&mut self) -> Instruction {
+        let pc = self.pc as usize;
+        if let Some(instruction) = self.cache[pc] {
+            return instruction;
+        }
+        let instruction = self.decode_at_pc();
+        self.cache[pc] = Some(instruction);
+        instruction
+    }
+
+    // Without the cache every cycle decodes again, for targets short on memory
+    #[cfg(not(feature = "decode-cache"))]
+    fn fetch(&mut self) -> Instruction {
+        self.decode_at_pc()
+    }
+
+    fn decode_at_pc(&self) -> Instruction {
+        let pc = self.pc as usize;
         // opcodes are two BYTES long, so need to fetch current byte plus one more byte and encode that
-        let opcode = (self.memory[self.pc as usize] as u16) << 8 | (self.memory[(self.pc + 1) as usize] as u16);
-        self.decode_opcode(opcode);
-        // println!("OPCODE: {:04x?}", opcode); // DEBUG
-        self.update_timer();
-    }
-
-    fn decode_opcode(&mut self, opcode: u16) {
-        // possible patterns of opcode variables:
-        // NNN: last three digits
-        // kk: last two digits
-        // X: second digit
-        // Y: third digit
-        let nnn = opcode & 0x0FFF;
-        let kk = (opcode & 0x00FF) as u8;
-        let x = ((opcode & 0x0F00) >> 8) as u8;
-        let y = ((opcode & 0x00F0) >> 4) as u8;
-        let n = (opcode & 0x000F) as u8;
-        let digits = ((opcode & 0xF000) >> 12, (opcode & 0x0F00) >> 8,
-                      (opcode & 0x00F0) >> 4,  (opcode & 0x000F));
-        match digits {
-            (0x0, 0x0, 0xE, 0x0) => self.execute_00E0(),
-            (0x0, 0x0, 0xE, 0xE) => self.execute_00EE(),
-            (0x1,   _,   _,   _) => self.execute_1NNN(nnn),
-            (0x2,   _,   _,   _) => self.execute_2NNN(nnn),
-            (0x3,   _,   _,   _) => self.execute_3XKK(x, kk),
-            (0x4,   _,   _,   _) => self.execute_4XKK(x, kk),
-            (0x5,   _,   _, 0x0) => self.execute_5XY0(x, y),
-            (0x6,   _,   _,   _) => self.execute_6XKK(x, kk),
-            (0x7,   _,   _,   _) => self.execute_7XKK(x, kk),
-            (0x8,   _,   _, 0x0) => self.execute_8XY0(x, y),
-            (0x8,   _,   _, 0x1) => self.execute_8XY1(x, y),
-            (0x8,   _,   _, 0x2) => self.execute_8XY2(x, y),
-            (0x8,   _,   _, 0x3) => self.execute_8XY3(x, y),
-            (0x8,   _,   _, 0x4) => self.execute_8XY4(x, y),
-            (0x8,   _,   _, 0x5) => self.execute_8XY5(x, y),
-            (0x8,   _,   _, 0x6) => self.execute_8XY6(x, y),
-            (0x8,   _,   _, 0x7) => self.execute_8XY7(x, y),
-            (0x8,   _,   _, 0xE) => self.execute_8XYE(x, y),
-            (0x9,   _,   _, 0x0) => self.execute_9XY0(x, y),
-            (0xA,   _,   _,   _) => self.execute_ANNN(nnn),
-            (0xB,   _,   _,   _) => self.execute_BNNN(nnn),
-            (0xC,   _,   _,   _) => self.execute_CXKK(x, kk),
-            (0xD,   _,   _,   _) => self.execute_DXYN(x, y, n),
-            (0xE,   _, 0x9, 0xE) => self.execute_EX9E(x),
-            (0xE,   _, 0xA, 0x1) => self.execute_EXA1(x),
-            (0xF,   _, 0x0, 0x7) => self.execute_FX07(x),
-            (0xF,   _, 0x0, 0xA) => self.execute_FX0A(x),
-            (0xF,   _, 0x1, 0x5) => self.execute_FX15(x),
-            (0xF,   _, 0x1, 0x8) => self.execute_FX18(x),
-            (0xF,   _, 0x1, 0xE) => self.execute_FX1E(x),
-            (0xF,   _, 0x2, 0x9) => self.execute_FX29(x),
-            (0xF,   _, 0x3, 0x3) => self.execute_FX33(x),
-            (0xF,   _, 0x5, 0x5) => self.execute_FX55(x),
-            (0xF,   _, 0x6, 0x5) => self.execute_FX65(x),
-            _ => panic!("Error: invalid opcode {:04x?}", opcode)
-        }
-     
+        // the second byte of an opcode at 0xFFF is the one at 0x000
+        let opcode = (self.memory[pc] as u16) << 8 | (self.memory[(pc + 1) % super::RAM_SIZE] as u16);
+        Instruction::decode(opcode)
+    }
+
+    pub fn execute(&mut self, instruction: Instruction) -> Result<(), CpuError> {
+        // X and Y are register numbers, KK a byte, NNN an address and N a nibble
+        match instruction {
+            Instruction::Cls => self.execute_00E0(),
+            Instruction::Ret => self.execute_00EE()?,
+            Instruction::Jp(nnn) => self.execute_1NNN(nnn),
+            Instruction::Call(nnn) => self.execute_2NNN(nnn)?,
+            Instruction::Se(x, kk) => self.execute_3XKK(x.0, kk),
+            Instruction::Sne(x, kk) => self.execute_4XKK(x.0, kk),
+            Instruction::SeReg(x, y) => self.execute_5XY0(x.0, y.0),
+            Instruction::Ld(x, kk) => self.execute_6XKK(x.0, kk),
+            Instruction::Add(x, kk) => self.execute_7XKK(x.0, kk),
+            Instruction::LdReg(x, y) => self.execute_8XY0(x.0, y.0),
+            Instruction::Or(x, y) => self.execute_8XY1(x.0, y.0),
+            Instruction::And(x, y) => self.execute_8XY2(x.0, y.0),
+            Instruction::Xor(x, y) => self.execute_8XY3(x.0, y.0),
+            Instruction::AddReg(x, y) => self.execute_8XY4(x.0, y.0),
+            Instruction::Sub(x, y) => self.execute_8XY5(x.0, y.0),
+            Instruction::Shr(x, y) => self.execute_8XY6(x.0, y.0),
+            Instruction::Subn(x, y) => self.execute_8XY7(x.0, y.0),
+            Instruction::Shl(x, y) => self.execute_8XYE(x.0, y.0),
+            Instruction::SneReg(x, y) => self.execute_9XY0(x.0, y.0),
+            Instruction::LdI(nnn) => self.execute_ANNN(nnn),
+            Instruction::JpV0(nnn) => self.execute_BNNN(nnn),
+            Instruction::Rnd(x, kk) => self.execute_CXKK(x.0, kk),
+            Instruction::Drw(x, y, n) => self.execute_DXYN(x.0, y.0, n),
+            Instruction::Skp(x) => self.execute_EX9E(x.0),
+            Instruction::Sknp(x) => self.execute_EXA1(x.0),
+            Instruction::LdRegDt(x) => self.execute_FX07(x.0),
+            Instruction::LdKey(x) => self.execute_FX0A(x.0),
+            Instruction::LdDt(x) => self.execute_FX15(x.0),
+            Instruction::LdSt(x) => self.execute_FX18(x.0),
+            Instruction::AddI(x) => self.execute_FX1E(x.0),
+            Instruction::LdFont(x) => self.execute_FX29(x.0),
+            Instruction::LdBcd(x) => self.execute_FX33(x.0),
+            Instruction::Store(x) => self.execute_FX55(x.0),
+            Instruction::Load(x) => self.execute_FX65(x.0),
+            Instruction::Unknown(opcode) => return Err(CpuError::InvalidOpcode(self.pc, opcode))
+        }
+        Ok(())
     }
 
+    // Addresses wrap around at the end of memory, like I does in DXYN
     fn next_instruction(&mut self) {
-        self.pc += OPCODE_SIZE;
+        self.pc = (self.pc + OPCODE_SIZE) & 0x0FFF;
     }
 
     fn skip_instruction(&mut self) {
-        self.pc += OPCODE_SIZE * 2;
+        self.pc = (self.pc + OPCODE_SIZE * 2) & 0x0FFF;
     }
 
     fn jump_instruction(&mut self, location: u16) {
-        self.pc = location;
+        self.pc = location & 0x0FFF;
     }
 
     fn skip_if_true(&mut self, condition: bool) {
@@ -205,9 +569,13 @@ impl Cpu {
     }
 
     // Return from subroutine in stack
-    fn execute_00EE(&mut self) {
+    fn execute_00EE(&mut self) -> Result<(), CpuError> {
+        if self.sp == 0 {
+            return Err(CpuError::StackUnderflow(self.pc));
+        }
         self.sp -= 1;
         self.jump_instruction(self.stack[self.sp as usize]);
+        Ok(())
     }
 
     // Jump to NNN
@@ -216,10 +584,14 @@ impl Cpu {
     }
 
     // Execute subroutine at NNN
-    fn execute_2NNN(&mut self, NNN: u16) {
+    fn execute_2NNN(&mut self, NNN: u16) -> Result<(), CpuError> {
+        if self.sp as usize == super::STACK_SIZE {
+            return Err(CpuError::StackOverflow(self.pc));
+        }
         self.stack[self.sp as usize] = self.pc + 2;
         self.sp += 1;
         self.jump_instruction(NNN);
+        Ok(())
     }
 
     // Compare VX to KK, if the same, skip next instruction
@@ -263,18 +635,27 @@ impl Cpu {
     // Set VX = VX | VY
     fn execute_8XY1(&mut self, X: u8, Y: u8) {
         self.v[X as usize] |= self.v[Y as usize];
+        if self.quirks.logic_resets_vf {
+            self.v[0xF] = 0;
+        }
         self.next_instruction();
     }
     
     // Set VX &= VY
     fn execute_8XY2(&mut self, X: u8, Y: u8) {
         self.v[X as usize] &= self.v[Y as usize];
+        if self.quirks.logic_resets_vf {
+            self.v[0xF] = 0;
+        }
         self.next_instruction();
     }
 
     // Set VX ^= VY
     fn execute_8XY3(&mut self, X: u8, Y: u8) {
         self.v[X as usize] ^= self.v[Y as usize];
+        if self.quirks.logic_resets_vf {
+            self.v[0xF] = 0;
+        }
         self.next_instruction();
     }
 
@@ -309,13 +690,9 @@ impl Cpu {
     // Set VX /= 2
     // Set VF = 1 if LSB of VX is 1
     fn execute_8XY6(&mut self, X: u8, Y: u8) {
-        let lsb: u8 = self.v[X as usize] & 0x0F;
-        if lsb == 1 {
-            self.v[0xF] = 1;
-        } else {
-            self.v[0xF] = 0;
-        }
-        self.v[X as usize] /= 2;
+        let value = if self.quirks.shift_uses_vy { self.v[Y as usize] } else { self.v[X as usize] };
+        self.v[X as usize] = value >> 1;
+        self.v[0xF] = value & 0x01;
         self.next_instruction();
     }
 
@@ -336,13 +713,9 @@ impl Cpu {
     // Set VX *= 2
     // If MSB of VS is 1, set VF 1
     fn execute_8XYE(&mut self, X: u8, Y: u8) {
-        let msb: u8 = (self.v[X as usize] & 0x80) >> 7;
-        if msb == 1 {
-            self.v[0xF] = 1;
-        } else {
-            self.v[0xF] = 0;
-        }
-        self.v[X as usize].wrapping_mul(2);
+        let value = if self.quirks.shift_uses_vy { self.v[Y as usize] } else { self.v[X as usize] };
+        self.v[X as usize] = value << 1;
+        self.v[0xF] = (value & 0x80) >> 7;
         self.next_instruction();
     }
 
@@ -358,13 +731,15 @@ impl Cpu {
     }
 
     // Set PC to NNN + V0
+    // With the jump quirk this is BXNN, jumping to XNN + VX
     fn execute_BNNN(&mut self, NNN: u16) {
-        self.jump_instruction(self.v[0] as u16 + NNN);
+        let register = if self.quirks.jump_uses_vx { (NNN >> 8) as usize } else { 0 };
+        self.jump_instruction((self.v[register] as u16 + NNN) & 0x0FFF);
     }
 
     // Set X to random byte & KK
     fn execute_CXKK(&mut self, X: u8, KK: u8) {
-        let random_number: u8 = rand::thread_rng().gen_range(0, 255);
+        let random_number = self.rng.next_u8();
         self.v[X as usize] = random_number & KK as u8; 
         self.next_instruction();
     }
@@ -373,15 +748,22 @@ impl Cpu {
     // VF is changed to 1 if any pixels are changed
     // Row of 8 pixels are read as bitcoded starting from memory location I
     fn execute_DXYN(&mut self, X: u8, Y: u8, N: u8) {
-        let value_x: u8 = self.v[X as usize];
-        let value_y: u8 = self.v[Y as usize];
+        // the starting position always wraps, the rest of the sprite may be clipped
+        let value_x = self.v[X as usize] as usize % super::WIDTH;
+        let value_y = self.v[Y as usize] as usize % super::HEIGHT;
         self.v[0xF] = 0; // default to 0
         // Taken from Starr Horne's implementation
-        for byte in 0..N {
-            let y = (self.v[Y as usize] + byte) as usize % super::HEIGHT;
+        for byte in 0..N as usize {
+            if self.quirks.clip_sprites && value_y + byte >= super::HEIGHT {
+                break;
+            }
+            let y = (value_y + byte) % super::HEIGHT;
             for bit in 0..8 {
-                let x = (self.v[X as usize] as u16 + bit) as usize % super::WIDTH;
-                let color = (self.memory[(self.i + byte as u16) as usize] >> (7 - bit)) & 1;
+                if self.quirks.clip_sprites && value_x + bit >= super::WIDTH {
+                    break;
+                }
+                let x = (value_x + bit) % super::WIDTH;
+                let color = (self.memory[(self.i as usize + byte) % super::RAM_SIZE] >> (7 - bit)) & 1;
                 self.v[0xF] |= color & self.display[y][x];
                 self.display[y][x] ^= color;
             }
@@ -391,14 +773,15 @@ impl Cpu {
     }
 
     // Skip next instruction if key at VX is pressed
+    // Only the low nibble of VX picks the key, like set_key
     fn execute_EX9E(&mut self, X: u8) {
-        let key: u8 = self.v[X as usize];
+        let key: u8 = self.v[X as usize] & 0xF;
         self.skip_if_true(self.keypad[key as usize]);
     }
 
     // Skip next instruction if key at VX is not pressed
     fn execute_EXA1(&mut self, X: u8) {
-        let key: u8 = self.v[X as usize];
+        let key: u8 = self.v[X as usize] & 0xF;
         self.skip_if_true(!self.keypad[key as usize]);
     }
 
@@ -409,13 +792,9 @@ impl Cpu {
     }
 
     // Wait for key press then store value in VX
+    // The cpu stops executing until set_key sees the key go down and up again
     fn execute_FX0A(&mut self, X: u8) {
-        let new_key_pressed = loop {
-            if let Some(key) = self.get_input() {
-                break key;
-            }
-        };
-        self.v[X as usize] = new_key_pressed;
+        self.key_wait = Some(X);
         self.next_instruction();
     }
 
@@ -433,7 +812,7 @@ impl Cpu {
 
     // Set I += VX
     fn execute_FX1E(&mut self, X: u8) {
-        self.i += self.v[X as usize] as u16;
+        self.i = self.i.wrapping_add(self.v[X as usize] as u16);
         self.next_instruction();
     }
 
@@ -443,19 +822,27 @@ impl Cpu {
         self.next_instruction();
     }
 
+    // The memory address `offset` bytes on from I, wrapping like DXYN
+    fn address_from_i(&self, offset: u8) -> usize {
+        (self.i as usize + offset as usize) % super::RAM_SIZE
+    }
+
     // Store binary decimal representation of VX starting at I
     fn execute_FX33(&mut self, X: u8) {
         let value_x = self.v[X as usize];
-        self.memory[self.i as usize] = value_x / 100;
-        self.memory[(self.i + 1) as usize] = (value_x % 100) / 10;
-        self.memory[(self.i + 2) as usize] = value_x % 10;
+        self.write_memory(self.address_from_i(0), value_x / 100);
+        self.write_memory(self.address_from_i(1), (value_x % 100) / 10);
+        self.write_memory(self.address_from_i(2), value_x % 10);
         self.next_instruction();
     }
 
     // Store registers from V0 to VX into memory starting at I
     fn execute_FX55(&mut self, X: u8) {
         for register in 0..(X + 1) {
-            self.memory[(self.i + register as u16) as usize] = self.v[register as usize];
+            self.write_memory(self.address_from_i(register), self.v[register as usize]);
+        }
+        if self.quirks.load_store_increments_i {
+            self.i = self.i.wrapping_add(X as u16 + 1);
         }
         self.next_instruction();
     }
@@ -463,8 +850,117 @@ impl Cpu {
     // Store registers from V0 to VX from memory starting at I
     fn execute_FX65(&mut self, X: u8) {
         for register in 0..(X + 1) {
-            self.v[register as usize] = self.memory[(self.i + register as u16) as usize];
+            self.v[register as usize] = self.memory[self.address_from_i(register)];
+        }
+        if self.quirks.load_store_increments_i {
+            self.i = self.i.wrapping_add(X as u16 + 1);
         }
         self.next_instruction();
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    extern crate alloc;
+
+    use super::*;
+    use alloc::string::ToString;
+
+    fn loaded(program: &[u8]) -> Cpu {
+        let mut cpu = Cpu::new();
+        cpu.set_seed(0);
+        cpu.load_rom_at(program, super::super::INITIAL_PC).unwrap();
+        cpu
+    }
+
+    fn cpu_at(program: &[u8], pc: u16, i: u16) -> Cpu {
+        let mut cpu = loaded(program);
+        cpu.set_pc(pc);
+        cpu.set_i(i);
+        cpu
+    }
+
+    #[test]
+    fn invalid_opcode() {
+        let mut cpu = loaded(&[0x00, 0x00]);
+        assert_eq!(cpu.emulate_cycle(), Err(CpuError::InvalidOpcode(0x200, 0x0000)));
+        assert_eq!(cpu.get_pc(), 0x200);
+        assert_eq!(cpu.emulate_cycle().unwrap_err().to_string(), "invalid opcode 0000 at 0x200");
+    }
+
+    #[test]
+    fn stack_limits() {
+        let mut cpu = loaded(&[0x00, 0xEE]);
+        assert_eq!(cpu.emulate_cycle(), Err(CpuError::StackUnderflow(0x200)));
+
+        // calls itself until the stack is full
+        let mut cpu = loaded(&[0x22, 0x00]);
+        for _ in 0..super::super::STACK_SIZE {
+            cpu.emulate_cycle().unwrap();
+        }
+        assert_eq!(cpu.emulate_cycle(), Err(CpuError::StackOverflow(0x200)));
+    }
+
+    #[test]
+    fn pc_wraps() {
+        // 6AF0 from the last byte of memory and the font's first byte
+        let mut cpu = cpu_at(&[], 0xFFF, 0);
+        cpu.set_memory(0xFFF, 0x6A);
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.get_registers()[0xA], 0xF0);
+        assert_eq!(cpu.get_pc(), 0x001);
+
+        // a call from 0xFFE comes back to 0x000
+        let mut cpu = cpu_at(&[0x00, 0xEE], 0xFFE, 0);
+        cpu.set_memory(0xFFE, 0x22);
+        cpu.set_memory(0xFFF, 0x00);
+        cpu.emulate_cycle().unwrap();
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.get_pc(), 0x000);
+    }
+
+    #[test]
+    fn i_wraps() {
+        let program = [
+            0x6A, 0x7B, // VA = 123
+            0xFA, 0x33, // BCD at 0xFFE, 0xFFF and 0x000
+            0xF2, 0x65, // V0 to V2 from there, I goes past the end
+            0xF2, 0x55, // and back to 0x001
+            0x6B, 0xFF,
+            0xFB, 0x1E  // I += 0xFF
+        ];
+        let mut cpu = cpu_at(&program, 0x200, 0xFFE);
+        cpu.set_quirks(Quirks::from_preset("vip").unwrap());
+        for _ in 0..4 {
+            cpu.emulate_cycle().unwrap();
+        }
+        assert_eq!(&cpu.get_registers()[..3], &[1, 2, 3]);
+        assert_eq!(&cpu.get_memory()[0xFFE..], &[1, 2]);
+        assert_eq!(cpu.get_memory()[0x000], 3);
+        assert_eq!(&cpu.get_memory()[0x001..0x004], &[1, 2, 3]);
+        assert_eq!(cpu.get_i(), 0x1004);
+        cpu.emulate_cycle().unwrap();
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.get_i(), 0x1103);
+
+        let mut cpu = cpu_at(&program[8..], 0x200, 0xFFFF);
+        cpu.emulate_cycle().unwrap();
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.get_i(), 0x00FE);
+    }
+
+    #[test]
+    fn key_from_low_nibble() {
+        let program = [
+            0x6A, 0x1F, // VA = 0x1F
+            0xEA, 0x9E, // skip if key F
+            0x00, 0x00,
+            0xEA, 0xA1  // skip if not key F
+        ];
+        let mut cpu = loaded(&program);
+        cpu.set_key(0xF, true);
+        for _ in 0..3 {
+            cpu.emulate_cycle().unwrap();
+        }
+        assert_eq!(cpu.get_pc(), 0x208);
+    }
+}
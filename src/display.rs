@@ -1,66 +1,355 @@
 extern crate sdl2;
 
-use sdl2::pixels::Color;
-use sdl2::render::Canvas;
-use sdl2::video::Window;
+use sdl2::pixels::{Color, PixelFormatEnum};
+use sdl2::render::{Canvas, Texture};
+use sdl2::VideoSubsystem;
+use sdl2::video::{FullscreenType, Window};
 use sdl2::rect::Rect;
 
 use super::cpu::DisplayData;
+use super::menu::Menu;
+use super::text;
 
-const PIXEL_SIZE: u32 = 24;
-const SCREEN_WIDTH: u32 = PIXEL_SIZE * super::WIDTH as u32;
-const SCREEN_HEIGHT: u32 = PIXEL_SIZE * super::HEIGHT as u32;
-const BLACK: Color = Color::RGB(0,0,0);
-const WHITE: Color = Color::RGB(255,255,255);
+const DEFAULT_SCALE: u32 = 12;
+const DEFAULT_DECAY: f32 = 0.6;
+const BORDER: Color = Color::RGB(0,0,0);
+const BYTES_PER_PIXEL: usize = 3; // RGB24
+pub const TITLE: &str = "CHIP8";
+const MENU_HINT: &str = "enter: play   esc: back   up/down: choose";
 
-/* 
+/*
  * A lot of this display code heavily refrences Starr Horne's display driver for
  * their CHIP 8 emulator, a link to their repo can be found in the readme.
- */ 
+ */
 
+#[derive(Clone, Copy)]
+pub struct Palette {
+    pub foreground: Color,
+    pub background: Color
+}
+
+impl Palette {
+    pub fn new(foreground: Color, background: Color) -> Palette {
+        Palette {
+            foreground: foreground,
+            background: background
+        }
+    }
+
+    // Named presets, mostly imitating old monitors and handhelds
+    pub fn from_name(name: &str) -> Option<Palette> {
+        match name.to_lowercase().as_str() {
+            "classic" => Some(Palette::new(Color::RGB(255,255,255), Color::RGB(0,0,0))),
+            "amber" => Some(Palette::new(Color::RGB(255,176,0), Color::RGB(40,20,0))),
+            "green" => Some(Palette::new(Color::RGB(51,255,102), Color::RGB(0,24,8))),
+            "lcd" => Some(Palette::new(Color::RGB(15,56,15), Color::RGB(155,188,15))),
+            "paper" => Some(Palette::new(Color::RGB(0,0,0), Color::RGB(240,234,214))),
+            _ => None
+        }
+    }
+
+    pub fn preset_names() -> &'static [&'static str] {
+        &["classic", "amber", "green", "lcd", "paper"]
+    }
+}
+
+// Parses a colour written as RRGGBB, with or without a leading #
+pub fn parse_color(text: &str) -> Option<Color> {
+    let hex = text.trim_start_matches('#');
+    if hex.len() != 6 {
+        return None;
+    }
+    let value = u32::from_str_radix(hex, 16).ok()?;
+    Some(Color::RGB((value >> 16) as u8, (value >> 8) as u8, value as u8))
+}
+
+// How the framebuffer is turned into pixels on screen.
+// Phosphor keeps a lit pixel glowing for a few frames, keeping `decay` of its
+// brightness every frame. FrameOr shows a pixel if it was lit in either of the
+// last two frames, which hides the XOR erase/redraw flicker of most games.
+#[derive(Clone, Copy, PartialEq)]
+pub enum RenderMode {
+    Normal,
+    Phosphor { decay: f32 },
+    FrameOr
+}
+
+impl RenderMode {
+    pub fn from_name(name: &str) -> Option<RenderMode> {
+        match name.to_lowercase().as_str() {
+            "normal" => Some(RenderMode::Normal),
+            "phosphor" => Some(RenderMode::Phosphor { decay: DEFAULT_DECAY }),
+            "frame-or" => Some(RenderMode::FrameOr),
+            _ => None
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct DisplayOptions {
+    pub palette: Palette,
+    pub scale: u32,
+    pub resizable: bool,
+    pub fullscreen: bool,
+    pub render_mode: RenderMode,
+    pub vsync: bool,
+    pub software: bool
+}
+
+impl DisplayOptions {
+    pub fn new() -> DisplayOptions {
+        DisplayOptions {
+            palette: Palette::from_name("classic").unwrap(),
+            scale: DEFAULT_SCALE,
+            resizable: false,
+            fullscreen: false,
+            render_mode: RenderMode::Normal,
+            // the main loop's timer paces frames, vsync would wait a second time
+            vsync: false,
+            software: false
+        }
+    }
+}
+
+// The framebuffer is written into `pixels` and uploaded to a streaming texture
+// once per frame, SDL then scales it into the viewport in a single copy.
 pub struct Display {
-    canvas: Canvas<Window>
+    canvas: Canvas<Window>,
+    texture: Texture,
+    pixels: Vec<u8>,
+    palette: Palette,
+    render_mode: RenderMode,
+    brightness: [[f32; super::WIDTH]; super::HEIGHT],
+    // the framebuffer at the end of the emulated frame before the last, for
+    // FrameOr, however many frames ran between draws
+    previous_frame: [[u8; super::WIDTH]; super::HEIGHT],
+    last_frame: [[u8; super::WIDTH]; super::HEIGHT],
+    status: Option<String>, // shown in the corner, e.g. PAUSED
+    overlay: Option<Vec<String>> // drawn over the whole game, e.g. the help
 }
 
 impl Display {
-    pub fn new() -> Display {
-        let sdl_context = sdl2::init().unwrap();
-        let video_subsystem = sdl_context.video().unwrap();
-    
-        let window = video_subsystem.window("CHIP8", SCREEN_WIDTH, SCREEN_HEIGHT)
-            .position_centered()
-            .build()
+    pub fn new(video_subsystem: &VideoSubsystem, options: &DisplayOptions) -> Display {
+        let scale = options.scale.max(1);
+        let mut builder = video_subsystem.window(TITLE, scale * super::WIDTH as u32, scale * super::HEIGHT as u32);
+        builder.position_centered();
+        if options.resizable {
+            builder.resizable();
+        }
+        if options.fullscreen {
+            builder.fullscreen_desktop();
+        }
+        let window = builder.build().unwrap();
+
+        let mut canvas_builder = window.into_canvas();
+        if options.vsync {
+            canvas_builder = canvas_builder.present_vsync();
+        }
+        if options.software {
+            canvas_builder = canvas_builder.software();
+        }
+        let mut canvas = canvas_builder.build().unwrap();
+        let texture = canvas.texture_creator()
+            .create_texture_streaming(PixelFormatEnum::RGB24, super::WIDTH as u32, super::HEIGHT as u32)
             .unwrap();
-    
-        let mut canvas = window.into_canvas().build().unwrap();
-    
-        canvas.set_draw_color(BLACK);
+
+        canvas.set_draw_color(BORDER);
         canvas.clear();
         canvas.present();
         Display {
-            canvas: canvas
+            canvas: canvas,
+            texture: texture,
+            pixels: vec![0; super::WIDTH * super::HEIGHT * BYTES_PER_PIXEL],
+            palette: options.palette,
+            render_mode: options.render_mode,
+            brightness: [[0.0; super::WIDTH]; super::HEIGHT],
+            previous_frame: [[0; super::WIDTH]; super::HEIGHT],
+            last_frame: [[0; super::WIDTH]; super::HEIGHT],
+            status: None,
+            overlay: None
+        }
+    }
+
+    pub fn set_palette(&mut self, palette: Palette) {
+        self.palette = palette;
+    }
+
+    pub fn set_render_mode(&mut self, render_mode: RenderMode) {
+        self.render_mode = render_mode;
+    }
+
+    pub fn set_status(&mut self, status: Option<String>) {
+        self.status = status;
+    }
+
+    // Lines of text drawn over the whole game, e.g. the help
+    pub fn set_overlay(&mut self, overlay: Option<Vec<String>>) {
+        self.overlay = overlay;
+    }
+
+    pub fn get_window_id(&self) -> u32 {
+        self.canvas.window().id()
+    }
+
+    pub fn set_title(&mut self, title: &str) {
+        let _ = self.canvas.window_mut().set_title(title);
+    }
+
+    pub fn toggle_fullscreen(&mut self) {
+        let window = self.canvas.window_mut();
+        let next = match window.fullscreen_state() {
+            FullscreenType::Off => FullscreenType::Desktop,
+            _ => FullscreenType::Off
+        };
+        let _ = window.set_fullscreen(next);
+    }
+
+    // Largest area with the CHIP-8 aspect ratio that fits in the window, centered.
+    // Integer multiples are preferred so pixels stay square and evenly sized.
+    fn viewport(&self) -> Rect {
+        let (output_width, output_height) = self.canvas.output_size().unwrap_or((1, 1));
+        let pixel_size = (output_width / super::WIDTH as u32)
+            .min(output_height / super::HEIGHT as u32)
+            .max(1);
+        let width = pixel_size * super::WIDTH as u32;
+        let height = pixel_size * super::HEIGHT as u32;
+        let x = (output_width as i32 - width as i32) / 2;
+        let y = (output_height as i32 - height as i32) / 2;
+        Rect::new(x, y, width, height)
+    }
+
+    // Called at the end of every emulated frame, drawn or not
+    pub fn end_frame(&mut self, display_data: DisplayData) {
+        self.previous_frame = self.last_frame;
+        self.last_frame = *display_data.display;
+    }
+
+    // Works out how bright each pixel should be this frame, from 0.0 (background) to 1.0
+    fn update_brightness(&mut self, display: &[[u8; super::WIDTH]; super::HEIGHT]) {
+        for y in 0..super::HEIGHT {
+            for x in 0..super::WIDTH {
+                let lit = display[y][x] != 0;
+                self.brightness[y][x] = match self.render_mode {
+                    RenderMode::Normal => if lit { 1.0 } else { 0.0 },
+                    RenderMode::FrameOr => if lit || self.previous_frame[y][x] != 0 { 1.0 } else { 0.0 },
+                    RenderMode::Phosphor { decay } => {
+                        if lit { 1.0 } else { self.brightness[y][x] * decay }
+                    }
+                };
+            }
         }
     }
 
+    fn blend(&self, brightness: f32) -> Color {
+        let mix = |background: u8, foreground: u8| {
+            (background as f32 + (foreground as f32 - background as f32) * brightness) as u8
+        };
+        let (fg, bg) = (self.palette.foreground, self.palette.background);
+        Color::RGB(mix(bg.r, fg.r), mix(bg.g, fg.g), mix(bg.b, fg.b))
+    }
+
+    // Called once per frame, so the phosphor decay keeps going even when
+    // the game has not drawn anything new
     pub fn draw(&mut self, display_data: DisplayData) {
         let display = display_data.display;
-        for (y, row) in display.iter().enumerate() {
-            for (x, color) in row.iter().enumerate() {
-                let pos_x: u32 = x as u32 * PIXEL_SIZE;
-                let pos_y: u32 = y as u32 * PIXEL_SIZE;
-                // println!("Color of pixel: {}", *color); // DEBUG
-                if *color != 0 { // draw a pixel
-                    // println!("hey im supposed to be uh drawing?"); // DEBUG
-                    self.canvas.set_draw_color(WHITE);
-                } else {
-                    // println!("hey im supposed to be uh drawing but black?"); // DEBUG
-                    self.canvas.set_draw_color(BLACK);
-                }
-                let _ = self.canvas.fill_rect(Rect::new(pos_x as i32, pos_y as i32, PIXEL_SIZE, PIXEL_SIZE));
-                // print!("{}", color);
+        self.update_brightness(display);
+        for y in 0..super::HEIGHT {
+            for x in 0..super::WIDTH {
+                let color = self.blend(self.brightness[y][x]);
+                let offset = (y * super::WIDTH + x) * BYTES_PER_PIXEL;
+                self.pixels[offset] = color.r;
+                self.pixels[offset + 1] = color.g;
+                self.pixels[offset + 2] = color.b;
             }
-            // println!();
         }
+        let _ = self.texture.update(None, &self.pixels, super::WIDTH * BYTES_PER_PIXEL);
+        self.present();
+    }
+
+    // Shows the last uploaded frame again, e.g. when the window was uncovered
+    pub fn present(&mut self) {
+        let viewport = self.viewport();
+        self.canvas.set_draw_color(BORDER);
+        self.canvas.clear();
+        let _ = self.canvas.copy(&self.texture, None, viewport);
+        if let Some(status) = &self.status {
+            let scale = (viewport.width() / 256).max(2);
+            let offset = scale as i32 * 2;
+            // long messages, e.g. errors with a file name, are cut to fit
+            let columns = (viewport.width() / text::char_advance(scale)).saturating_sub(4) as usize;
+            let status: String = if status.chars().count() > columns {
+                status.chars().take(columns.saturating_sub(3)).chain("...".chars()).collect()
+            } else {
+                status.clone()
+            };
+            text::draw_label(&mut self.canvas, &status, viewport.x() + offset, viewport.y() + offset,
+                             scale, self.palette.foreground);
+        }
+        if let Some(lines) = self.overlay.take() {
+            self.draw_overlay(viewport, &lines);
+            self.overlay = Some(lines);
+        }
+        self.canvas.present();
+    }
+
+    // Darkens the game and lists the lines on top, as many as fit
+    fn draw_overlay(&mut self, viewport: Rect, lines: &[String]) {
+        let scale = (viewport.width() / 384).max(1);
+        let line_height = (text::GLYPH_HEIGHT + 2) * scale;
+        let margin = scale * 4;
+        let columns = (viewport.width().saturating_sub(margin * 2) / text::char_advance(scale)) as usize;
+        let rows = (viewport.height().saturating_sub(margin * 2) / line_height) as usize;
+        self.canvas.set_draw_color(BORDER);
+        let _ = self.canvas.fill_rect(viewport);
+        for (row, line) in lines.iter().take(rows).enumerate() {
+            let line: String = line.chars().take(columns).collect();
+            let y = viewport.y() + (margin + row as u32 * line_height) as i32;
+            text::draw_text(&mut self.canvas, &line, viewport.x() + margin as i32, y, scale, self.palette.foreground);
+        }
+    }
+
+    // Text size, line height and margin for the menu in the current window size
+    fn menu_metrics(&self) -> (u32, u32, u32) {
+        let (output_width, _) = self.canvas.output_size().unwrap_or((1, 1));
+        let scale = (output_width / 256).max(2);
+        (scale, (text::GLYPH_HEIGHT + 2) * scale, scale * 2)
+    }
+
+    // How many menu lines fit above the hint at the bottom
+    pub fn menu_rows(&self) -> usize {
+        let (_, output_height) = self.canvas.output_size().unwrap_or((1, 1));
+        let (_, line_height, margin) = self.menu_metrics();
+        (output_height.saturating_sub(margin * 2) / line_height).saturating_sub(2).max(1) as usize
+    }
+
+    // Draws the ROM menu over the whole window, scrolled so the selection is visible
+    pub fn draw_menu(&mut self, menu: &Menu) {
+        let (output_width, output_height) = self.canvas.output_size().unwrap_or((1, 1));
+        let (scale, line_height, margin) = self.menu_metrics();
+        let rows = self.menu_rows();
+        let columns = (output_width.saturating_sub(margin * 2) / text::char_advance(scale)) as usize;
+        let (foreground, background) = (self.palette.foreground, self.palette.background);
+
+        let lines = menu.lines(columns);
+        let selected = lines.iter().position(|line| line.selected).unwrap_or(0);
+        let first = selected.saturating_sub(rows / 2).min(lines.len().saturating_sub(rows));
+
+        self.canvas.set_draw_color(background);
+        self.canvas.clear();
+        for (row, line) in lines.iter().skip(first).take(rows).enumerate() {
+            let y = (margin + row as u32 * line_height) as i32;
+            let color = if line.selected {
+                self.canvas.set_draw_color(foreground);
+                let _ = self.canvas.fill_rect(Rect::new(0, y - scale as i32, output_width, line_height));
+                background
+            } else {
+                foreground
+            };
+            text::draw_text(&mut self.canvas, &line.text, margin as i32, y, scale, color);
+        }
+        let footer = menu.get_message().unwrap_or(MENU_HINT);
+        let y = output_height as i32 - (margin + line_height) as i32;
+        text::draw_text(&mut self.canvas, footer, margin as i32, y, scale, foreground);
         self.canvas.present();
     }
-}
\ No newline at end of file
+}
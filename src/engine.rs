@@ -0,0 +1,139 @@
+use super::cpu::Cpu;
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+use super::jit::Jit;
+
+/*
+ * What runs the instructions. The interpreter works everywhere, the JIT only
+ * on x86-64 Linux. Lockstep runs the JIT and checks every block against the
+ * interpreter running the same instructions on a copy of the cpu, stopping at
+ * the first difference in registers, timers, memory or the screen.
+ */
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EngineKind {
+    Interpreter,
+    Jit,
+    Lockstep
+}
+
+impl EngineKind {
+    pub fn from_name(name: &str) -> Option<EngineKind> {
+        match name.to_lowercase().as_str() {
+            "interpreter" => Some(EngineKind::Interpreter),
+            "jit" => Some(EngineKind::Jit),
+            "lockstep" => Some(EngineKind::Lockstep),
+            _ => None
+        }
+    }
+
+    pub fn names() -> &'static [&'static str] {
+        &["interpreter", "jit", "lockstep"]
+    }
+}
+
+pub struct Engine {
+    kind: EngineKind,
+    executed: u64, // instructions run, see get_executed
+    #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+    jit: Option<Jit>
+}
+
+impl Engine {
+    #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+    pub fn new(kind: EngineKind) -> Result<Engine, String> {
+        Ok(Engine {
+            kind: kind,
+            executed: 0,
+            jit: if kind == EngineKind::Interpreter { None } else { Some(Jit::new()?) }
+        })
+    }
+
+    #[cfg(not(all(target_arch = "x86_64", target_os = "linux")))]
+    pub fn new(kind: EngineKind) -> Result<Engine, String> {
+        if kind != EngineKind::Interpreter {
+            return Err("the JIT only runs on x86-64 Linux".to_string());
+        }
+        Ok(Engine {
+            kind: kind,
+            executed: 0
+        })
+    }
+
+    // Forgets compiled code, for when the cpu is swapped for another one
+    pub fn flush(&mut self) {
+        #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+        {
+            if let Some(jit) = &mut self.jit {
+                jit.flush();
+            }
+        }
+    }
+
+    // Runs `cycles` instructions, failing when the program stops on an error
+    // or lockstep finds a difference
+    pub fn run(&mut self, cpu: &mut Cpu, cycles: u32) -> Result<(), String> {
+        #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+        {
+            if let Some(jit) = &mut self.jit {
+                return match self.kind {
+                    EngineKind::Lockstep => lockstep(jit, cpu, cycles, &mut self.executed),
+                    _ => run_jit(jit, cpu, cycles, &mut self.executed)
+                };
+            }
+        }
+        for _ in 0..cycles {
+            let waiting = cpu.is_waiting_for_key();
+            cpu.emulate_cycle().map_err(|error| error.to_string())?;
+            if !waiting {
+                self.executed += 1;
+            }
+        }
+        Ok(())
+    }
+
+    // Instructions run so far, leaving out cycles spent waiting for a key in
+    // FX0A and an instruction that stopped the program
+    pub fn get_executed(&self) -> u64 {
+        self.executed
+    }
+}
+
+// A cpu waiting for a key does nothing, however many cycles the JIT says it
+// was given
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+fn run_jit(jit: &mut Jit, cpu: &mut Cpu, cycles: u32, executed: &mut u64) -> Result<(), String> {
+    let mut remaining = cycles;
+    while remaining > 0 {
+        let waiting = cpu.is_waiting_for_key();
+        let count = jit.step(cpu, remaining)?;
+        if !waiting {
+            *executed += count as u64;
+        }
+        remaining -= count;
+    }
+    Ok(())
+}
+
+// Each block the JIT runs is followed by as many instructions in the
+// interpreter on a copy of the cpu, then the two are compared
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+fn lockstep(jit: &mut Jit, cpu: &mut Cpu, cycles: u32, executed: &mut u64) -> Result<(), String> {
+    let mut reference = cpu.clone();
+    let mut remaining = cycles;
+    while remaining > 0 {
+        let (pc, waiting) = (cpu.get_pc(), cpu.is_waiting_for_key());
+        let count = jit.step(cpu, remaining)?;
+        if !waiting {
+            *executed += count as u64;
+        }
+        for _ in 0..count {
+            reference.emulate_cycle()
+                .map_err(|error| format!("the interpreter stopped where the JIT did not: {}", error))?;
+        }
+        if let Some(difference) = cpu.compare(&reference) {
+            return Err(format!("the JIT and the interpreter differ after {} instructions from {:#05x}: {}", count, pc, difference));
+        }
+        remaining -= count;
+    }
+    Ok(())
+}
@@ -0,0 +1,436 @@
+extern crate sdl2;
+
+use sdl2::{EventPump, GameControllerSubsystem, VideoSubsystem};
+use sdl2::controller::{Button, GameController};
+use sdl2::event::{Event, WindowEvent};
+use sdl2::keyboard::Keycode;
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use super::audio::Audio;
+use super::cpu::Cpu;
+use super::display::{self, Display, DisplayOptions, Palette};
+use super::input::{InputSource, KeypadInput};
+use super::keys::{Hotkey, KeyBindings};
+use super::memview::MemoryViewer;
+use super::menu::{Menu, MenuInput};
+
+// Slow motion cycles through these, in percent of normal speed
+const SLOW_MOTION_SPEEDS: [u32; 3] = [100, 50, 25];
+// How long messages such as load errors stay on screen
+const MESSAGE_DURATION: Duration = Duration::from_secs(4);
+
+/*
+ * The SDL frontend owns the window and the event pump. Keypad state is driven
+ * from the window's key events and from game controllers, so keys pressed in
+ * other applications are ignored.
+ */
+
+#[derive(Clone, Copy)]
+pub struct FrontendOptions {
+    pub display: DisplayOptions,
+    pub pause_on_focus_loss: bool,
+    pub mute: bool
+}
+
+impl FrontendOptions {
+    pub fn new() -> FrontendOptions {
+        FrontendOptions {
+            display: DisplayOptions::new(),
+            pause_on_focus_loss: false,
+            mute: false
+        }
+    }
+}
+
+pub struct Frontend {
+    display: Display,
+    video_subsystem: VideoSubsystem, // for opening the memory viewer
+    memory_viewer: Option<MemoryViewer>,
+    rom_range: (u16, usize), // start and length of the loaded ROM
+    audio: Option<Audio>,
+    mute: bool, // per game, the device is only opened when not muted at startup
+    event_pump: EventPump,
+    controller_subsystem: GameControllerSubsystem,
+    controllers: HashMap<i32, GameController>, // by instance id, which sdl2 gives as i32 here and u32 in events
+    bindings: KeyBindings,
+    keypad: KeypadInput,
+    actions: Vec<Hotkey>, // hotkeys the emulator loop has to deal with
+    pause_on_focus_loss: bool,
+    focus_paused: bool,
+    paused: bool,
+    turbo: bool,
+    speed_index: usize, // into SLOW_MOTION_SPEEDS
+    message: Option<(String, Instant)>, // shown until the instant has passed
+    help: Vec<String>, // the game's controls
+    showing_help: bool,
+    dropped_file: Option<String>, // a file dropped onto the window, not yet loaded
+    quit: bool
+}
+
+impl Frontend {
+    pub fn new(options: &FrontendOptions, bindings: KeyBindings) -> Frontend {
+        let sdl_context = sdl2::init().unwrap();
+        let video_subsystem = sdl_context.video().unwrap();
+        let display = Display::new(&video_subsystem, &options.display);
+        let audio = if options.mute {
+            None
+        } else {
+            // the emulator is still usable without sound, e.g. with no audio device
+            match sdl_context.audio().and_then(|audio_subsystem| Audio::new(&audio_subsystem)) {
+                Ok(audio) => Some(audio),
+                Err(error) => {
+                    eprintln!("Sound disabled: {}", error);
+                    None
+                }
+            }
+        };
+        let controller_subsystem = sdl_context.game_controller().unwrap();
+        let event_pump = sdl_context.event_pump().unwrap();
+        Frontend {
+            display: display,
+            video_subsystem: video_subsystem,
+            memory_viewer: None,
+            rom_range: (super::INITIAL_PC, 0),
+            audio: audio,
+            mute: options.mute,
+            event_pump: event_pump,
+            controller_subsystem: controller_subsystem,
+            controllers: HashMap::new(),
+            bindings: bindings,
+            keypad: KeypadInput::new(),
+            actions: Vec::new(),
+            pause_on_focus_loss: options.pause_on_focus_loss,
+            focus_paused: false,
+            paused: false,
+            turbo: false,
+            speed_index: 0,
+            message: None,
+            help: Vec::new(),
+            showing_help: false,
+            dropped_file: None,
+            quit: false
+        }
+    }
+
+    pub fn should_quit(&self) -> bool {
+        self.quit
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused || self.focus_paused || self.showing_help
+    }
+
+    pub fn is_turbo(&self) -> bool {
+        self.turbo
+    }
+
+    // Emulation speed in percent, turbo aside
+    pub fn get_speed(&self) -> u32 {
+        SLOW_MOTION_SPEEDS[self.speed_index]
+    }
+
+    // Hotkeys pressed since the last call that need the emulator loop, e.g. reset
+    pub fn take_actions(&mut self) -> Vec<Hotkey> {
+        std::mem::replace(&mut self.actions, Vec::new())
+    }
+
+    pub fn set_bindings(&mut self, bindings: KeyBindings) {
+        self.bindings = bindings;
+    }
+
+    // Switches to the settings of a newly started game. The window itself
+    // keeps the size and mode it was opened with.
+    pub fn configure(&mut self, options: &FrontendOptions) {
+        self.display.set_palette(options.display.palette);
+        self.display.set_render_mode(options.display.render_mode);
+        self.pause_on_focus_loss = options.pause_on_focus_loss;
+        self.mute = options.mute;
+        self.paused = false;
+        self.turbo = false;
+        self.speed_index = 0;
+        self.message = None;
+        self.showing_help = false;
+    }
+
+    // What the help hotkey shows for the current game
+    pub fn set_help(&mut self, lines: Vec<String>) {
+        self.help = lines;
+    }
+
+    // Shows the game's name in the title bar, or just the emulator's without a game
+    pub fn set_title(&mut self, game: Option<&str>) {
+        match game {
+            Some(game) => self.display.set_title(&format!("{} - {}", display::TITLE, game)),
+            None => self.display.set_title(display::TITLE)
+        }
+    }
+
+    // Where the game was loaded, highlighted by the memory viewer
+    pub fn set_rom_range(&mut self, start: u16, length: usize) {
+        self.rom_range = (start, length);
+        if let Some(viewer) = &mut self.memory_viewer {
+            viewer.set_rom_range(start, length);
+        }
+    }
+
+    // A message shown over the game for a few seconds
+    pub fn show_message(&mut self, message: String) {
+        self.message = Some((message, Instant::now() + MESSAGE_DURATION));
+    }
+
+    // The last file dropped onto the window since the previous call
+    pub fn take_dropped_file(&mut self) -> Option<String> {
+        self.dropped_file.take()
+    }
+
+    // Takes effect with the next drawn frame
+    pub fn set_palette(&mut self, palette: Palette) {
+        self.display.set_palette(palette);
+    }
+
+    pub fn handle_events(&mut self, cpu: &mut Cpu) {
+        let events: Vec<Event> = self.event_pump.poll_iter().collect();
+        for event in events {
+            match event {
+                Event::Quit { .. } => self.quit = true,
+                Event::DropFile { filename, .. } => self.dropped_file = Some(filename),
+                Event::KeyDown { window_id, keycode, scancode, repeat, .. } if self.is_viewer(window_id) => {
+                    let paused = self.is_paused();
+                    let used = match (&mut self.memory_viewer, keycode) {
+                        (Some(viewer), Some(keycode)) => viewer.handle_key(keycode, cpu, paused),
+                        _ => false
+                    };
+                    // the keypad stays with the game window
+                    if !used && !repeat {
+                        let hotkey = self.bindings.host_key(keycode, scancode).and_then(|key| self.bindings.hotkey(key));
+                        if let Some(hotkey) = hotkey {
+                            self.handle_hotkey(hotkey);
+                        }
+                    }
+                }
+                Event::KeyDown { keycode, scancode, repeat: false, .. } => {
+                    if let Some(key) = self.bindings.host_key(keycode, scancode) {
+                        if let Some(hotkey) = self.bindings.hotkey(key) {
+                            self.handle_hotkey(hotkey);
+                        } else if let Some(chip8_key) = self.bindings.keypad_key(key) {
+                            self.keypad.press(InputSource::Key(key), chip8_key);
+                        }
+                    }
+                }
+                Event::KeyUp { keycode, scancode, .. } => {
+                    if let Some(key) = self.bindings.host_key(keycode, scancode) {
+                        if self.bindings.hotkey(key) == Some(Hotkey::Turbo) {
+                            self.turbo = false;
+                        }
+                        self.keypad.release(InputSource::Key(key));
+                    }
+                }
+                // SDL also sends these for controllers already plugged in at startup
+                Event::ControllerDeviceAdded { which, .. } => self.open_controller(which),
+                Event::ControllerDeviceRemoved { which, .. } => {
+                    self.controllers.remove(&(which as i32));
+                    self.keypad.controller_event(self.bindings.get_controller(), &event);
+                }
+                Event::ControllerButtonDown { .. } | Event::ControllerButtonUp { .. } | Event::ControllerAxisMotion { .. } => {
+                    self.keypad.controller_event(self.bindings.get_controller(), &event);
+                }
+                Event::Window { window_id, win_event: WindowEvent::Close, .. } if self.is_viewer(window_id) => {
+                    self.memory_viewer = None;
+                }
+                // redrawn with the next frame
+                Event::Window { window_id, .. } if self.is_viewer(window_id) => {}
+                Event::Window { win_event, .. } => match win_event {
+                    // with the viewer open, closing the game window no longer sends Quit
+                    WindowEvent::Close => self.quit = true,
+                    WindowEvent::Exposed | WindowEvent::SizeChanged(..) => self.display.present(),
+                    WindowEvent::FocusLost => {
+                        // keys held while leaving the window never see their KeyUp
+                        self.release_all(cpu);
+                        self.focus_paused = self.pause_on_focus_loss;
+                    }
+                    WindowEvent::FocusGained => self.focus_paused = false,
+                    _ => {}
+                },
+                _ => {}
+            }
+            for (chip8_key, pressed) in self.keypad.take_changes() {
+                cpu.set_key(chip8_key, pressed);
+            }
+        }
+    }
+
+    // Menu navigation is fixed to the arrow keys and the D-pad so it works
+    // whatever the bindings are
+    pub fn menu_events(&mut self) -> Vec<MenuInput> {
+        let events: Vec<Event> = self.event_pump.poll_iter().collect();
+        let mut inputs = Vec::new();
+        for event in events {
+            let input = match event {
+                Event::Quit { .. } => {
+                    self.quit = true;
+                    None
+                }
+                Event::DropFile { filename, .. } => {
+                    self.dropped_file = Some(filename);
+                    None
+                }
+                Event::Window { window_id, win_event: WindowEvent::Close, .. } => {
+                    if self.is_viewer(window_id) {
+                        self.memory_viewer = None;
+                    } else {
+                        self.quit = true;
+                    }
+                    None
+                }
+                Event::KeyDown { window_id, .. } if self.is_viewer(window_id) => None,
+                Event::KeyDown { keycode: Some(keycode), .. } => match keycode {
+                    Keycode::Up => Some(MenuInput::Up),
+                    Keycode::Down => Some(MenuInput::Down),
+                    Keycode::PageUp => Some(MenuInput::PageUp),
+                    Keycode::PageDown => Some(MenuInput::PageDown),
+                    Keycode::Return | Keycode::KpEnter => Some(MenuInput::Select),
+                    Keycode::Escape | Keycode::Backspace => Some(MenuInput::Back),
+                    _ => None
+                },
+                Event::ControllerDeviceAdded { which, .. } => {
+                    self.open_controller(which);
+                    None
+                }
+                Event::ControllerDeviceRemoved { which, .. } => {
+                    self.controllers.remove(&(which as i32));
+                    None
+                }
+                Event::ControllerButtonDown { button, .. } => match button {
+                    Button::DPadUp => Some(MenuInput::Up),
+                    Button::DPadDown => Some(MenuInput::Down),
+                    Button::LeftShoulder => Some(MenuInput::PageUp),
+                    Button::RightShoulder => Some(MenuInput::PageDown),
+                    Button::A | Button::Start => Some(MenuInput::Select),
+                    Button::B | Button::Back => Some(MenuInput::Back),
+                    _ => None
+                },
+                _ => None
+            };
+            inputs.extend(input);
+        }
+        inputs
+    }
+
+    pub fn get_menu_rows(&self) -> usize {
+        self.display.menu_rows()
+    }
+
+    pub fn draw_menu(&mut self, menu: &Menu) {
+        self.display.draw_menu(menu);
+        self.set_sound(false);
+    }
+
+    fn handle_hotkey(&mut self, hotkey: Hotkey) {
+        match hotkey {
+            Hotkey::Quit => self.quit = true,
+            Hotkey::Pause => self.paused = !self.paused,
+            // the first press pauses, after that every press runs one frame
+            Hotkey::FrameAdvance if !self.paused => self.paused = true,
+            Hotkey::Turbo => self.turbo = true,
+            Hotkey::SlowMotion => self.speed_index = (self.speed_index + 1) % SLOW_MOTION_SPEEDS.len(),
+            Hotkey::Fullscreen => self.display.toggle_fullscreen(),
+            Hotkey::Help => self.showing_help = !self.showing_help,
+            Hotkey::MemoryViewer => self.toggle_memory_viewer(),
+            _ => self.actions.push(hotkey)
+        }
+    }
+
+    fn is_viewer(&self, window_id: u32) -> bool {
+        self.memory_viewer.as_ref().map_or(false, |viewer| viewer.get_window_id() == window_id)
+    }
+
+    fn toggle_memory_viewer(&mut self) {
+        if self.memory_viewer.take().is_some() {
+            return;
+        }
+        match MemoryViewer::new(&self.video_subsystem) {
+            Ok(mut viewer) => {
+                viewer.set_rom_range(self.rom_range.0, self.rom_range.1);
+                self.memory_viewer = Some(viewer);
+            }
+            Err(error) => self.show_message(format!("No memory viewer: {}", error))
+        }
+    }
+
+    fn open_controller(&mut self, joystick_index: u32) {
+        match self.controller_subsystem.open(joystick_index) {
+            Ok(controller) => {
+                println!("Controller connected: {}", controller.name());
+                self.controllers.insert(controller.instance_id(), controller);
+            }
+            Err(error) => eprintln!("Could not open controller {}: {}", joystick_index, error)
+        }
+    }
+
+    pub fn release_all(&mut self, cpu: &mut Cpu) {
+        self.keypad.release_all();
+        cpu.clear_keypad();
+    }
+
+    fn status(&self) -> Option<String> {
+        if let Some((message, until)) = &self.message {
+            if Instant::now() < *until {
+                return Some(message.clone());
+            }
+        }
+        if self.showing_help {
+            None
+        } else if self.is_paused() {
+            Some("PAUSED".to_string())
+        } else if self.turbo {
+            Some("TURBO".to_string())
+        } else if self.get_speed() != 100 {
+            Some(format!("SLOW {}%", self.get_speed()))
+        } else {
+            None
+        }
+    }
+
+    fn set_sound(&mut self, playing: bool) {
+        let mute = self.mute;
+        if let Some(audio) = &mut self.audio {
+            audio.set_playing(playing && !mute);
+        }
+    }
+
+    fn update_overlay(&mut self) {
+        let status = self.status();
+        self.display.set_status(status);
+        let overlay = if self.showing_help { Some(self.help.clone()) } else { None };
+        self.display.set_overlay(overlay);
+    }
+
+    // After every emulated frame, including the ones turbo does not show
+    pub fn end_frame(&mut self, cpu: &Cpu) {
+        self.display.end_frame(cpu.get_display());
+    }
+
+    pub fn draw(&mut self, cpu: &Cpu) {
+        self.update_overlay();
+        self.display.draw(cpu.get_display());
+        self.draw_memory_viewer(cpu, true);
+        self.set_sound(cpu.is_sound_active());
+    }
+
+    // Redraws the last frame without advancing the display, e.g. while paused
+    pub fn present(&mut self, cpu: &Cpu) {
+        self.update_overlay();
+        self.display.present();
+        self.draw_memory_viewer(cpu, false);
+        self.set_sound(false);
+    }
+
+    fn draw_memory_viewer(&mut self, cpu: &Cpu, new_frame: bool) {
+        let paused = self.is_paused();
+        if let Some(viewer) = &mut self.memory_viewer {
+            viewer.draw(cpu, paused, new_frame);
+        }
+    }
+}
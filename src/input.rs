@@ -0,0 +1,212 @@
+extern crate sdl2;
+
+use sdl2::controller::{Axis, Button};
+use sdl2::event::Event;
+
+use std::collections::HashMap;
+
+use super::controller::{ControllerBindings, Direction};
+use super::keys::HostKey;
+
+/*
+ * Keeps track of which keyboard keys and controller inputs are holding down
+ * which CHIP-8 keys. The frontend feeds it SDL events and passes the
+ * resulting keypad changes on to the cpu, so it can be driven by synthetic
+ * events without a window or a real controller.
+ */
+
+// Anything that can hold down a CHIP-8 key. Controllers are told apart by
+// their SDL instance id.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum InputSource {
+    Key(HostKey),
+    Button(u32, Button),
+    Axis(u32, Axis, Direction)
+}
+
+pub struct KeypadInput {
+    held: HashMap<InputSource, u8>, // which CHIP-8 key each source is holding down
+    held_count: [u8; super::KEYPAD_SIZE], // several sources can share a CHIP-8 key
+    changes: Vec<(u8, bool)> // keys pressed or released since the last take_changes
+}
+
+impl KeypadInput {
+    pub fn new() -> KeypadInput {
+        KeypadInput {
+            held: HashMap::new(),
+            held_count: [0; super::KEYPAD_SIZE],
+            changes: Vec::new()
+        }
+    }
+
+    // Keypad changes for the cpu, in the order they happened
+    pub fn take_changes(&mut self) -> Vec<(u8, bool)> {
+        std::mem::replace(&mut self.changes, Vec::new())
+    }
+
+    // Handles the controller events, anything else is ignored
+    pub fn controller_event(&mut self, bindings: &ControllerBindings, event: &Event) {
+        match *event {
+            Event::ControllerDeviceRemoved { which, .. } => self.release_controller(which),
+            Event::ControllerButtonDown { which, button, .. } => {
+                if let Some(chip8_key) = bindings.button_key(button) {
+                    self.press(InputSource::Button(which, button), chip8_key);
+                }
+            }
+            Event::ControllerButtonUp { which, button, .. } => {
+                self.release(InputSource::Button(which, button));
+            }
+            Event::ControllerAxisMotion { which, axis, value, .. } => {
+                for (direction, pushed) in bindings.axis_state(value).iter() {
+                    let source = InputSource::Axis(which, axis, *direction);
+                    match bindings.axis_key(axis, *direction).filter(|_| *pushed) {
+                        Some(chip8_key) => self.press(source, chip8_key),
+                        None => self.release(source)
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    pub fn press(&mut self, source: InputSource, chip8_key: u8) {
+        if self.held.contains_key(&source) {
+            return;
+        }
+        self.held.insert(source, chip8_key);
+        self.held_count[chip8_key as usize] += 1;
+        if self.held_count[chip8_key as usize] == 1 {
+            self.changes.push((chip8_key, true));
+        }
+    }
+
+    pub fn release(&mut self, source: InputSource) {
+        if let Some(chip8_key) = self.held.remove(&source) {
+            self.held_count[chip8_key as usize] -= 1;
+            if self.held_count[chip8_key as usize] == 0 {
+                self.changes.push((chip8_key, false));
+            }
+        }
+    }
+
+    // Lets go of everything an unplugged controller was holding
+    fn release_controller(&mut self, which: u32) {
+        let sources: Vec<InputSource> = self.held.keys()
+            .filter(|source| match source {
+                InputSource::Button(id, _) | InputSource::Axis(id, _, _) => *id == which,
+                InputSource::Key(_) => false
+            })
+            .cloned()
+            .collect();
+        for source in sources {
+            self.release(source);
+        }
+    }
+
+    // Forgets every held key, the caller clears the cpu's keypad itself
+    pub fn release_all(&mut self) {
+        self.held.clear();
+        self.held_count = [0; super::KEYPAD_SIZE];
+        self.changes.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sdl2::keyboard::Scancode;
+
+    fn button(which: u32, button: Button, down: bool) -> Event {
+        if down {
+            Event::ControllerButtonDown { timestamp: 0, which: which, button: button }
+        } else {
+            Event::ControllerButtonUp { timestamp: 0, which: which, button: button }
+        }
+    }
+
+    fn axis(which: u32, axis: Axis, value: i16) -> Event {
+        Event::ControllerAxisMotion { timestamp: 0, which: which, axis: axis, value: value }
+    }
+
+    #[test]
+    fn buttons() {
+        let bindings = ControllerBindings::new();
+        let mut input = KeypadInput::new();
+        input.controller_event(&bindings, &button(0, Button::A, true));
+        assert_eq!(input.take_changes(), vec![(0x5, true)]);
+        // buttons without a binding do nothing
+        input.controller_event(&bindings, &button(0, Button::Guide, true));
+        input.controller_event(&bindings, &button(0, Button::Guide, false));
+        assert_eq!(input.take_changes(), vec![]);
+        input.controller_event(&bindings, &button(0, Button::A, false));
+        assert_eq!(input.take_changes(), vec![(0x5, false)]);
+    }
+
+    #[test]
+    fn axes() {
+        let bindings = ControllerBindings::new();
+        let mut input = KeypadInput::new();
+        // inside the dead zone
+        input.controller_event(&bindings, &axis(0, Axis::LeftX, -8000));
+        assert_eq!(input.take_changes(), vec![]);
+        input.controller_event(&bindings, &axis(0, Axis::LeftX, -30000));
+        input.controller_event(&bindings, &axis(0, Axis::LeftX, -31000));
+        assert_eq!(input.take_changes(), vec![(0x4, true)]);
+        // straight over to the other side
+        input.controller_event(&bindings, &axis(0, Axis::LeftX, 30000));
+        assert_eq!(input.take_changes(), vec![(0x4, false), (0x6, true)]);
+        input.controller_event(&bindings, &axis(0, Axis::LeftX, 0));
+        assert_eq!(input.take_changes(), vec![(0x6, false)]);
+    }
+
+    #[test]
+    fn controller_removed() {
+        let bindings = ControllerBindings::new();
+        let mut input = KeypadInput::new();
+        input.controller_event(&bindings, &Event::ControllerDeviceAdded { timestamp: 0, which: 0 });
+        input.controller_event(&bindings, &button(1, Button::DPadUp, true));
+        input.controller_event(&bindings, &axis(1, Axis::LeftY, 30000));
+        input.controller_event(&bindings, &button(2, Button::A, true));
+        assert_eq!(input.take_changes(), vec![(0x2, true), (0x8, true), (0x5, true)]);
+        // only the unplugged controller lets go, and its release events never come
+        input.controller_event(&bindings, &Event::ControllerDeviceRemoved { timestamp: 0, which: 1 });
+        let mut changes = input.take_changes();
+        changes.sort();
+        assert_eq!(changes, vec![(0x2, false), (0x8, false)]);
+        input.controller_event(&bindings, &button(1, Button::DPadUp, false));
+        assert_eq!(input.take_changes(), vec![]);
+        input.controller_event(&bindings, &button(2, Button::A, false));
+        assert_eq!(input.take_changes(), vec![(0x5, false)]);
+    }
+
+    #[test]
+    fn shared_key() {
+        let bindings = ControllerBindings::new();
+        let mut input = KeypadInput::new();
+        let key = InputSource::Key(HostKey::Scancode(Scancode::W));
+        input.press(key, 0x5);
+        input.controller_event(&bindings, &button(0, Button::A, true));
+        assert_eq!(input.take_changes(), vec![(0x5, true)]);
+        // the key stays down until both have let go
+        input.release(key);
+        assert_eq!(input.take_changes(), vec![]);
+        input.press(key, 0x5);
+        input.controller_event(&bindings, &button(0, Button::A, false));
+        assert_eq!(input.take_changes(), vec![]);
+        input.release(key);
+        assert_eq!(input.take_changes(), vec![(0x5, false)]);
+    }
+
+    #[test]
+    fn release_all() {
+        let bindings = ControllerBindings::new();
+        let mut input = KeypadInput::new();
+        input.press(InputSource::Key(HostKey::Scancode(Scancode::W)), 0x5);
+        input.controller_event(&bindings, &button(0, Button::A, true));
+        input.release_all();
+        assert_eq!(input.take_changes(), vec![]);
+        // held keys are forgotten, so pressing again counts as a new press
+        input.controller_event(&bindings, &button(0, Button::A, true));
+        assert_eq!(input.take_changes(), vec![(0x5, true)]);
+    }
+}
@@ -0,0 +1,268 @@
+use core::fmt;
+
+/*
+ * The CHIP-8 instruction set as data, so the cpu, the linter, the profiler
+ * and anything else reading programs share one decoder. Variants follow
+ * Cowgod's reference and Display prints his mnemonics, e.g. DRW V1, V2, 5.
+ *
+ * decode and encode are exact inverses over all 65536 opcodes: an opcode
+ * only decodes to a variant when every nibble the variant does not keep is
+ * the one encode writes back, everything else is Unknown.
+ */
+
+// One of the sixteen V registers
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct Reg(pub u8);
+
+impl Reg {
+    pub fn index(&self) -> usize {
+        self.0 as usize
+    }
+}
+
+impl fmt::Display for Reg {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "V{:X}", self.0)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Instruction {
+    Cls, // 00E0
+    Ret, // 00EE
+    Jp(u16), // 1NNN
+    Call(u16), // 2NNN
+    Se(Reg, u8), // 3XKK, skip if VX == KK
+    Sne(Reg, u8), // 4XKK
+    SeReg(Reg, Reg), // 5XY0
+    Ld(Reg, u8), // 6XKK
+    Add(Reg, u8), // 7XKK
+    LdReg(Reg, Reg), // 8XY0
+    Or(Reg, Reg), // 8XY1
+    And(Reg, Reg), // 8XY2
+    Xor(Reg, Reg), // 8XY3
+    AddReg(Reg, Reg), // 8XY4, VF is the carry
+    Sub(Reg, Reg), // 8XY5, VX = VX - VY
+    Shr(Reg, Reg), // 8XY6
+    Subn(Reg, Reg), // 8XY7, VX = VY - VX
+    Shl(Reg, Reg), // 8XYE
+    SneReg(Reg, Reg), // 9XY0
+    LdI(u16), // ANNN
+    JpV0(u16), // BNNN
+    Rnd(Reg, u8), // CXKK
+    Drw(Reg, Reg, u8), // DXYN
+    Skp(Reg), // EX9E, skip if the key in VX is down
+    Sknp(Reg), // EXA1
+    LdRegDt(Reg), // FX07, VX = delay timer
+    LdKey(Reg), // FX0A, wait for a key
+    LdDt(Reg), // FX15, delay timer = VX
+    LdSt(Reg), // FX18, sound timer = VX
+    AddI(Reg), // FX1E
+    LdFont(Reg), // FX29, I = the font digit in VX
+    LdBcd(Reg), // FX33
+    Store(Reg), // FX55, V0 to VX into memory at I
+    Load(Reg), // FX65, memory at I into V0 to VX
+    Unknown(u16)
+}
+
+impl Instruction {
+    pub fn decode(opcode: u16) -> Instruction {
+        let nnn = opcode & 0x0FFF;
+        let kk = (opcode & 0x00FF) as u8;
+        let x = Reg(((opcode & 0x0F00) >> 8) as u8);
+        let y = Reg(((opcode & 0x00F0) >> 4) as u8);
+        let n = (opcode & 0x000F) as u8;
+        match (opcode >> 12, n) {
+            (0x0, _) if opcode == 0x00E0 => Instruction::Cls,
+            (0x0, _) if opcode == 0x00EE => Instruction::Ret,
+            (0x1, _) => Instruction::Jp(nnn),
+            (0x2, _) => Instruction::Call(nnn),
+            (0x3, _) => Instruction::Se(x, kk),
+            (0x4, _) => Instruction::Sne(x, kk),
+            (0x5, 0x0) => Instruction::SeReg(x, y),
+            (0x6, _) => Instruction::Ld(x, kk),
+            (0x7, _) => Instruction::Add(x, kk),
+            (0x8, 0x0) => Instruction::LdReg(x, y),
+            (0x8, 0x1) => Instruction::Or(x, y),
+            (0x8, 0x2) => Instruction::And(x, y),
+            (0x8, 0x3) => Instruction::Xor(x, y),
+            (0x8, 0x4) => Instruction::AddReg(x, y),
+            (0x8, 0x5) => Instruction::Sub(x, y),
+            (0x8, 0x6) => Instruction::Shr(x, y),
+            (0x8, 0x7) => Instruction::Subn(x, y),
+            (0x8, 0xE) => Instruction::Shl(x, y),
+            (0x9, 0x0) => Instruction::SneReg(x, y),
+            (0xA, _) => Instruction::LdI(nnn),
+            (0xB, _) => Instruction::JpV0(nnn),
+            (0xC, _) => Instruction::Rnd(x, kk),
+            (0xD, _) => Instruction::Drw(x, y, n),
+            (0xE, _) if kk == 0x9E => Instruction::Skp(x),
+            (0xE, _) if kk == 0xA1 => Instruction::Sknp(x),
+            (0xF, _) => match kk {
+                0x07 => Instruction::LdRegDt(x),
+                0x0A => Instruction::LdKey(x),
+                0x15 => Instruction::LdDt(x),
+                0x18 => Instruction::LdSt(x),
+                0x1E => Instruction::AddI(x),
+                0x29 => Instruction::LdFont(x),
+                0x33 => Instruction::LdBcd(x),
+                0x55 => Instruction::Store(x),
+                0x65 => Instruction::Load(x),
+                _ => Instruction::Unknown(opcode)
+            },
+            _ => Instruction::Unknown(opcode)
+        }
+    }
+
+    pub fn encode(&self) -> u16 {
+        let xkk = |prefix: u16, x: Reg, kk: u8| prefix << 12 | (x.0 as u16 & 0xF) << 8 | kk as u16;
+        let xyn = |prefix: u16, x: Reg, y: Reg, n: u8| {
+            prefix << 12 | (x.0 as u16 & 0xF) << 8 | (y.0 as u16 & 0xF) << 4 | (n as u16 & 0xF)
+        };
+        match *self {
+            Instruction::Cls => 0x00E0,
+            Instruction::Ret => 0x00EE,
+            Instruction::Jp(nnn) => 0x1000 | (nnn & 0x0FFF),
+            Instruction::Call(nnn) => 0x2000 | (nnn & 0x0FFF),
+            Instruction::Se(x, kk) => xkk(0x3, x, kk),
+            Instruction::Sne(x, kk) => xkk(0x4, x, kk),
+            Instruction::SeReg(x, y) => xyn(0x5, x, y, 0x0),
+            Instruction::Ld(x, kk) => xkk(0x6, x, kk),
+            Instruction::Add(x, kk) => xkk(0x7, x, kk),
+            Instruction::LdReg(x, y) => xyn(0x8, x, y, 0x0),
+            Instruction::Or(x, y) => xyn(0x8, x, y, 0x1),
+            Instruction::And(x, y) => xyn(0x8, x, y, 0x2),
+            Instruction::Xor(x, y) => xyn(0x8, x, y, 0x3),
+            Instruction::AddReg(x, y) => xyn(0x8, x, y, 0x4),
+            Instruction::Sub(x, y) => xyn(0x8, x, y, 0x5),
+            Instruction::Shr(x, y) => xyn(0x8, x, y, 0x6),
+            Instruction::Subn(x, y) => xyn(0x8, x, y, 0x7),
+            Instruction::Shl(x, y) => xyn(0x8, x, y, 0xE),
+            Instruction::SneReg(x, y) => xyn(0x9, x, y, 0x0),
+            Instruction::LdI(nnn) => 0xA000 | (nnn & 0x0FFF),
+            Instruction::JpV0(nnn) => 0xB000 | (nnn & 0x0FFF),
+            Instruction::Rnd(x, kk) => xkk(0xC, x, kk),
+            Instruction::Drw(x, y, n) => xyn(0xD, x, y, n),
+            Instruction::Skp(x) => xkk(0xE, x, 0x9E),
+            Instruction::Sknp(x) => xkk(0xE, x, 0xA1),
+            Instruction::LdRegDt(x) => xkk(0xF, x, 0x07),
+            Instruction::LdKey(x) => xkk(0xF, x, 0x0A),
+            Instruction::LdDt(x) => xkk(0xF, x, 0x15),
+            Instruction::LdSt(x) => xkk(0xF, x, 0x18),
+            Instruction::AddI(x) => xkk(0xF, x, 0x1E),
+            Instruction::LdFont(x) => xkk(0xF, x, 0x29),
+            Instruction::LdBcd(x) => xkk(0xF, x, 0x33),
+            Instruction::Store(x) => xkk(0xF, x, 0x55),
+            Instruction::Load(x) => xkk(0xF, x, 0x65),
+            Instruction::Unknown(opcode) => opcode
+        }
+    }
+
+    // The opcode table's pattern, e.g. DXYN, or "unknown"
+    pub fn get_pattern(&self) -> &'static str {
+        match self {
+            Instruction::Cls => "00E0",
+            Instruction::Ret => "00EE",
+            Instruction::Jp(_) => "1NNN",
+            Instruction::Call(_) => "2NNN",
+            Instruction::Se(..) => "3XKK",
+            Instruction::Sne(..) => "4XKK",
+            Instruction::SeReg(..) => "5XY0",
+            Instruction::Ld(..) => "6XKK",
+            Instruction::Add(..) => "7XKK",
+            Instruction::LdReg(..) => "8XY0",
+            Instruction::Or(..) => "8XY1",
+            Instruction::And(..) => "8XY2",
+            Instruction::Xor(..) => "8XY3",
+            Instruction::AddReg(..) => "8XY4",
+            Instruction::Sub(..) => "8XY5",
+            Instruction::Shr(..) => "8XY6",
+            Instruction::Subn(..) => "8XY7",
+            Instruction::Shl(..) => "8XYE",
+            Instruction::SneReg(..) => "9XY0",
+            Instruction::LdI(_) => "ANNN",
+            Instruction::JpV0(_) => "BNNN",
+            Instruction::Rnd(..) => "CXKK",
+            Instruction::Drw(..) => "DXYN",
+            Instruction::Skp(_) => "EX9E",
+            Instruction::Sknp(_) => "EXA1",
+            Instruction::LdRegDt(_) => "FX07",
+            Instruction::LdKey(_) => "FX0A",
+            Instruction::LdDt(_) => "FX15",
+            Instruction::LdSt(_) => "FX18",
+            Instruction::AddI(_) => "FX1E",
+            Instruction::LdFont(_) => "FX29",
+            Instruction::LdBcd(_) => "FX33",
+            Instruction::Store(_) => "FX55",
+            Instruction::Load(_) => "FX65",
+            Instruction::Unknown(_) => "unknown"
+        }
+    }
+
+    // Skips over the next instruction when their condition holds
+    pub fn is_skip(&self) -> bool {
+        match self {
+            Instruction::Se(..) | Instruction::Sne(..) | Instruction::SeReg(..) | Instruction::SneReg(..) => true,
+            Instruction::Skp(_) | Instruction::Sknp(_) => true,
+            _ => false
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Instruction::Cls => write!(formatter, "CLS"),
+            Instruction::Ret => write!(formatter, "RET"),
+            Instruction::Jp(nnn) => write!(formatter, "JP {:#05x}", nnn),
+            Instruction::Call(nnn) => write!(formatter, "CALL {:#05x}", nnn),
+            Instruction::Se(x, kk) => write!(formatter, "SE {}, {:#04x}", x, kk),
+            Instruction::Sne(x, kk) => write!(formatter, "SNE {}, {:#04x}", x, kk),
+            Instruction::SeReg(x, y) => write!(formatter, "SE {}, {}", x, y),
+            Instruction::Ld(x, kk) => write!(formatter, "LD {}, {:#04x}", x, kk),
+            Instruction::Add(x, kk) => write!(formatter, "ADD {}, {:#04x}", x, kk),
+            Instruction::LdReg(x, y) => write!(formatter, "LD {}, {}", x, y),
+            Instruction::Or(x, y) => write!(formatter, "OR {}, {}", x, y),
+            Instruction::And(x, y) => write!(formatter, "AND {}, {}", x, y),
+            Instruction::Xor(x, y) => write!(formatter, "XOR {}, {}", x, y),
+            Instruction::AddReg(x, y) => write!(formatter, "ADD {}, {}", x, y),
+            Instruction::Sub(x, y) => write!(formatter, "SUB {}, {}", x, y),
+            Instruction::Shr(x, y) => write!(formatter, "SHR {}, {}", x, y),
+            Instruction::Subn(x, y) => write!(formatter, "SUBN {}, {}", x, y),
+            Instruction::Shl(x, y) => write!(formatter, "SHL {}, {}", x, y),
+            Instruction::SneReg(x, y) => write!(formatter, "SNE {}, {}", x, y),
+            Instruction::LdI(nnn) => write!(formatter, "LD I, {:#05x}", nnn),
+            Instruction::JpV0(nnn) => write!(formatter, "JP V0, {:#05x}", nnn),
+            Instruction::Rnd(x, kk) => write!(formatter, "RND {}, {:#04x}", x, kk),
+            Instruction::Drw(x, y, n) => write!(formatter, "DRW {}, {}, {}", x, y, n),
+            Instruction::Skp(x) => write!(formatter, "SKP {}", x),
+            Instruction::Sknp(x) => write!(formatter, "SKNP {}", x),
+            Instruction::LdRegDt(x) => write!(formatter, "LD {}, DT", x),
+            Instruction::LdKey(x) => write!(formatter, "LD {}, K", x),
+            Instruction::LdDt(x) => write!(formatter, "LD DT, {}", x),
+            Instruction::LdSt(x) => write!(formatter, "LD ST, {}", x),
+            Instruction::AddI(x) => write!(formatter, "ADD I, {}", x),
+            Instruction::LdFont(x) => write!(formatter, "LD F, {}", x),
+            Instruction::LdBcd(x) => write!(formatter, "LD B, {}", x),
+            Instruction::Store(x) => write!(formatter, "LD [I], {}", x),
+            Instruction::Load(x) => write!(formatter, "LD {}, [I]", x),
+            Instruction::Unknown(opcode) => write!(formatter, "DW {:#06x}", opcode)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_reverses_decode() {
+        for opcode in 0..=0xFFFFu16 {
+            let instruction = Instruction::decode(opcode);
+            if let Instruction::Unknown(_) = instruction {
+                continue;
+            }
+            assert_eq!(instruction.encode(), opcode, "{:04x} decodes to {}", opcode, instruction);
+        }
+    }
+}
@@ -0,0 +1,656 @@
+extern crate libc;
+
+use std::io;
+use std::mem;
+use std::ptr;
+
+use super::cpu::Cpu;
+use super::instruction::Instruction;
+use super::quirks::Quirks;
+
+/*
+ * A basic block compiler to x86-64 machine code, for running large numbers
+ * of ROMs faster than the interpreter can.
+ *
+ * A block starts wherever the cpu jumps to and runs on through arithmetic,
+ * I, the timers and register loads and stores, up to a jump, a store, the
+ * first instruction it cannot compile or BLOCK_LIMIT instructions. A skip
+ * that is taken leaves the block early. Everything else runs in the
+ * interpreter one instruction at a time: drawing, FX0A, calls and returns,
+ * random numbers and keys. Quirks are compiled into the code, so blocks are
+ * thrown away when they change.
+ *
+ * Compiled code works on the cpu's state in place:
+ *
+ *   rdi  the V registers     rdx  the delay timer     r8  memory
+ *   rsi  I                   rcx  the sound timer
+ *
+ * and returns the address to carry on at in the low 16 bits of eax, with
+ * the number of instructions it ran above them. r9, r10 and r11 are scratch.
+ *
+ * Blocks compiled from memory that has since been written are thrown away
+ * and compiled again. A block that keeps changing is self-modifying code and
+ * is left to the interpreter from then on.
+ */
+
+const BUFFER_SIZE: usize = 1 << 20;
+const BLOCK_LIMIT: usize = 64; // instructions
+const RECOMPILE_LIMIT: u32 = 4;
+
+type BlockFunction = extern "C" fn(*mut u8, *mut u16, *mut u8, *mut u8, *mut u8) -> u32;
+
+// Memory for the compiled code, only writable while a block is copied in
+struct CodeBuffer {
+    memory: *mut u8,
+    length: usize
+}
+
+impl CodeBuffer {
+    fn new() -> Result<CodeBuffer, String> {
+        let memory = unsafe {
+            libc::mmap(ptr::null_mut(), BUFFER_SIZE, libc::PROT_READ | libc::PROT_EXEC,
+                       libc::MAP_PRIVATE | libc::MAP_ANONYMOUS, -1, 0)
+        };
+        if memory == libc::MAP_FAILED {
+            return Err(format!("could not map memory for the JIT: {}", io::Error::last_os_error()));
+        }
+        Ok(CodeBuffer {
+            memory: memory as *mut u8,
+            length: 0
+        })
+    }
+
+    fn protect(&self, protection: libc::c_int) -> Result<(), String> {
+        if unsafe { libc::mprotect(self.memory as *mut libc::c_void, BUFFER_SIZE, protection) } != 0 {
+            return Err(format!("could not change the JIT's memory protection: {}", io::Error::last_os_error()));
+        }
+        Ok(())
+    }
+
+    // Copies code in and returns its offset, or None when there is no room left
+    fn add(&mut self, code: &[u8]) -> Result<Option<usize>, String> {
+        if self.length + code.len() > BUFFER_SIZE {
+            return Ok(None);
+        }
+        self.protect(libc::PROT_READ | libc::PROT_WRITE)?;
+        unsafe {
+            ptr::copy_nonoverlapping(code.as_ptr(), self.memory.add(self.length), code.len());
+        }
+        self.protect(libc::PROT_READ | libc::PROT_EXEC)?;
+        let offset = self.length;
+        self.length += code.len();
+        Ok(Some(offset))
+    }
+
+    fn clear(&mut self) {
+        self.length = 0;
+    }
+
+    fn get_function(&self, offset: usize) -> BlockFunction {
+        unsafe { mem::transmute(self.memory.add(offset)) }
+    }
+}
+
+impl Drop for CodeBuffer {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.memory as *mut libc::c_void, BUFFER_SIZE);
+        }
+    }
+}
+
+// Machine code for one block, named after the x86 instructions written
+struct Assembler {
+    code: Vec<u8>
+}
+
+impl Assembler {
+    // mov al, [rdi + register]
+    fn load_al(&mut self, register: u8) {
+        self.code.extend_from_slice(&[0x8A, 0x47, register]);
+    }
+
+    // mov [rdi + register], al
+    fn store_al(&mut self, register: u8) {
+        self.code.extend_from_slice(&[0x88, 0x47, register]);
+    }
+
+    // mov r9b, [rdi + register]
+    fn load_r9b(&mut self, register: u8) {
+        self.code.extend_from_slice(&[0x44, 0x8A, 0x4F, register]);
+    }
+
+    // mov [rdi + register], r11b
+    fn store_r11b(&mut self, register: u8) {
+        self.code.extend_from_slice(&[0x44, 0x88, 0x5F, register]);
+    }
+
+    // mov r11b, al
+    fn copy_al_to_r11b(&mut self) {
+        self.code.extend_from_slice(&[0x41, 0x88, 0xC3]);
+    }
+
+    // setc r11b
+    fn set_r11b_if_carry(&mut self) {
+        self.code.extend_from_slice(&[0x41, 0x0F, 0x92, 0xC3]);
+    }
+
+    // seta r11b
+    fn set_r11b_if_above(&mut self) {
+        self.code.extend_from_slice(&[0x41, 0x0F, 0x97, 0xC3]);
+    }
+
+    // cmp al, r9b
+    fn compare_al_r9b(&mut self) {
+        self.code.extend_from_slice(&[0x44, 0x38, 0xC8]);
+    }
+
+    // sub al, r9b
+    fn subtract_r9b(&mut self) {
+        self.code.extend_from_slice(&[0x44, 0x28, 0xC8]);
+    }
+
+    // movzx eax, byte [rdi + register]
+    fn load_eax(&mut self, register: u8) {
+        self.code.extend_from_slice(&[0x0F, 0xB6, 0x47, register]);
+    }
+
+    // movzx r10d, word [rsi]
+    fn load_i_r10d(&mut self) {
+        self.code.extend_from_slice(&[0x44, 0x0F, 0xB7, 0x16]);
+    }
+
+    // mov al, [r8 + r10 + offset]
+    fn load_al_from_memory(&mut self, offset: u8) {
+        self.code.extend_from_slice(&[0x43, 0x8A, 0x44, 0x10, offset]);
+    }
+
+    // mov [r8 + r10 + offset], al
+    fn store_al_to_memory(&mut self, offset: u8) {
+        self.code.extend_from_slice(&[0x43, 0x88, 0x44, 0x10, offset]);
+    }
+
+    // add word [rsi], value
+    fn add_i(&mut self, value: u8) {
+        self.code.extend_from_slice(&[0x66, 0x83, 0x06, value]);
+    }
+
+    // mov eax, pc and count; ret
+    fn exit(&mut self, pc: u16, count: u32) {
+        self.code.push(0xB8);
+        self.code.extend_from_slice(&(pc as u32 | count << 16).to_le_bytes());
+        self.code.push(0xC3);
+    }
+
+    // Leaves the block at pc unless the flags match the short jump `jump`,
+    // which hops over the exit
+    fn exit_unless(&mut self, jump: u8, pc: u16, count: u32) {
+        self.code.extend_from_slice(&[jump, 6]);
+        self.exit(pc, count);
+    }
+
+    // Hands the instruction at pc to the interpreter when `length` bytes
+    // from I on would be past the end of memory, where it wraps around
+    fn check_memory(&mut self, length: u8, pc: u16, count: u32) {
+        self.load_i_r10d();
+        // cmp r10d, RAM_SIZE - length
+        self.code.extend_from_slice(&[0x41, 0x81, 0xFA]);
+        self.code.extend_from_slice(&((super::RAM_SIZE - length as usize) as u32).to_le_bytes());
+        self.exit_unless(JB, pc, count);
+    }
+}
+
+// Short jumps by condition
+const JB: u8 = 0x72;
+const JE: u8 = 0x74;
+const JNE: u8 = 0x75;
+
+// Where a block ends up after its code ran
+enum End {
+    Continue, // on to the next instruction
+    Exit, // the instruction left the block itself
+    Store // left after writing memory, which has to be reported to the cpu
+}
+
+// Writes one instruction, None when it has to be left to the interpreter.
+// Every step loads and stores the registers in the same order as the
+// interpreter, which matters when X or Y is VF. `count` is the number of
+// instructions before this one in the block.
+fn compile_instruction(assembler: &mut Assembler, instruction: Instruction, pc: u16, count: u32, quirks: Quirks) -> Option<End> {
+    match instruction {
+        Instruction::Jp(nnn) => {
+            assembler.exit(nnn, count + 1);
+            return Some(End::Exit);
+        }
+        Instruction::JpV0(nnn) => {
+            let register = if quirks.jump_uses_vx { (nnn >> 8) as u8 } else { 0 };
+            assembler.load_eax(register);
+            assembler.code.push(0x05); // add eax, nnn
+            assembler.code.extend_from_slice(&(nnn as u32).to_le_bytes());
+            assembler.code.extend_from_slice(&[0x25, 0xFF, 0x0F, 0x00, 0x00]); // and eax, 0xFFF
+            assembler.code.push(0x0D); // or eax, count
+            assembler.code.extend_from_slice(&((count + 1) << 16).to_le_bytes());
+            assembler.code.push(0xC3);
+            return Some(End::Exit);
+        }
+        // a skip that happens leaves the block, otherwise it carries on
+        Instruction::Se(x, kk) | Instruction::Sne(x, kk) => {
+            assembler.code.extend_from_slice(&[0x80, 0x7F, x.0, kk]); // cmp byte [rdi + x], kk
+            let jump = if let Instruction::Se(_, _) = instruction { JNE } else { JE };
+            assembler.exit_unless(jump, pc + 4, count + 1);
+        }
+        Instruction::SeReg(x, y) | Instruction::SneReg(x, y) => {
+            assembler.load_r9b(y.0);
+            assembler.code.extend_from_slice(&[0x44, 0x38, 0x4F, x.0]); // cmp [rdi + x], r9b
+            let jump = if let Instruction::SeReg(_, _) = instruction { JNE } else { JE };
+            assembler.exit_unless(jump, pc + 4, count + 1);
+        }
+        Instruction::Ld(x, kk) => assembler.code.extend_from_slice(&[0xC6, 0x47, x.0, kk]), // mov byte [rdi + x], kk
+        Instruction::Add(x, kk) => {
+            // VF is the carry, set before VX is read again for the sum
+            assembler.load_al(x.0);
+            assembler.code.extend_from_slice(&[0x04, kk]); // add al, kk
+            assembler.set_r11b_if_carry();
+            assembler.store_r11b(0xF);
+            assembler.load_al(x.0);
+            assembler.code.extend_from_slice(&[0x04, kk]);
+            assembler.store_al(x.0);
+        }
+        Instruction::LdReg(x, y) => {
+            assembler.load_al(y.0);
+            assembler.store_al(x.0);
+        }
+        Instruction::Or(x, y) | Instruction::And(x, y) | Instruction::Xor(x, y) => {
+            let operation = match instruction {
+                Instruction::Or(_, _) => 0x0A,
+                Instruction::And(_, _) => 0x22,
+                _ => 0x32
+            };
+            assembler.load_al(x.0);
+            assembler.code.extend_from_slice(&[operation, 0x47, y.0]); // op al, [rdi + y]
+            assembler.store_al(x.0);
+            if quirks.logic_resets_vf {
+                assembler.code.extend_from_slice(&[0xC6, 0x47, 0xF, 0]); // mov byte [rdi + 15], 0
+            }
+        }
+        Instruction::AddReg(x, y) => {
+            assembler.load_al(x.0);
+            assembler.code.extend_from_slice(&[0x02, 0x47, y.0]); // add al, [rdi + y]
+            assembler.set_r11b_if_carry();
+            assembler.store_r11b(0xF);
+            assembler.store_al(x.0);
+        }
+        Instruction::Sub(x, y) | Instruction::Subn(x, y) => {
+            // VF is set when the first operand is larger, i.e. not borrowed
+            let (first, second) = if let Instruction::Sub(_, _) = instruction { (x, y) } else { (y, x) };
+            assembler.load_al(first.0);
+            assembler.load_r9b(second.0);
+            assembler.compare_al_r9b();
+            assembler.set_r11b_if_above();
+            assembler.subtract_r9b();
+            assembler.store_r11b(0xF);
+            assembler.store_al(x.0);
+        }
+        Instruction::Shr(x, y) | Instruction::Shl(x, y) => {
+            let source = if quirks.shift_uses_vy { y } else { x };
+            assembler.load_al(source.0);
+            assembler.copy_al_to_r11b();
+            if let Instruction::Shr(_, _) = instruction {
+                assembler.code.extend_from_slice(&[0xD0, 0xE8]); // shr al, 1
+                assembler.code.extend_from_slice(&[0x41, 0x80, 0xE3, 0x01]); // and r11b, 1
+            } else {
+                assembler.code.extend_from_slice(&[0xD0, 0xE0]); // shl al, 1
+                assembler.code.extend_from_slice(&[0x41, 0xC0, 0xEB, 0x07]); // shr r11b, 7
+            }
+            assembler.store_al(x.0);
+            assembler.store_r11b(0xF);
+        }
+        Instruction::LdI(nnn) => {
+            assembler.code.extend_from_slice(&[0x66, 0xC7, 0x06]); // mov word [rsi], nnn
+            assembler.code.extend_from_slice(&nnn.to_le_bytes());
+        }
+        Instruction::AddI(x) => {
+            assembler.load_eax(x.0);
+            assembler.code.extend_from_slice(&[0x66, 0x01, 0x06]); // add [rsi], ax
+        }
+        Instruction::LdFont(x) => {
+            assembler.load_eax(x.0);
+            assembler.code.extend_from_slice(&[0x8D, 0x04, 0x80]); // lea eax, [rax + rax * 4]
+            assembler.code.extend_from_slice(&[0x66, 0x89, 0x06]); // mov [rsi], ax
+        }
+        Instruction::LdRegDt(x) => {
+            assembler.code.extend_from_slice(&[0x8A, 0x02]); // mov al, [rdx]
+            assembler.store_al(x.0);
+        }
+        Instruction::LdDt(x) => {
+            assembler.load_al(x.0);
+            assembler.code.extend_from_slice(&[0x88, 0x02]); // mov [rdx], al
+        }
+        Instruction::LdSt(x) => {
+            assembler.load_al(x.0);
+            assembler.code.extend_from_slice(&[0x88, 0x01]); // mov [rcx], al
+        }
+        Instruction::LdBcd(x) => {
+            // dividing by multiplying, v * 41 >> 12 is v / 100 and
+            // v * 205 >> 11 is v / 10 for every v this sees
+            assembler.check_memory(2, pc, count);
+            assembler.load_eax(x.0);
+            assembler.code.extend_from_slice(&[0x44, 0x6B, 0xC8, 41]); // imul r9d, eax, 41
+            assembler.code.extend_from_slice(&[0x41, 0xC1, 0xE9, 12]); // shr r9d, 12
+            assembler.code.extend_from_slice(&[0x47, 0x88, 0x4C, 0x10, 0]); // mov [r8 + r10], r9b
+            assembler.code.extend_from_slice(&[0x45, 0x6B, 0xC9, 100]); // imul r9d, r9d, 100
+            assembler.code.extend_from_slice(&[0x44, 0x29, 0xC8]); // sub eax, r9d
+            assembler.code.extend_from_slice(&[0x44, 0x69, 0xC8, 205, 0, 0, 0]); // imul r9d, eax, 205
+            assembler.code.extend_from_slice(&[0x41, 0xC1, 0xE9, 11]); // shr r9d, 11
+            assembler.code.extend_from_slice(&[0x47, 0x88, 0x4C, 0x10, 1]); // mov [r8 + r10 + 1], r9b
+            assembler.code.extend_from_slice(&[0x45, 0x6B, 0xC9, 10]); // imul r9d, r9d, 10
+            assembler.code.extend_from_slice(&[0x44, 0x29, 0xC8]); // sub eax, r9d
+            assembler.store_al_to_memory(2);
+            assembler.exit(pc + 2, count + 1);
+            return Some(End::Store);
+        }
+        Instruction::Store(x) | Instruction::Load(x) => {
+            assembler.check_memory(x.0, pc, count);
+            let store = instruction == Instruction::Store(x);
+            for register in 0..=x.0 {
+                if store {
+                    assembler.load_al(register);
+                    assembler.store_al_to_memory(register);
+                } else {
+                    assembler.load_al_from_memory(register);
+                    assembler.store_al(register);
+                }
+            }
+            if quirks.load_store_increments_i {
+                assembler.add_i(x.0 + 1);
+            }
+            if store {
+                // the block may have just written over itself
+                assembler.exit(pc + 2, count + 1);
+                return Some(End::Store);
+            }
+        }
+        _ => return None
+    }
+    Some(End::Continue)
+}
+
+// Compiles from pc on. The block gives the code and the number of
+// instructions in it, none means the one at pc is left to the interpreter.
+fn compile_block(memory: &[u8], start: usize, quirks: Quirks) -> (Vec<u8>, Block) {
+    let mut assembler = Assembler { code: Vec::new() };
+    let mut pc = start;
+    let mut count = 0;
+    let mut end = End::Continue;
+    let mut last = Instruction::Unknown(0);
+    while count < BLOCK_LIMIT as u32 && pc + 1 < memory.len() {
+        last = Instruction::decode((memory[pc] as u16) << 8 | memory[pc + 1] as u16);
+        match compile_instruction(&mut assembler, last, pc as u16, count, quirks) {
+            Some(End::Continue) => {
+                pc += 2;
+                count += 1;
+            }
+            Some(instruction_end) => {
+                pc += 2;
+                count += 1;
+                end = instruction_end;
+                break;
+            }
+            None => break
+        }
+    }
+    if let End::Continue = end {
+        assembler.exit(pc as u16, count);
+    }
+    let block = Block {
+        offset: None,
+        count: count,
+        end: pc,
+        store: if let End::Store = end { Some(last) } else { None }
+    };
+    (assembler.code, block)
+}
+
+struct Block {
+    offset: Option<usize>, // in the code buffer, None when the interpreter runs the instruction at the start
+    count: u32, // instructions run by the block
+    end: usize, // address after the last byte it was compiled from
+    store: Option<Instruction> // the FX33 or FX55 that ends it
+}
+
+pub struct Jit {
+    buffer: CodeBuffer,
+    blocks: Vec<Option<Block>>, // by start address
+    code: Vec<bool>, // bytes blocks were compiled from, so writes to data are quick to pass over
+    compiles: Vec<u32>, // how often each start address was compiled, to catch self-modifying code
+    quirks: Quirks
+}
+
+impl Jit {
+    pub fn new() -> Result<Jit, String> {
+        Ok(Jit {
+            buffer: CodeBuffer::new()?,
+            blocks: (0..super::RAM_SIZE).map(|_| None).collect(),
+            code: vec![false; super::RAM_SIZE],
+            compiles: vec![0; super::RAM_SIZE],
+            quirks: Quirks::new()
+        })
+    }
+
+    // Throws away every compiled block, needed when the cpu is replaced by
+    // another one, e.g. a reset or a loaded state
+    pub fn flush(&mut self) {
+        self.blocks.iter_mut().for_each(|block| *block = None);
+        self.code.iter_mut().for_each(|code| *code = false);
+        self.compiles.iter_mut().for_each(|compiles| *compiles = 0);
+        self.buffer.clear();
+    }
+
+    // Throws away the blocks compiled from memory written since last time
+    fn invalidate(&mut self, cpu: &mut Cpu) {
+        let (first, last) = match cpu.take_written() {
+            Some(range) => range,
+            None => return
+        };
+        if !self.code[first..=last].iter().any(|code| *code) {
+            return;
+        }
+        for address in first.saturating_sub(BLOCK_LIMIT * 2)..=last {
+            if let Some(block) = &self.blocks[address] {
+                if block.end > first {
+                    self.blocks[address] = None;
+                }
+            }
+        }
+    }
+
+    fn compile(&mut self, cpu: &Cpu, pc: usize) -> Result<(), String> {
+        let (code, mut block) = compile_block(cpu.get_memory(), pc, self.quirks);
+        self.compiles[pc] += 1;
+        block.offset = if block.count == 0 || self.compiles[pc] > RECOMPILE_LIMIT {
+            None
+        } else {
+            match self.buffer.add(&code)? {
+                Some(offset) => Some(offset),
+                None => {
+                    // full, start over with only this block
+                    self.flush();
+                    self.buffer.add(&code)?
+                }
+            }
+        };
+        // the instruction left to the interpreter counts too, it may become
+        // one that compiles
+        block.end = block.end.max(pc + 2);
+        self.code[pc..block.end].iter_mut().for_each(|code| *code = true);
+        self.blocks[pc] = Some(block);
+        Ok(())
+    }
+
+    // Runs one block, or one instruction in the interpreter, within `budget`
+    // instructions and returns how many ran
+    pub fn step(&mut self, cpu: &mut Cpu, budget: u32) -> Result<u32, String> {
+        if cpu.get_quirks() != self.quirks {
+            self.flush();
+            self.quirks = cpu.get_quirks();
+        }
+        self.invalidate(cpu);
+        if cpu.is_waiting_for_key() {
+            // the interpreter would do nothing for the rest of the frame
+            cpu.emulate_cycle().map_err(|error| error.to_string())?;
+            return Ok(budget);
+        }
+        let pc = cpu.get_pc() as usize;
+        if pc + 1 < super::RAM_SIZE {
+            if self.blocks[pc].is_none() {
+                self.compile(cpu, pc)?;
+            }
+            let block = self.blocks[pc].as_ref().unwrap();
+            if let (Some(offset), true) = (block.offset, block.count <= budget) {
+                let function = self.buffer.get_function(offset);
+                let (v, i, delay_timer, sound_timer, memory) = cpu.get_jit_state();
+                let exit = function(v.as_mut_ptr(), i, delay_timer, sound_timer, memory.as_mut_ptr());
+                let (next, count) = ((exit & 0xFFFF) as u16, exit >> 16);
+                // a block leaving early never got to its store
+                match block.store {
+                    Some(Instruction::LdBcd(_)) if count == block.count => {
+                        let i = cpu.get_i() as usize;
+                        cpu.mark_written(i, i + 2);
+                    }
+                    Some(Instruction::Store(x)) if count == block.count => {
+                        let i = cpu.get_i() as usize;
+                        let first = if self.quirks.load_store_increments_i { i - x.index() - 1 } else { i };
+                        cpu.mark_written(first, first + x.index());
+                    }
+                    _ => {}
+                }
+                cpu.end_block(next);
+                if count > 0 {
+                    return Ok(count);
+                }
+                // left before its first instruction, which the interpreter runs
+            }
+        }
+        cpu.emulate_cycle().map_err(|error| error.to_string())?;
+        Ok(1)
+    }
+
+    // Runs exactly `cycles` instructions, like as many emulate_cycle calls
+    pub fn run(&mut self, cpu: &mut Cpu, cycles: u32) -> Result<(), String> {
+        let mut remaining = cycles;
+        while remaining > 0 {
+            remaining -= self.step(cpu, remaining)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use super::*;
+
+    // Runs the program in the JIT and in the interpreter side by side, a
+    // frame of 10 instructions at a time, and compares the two after each
+    fn run_both(program: &[u8], preset: &str, frames: u32) {
+        let mut jit = Jit::new().unwrap();
+        let mut compiled = Cpu::new();
+        compiled.set_seed(1);
+        compiled.set_quirks(Quirks::from_preset(preset).unwrap());
+        compiled.load_rom_at(program, super::super::INITIAL_PC).unwrap();
+        let mut interpreted = compiled.clone();
+        for frame in 0..frames {
+            jit.run(&mut compiled, 10).unwrap();
+            for _ in 0..10 {
+                interpreted.emulate_cycle().unwrap();
+            }
+            if let Some(difference) = compiled.compare(&interpreted) {
+                panic!("{} quirks, frame {}: {}", preset, frame, difference);
+            }
+            compiled.update_timer();
+            interpreted.update_timer();
+        }
+        assert!(jit.buffer.length > 0, "nothing was compiled");
+    }
+
+    #[test]
+    fn arithmetic_and_vf() {
+        let program = [
+            0x6A, 0x7B, 0x6B, 0xC8, // VA = 123, VB = 200
+            0x8A, 0xB4, 0x8A, 0xB5, // VA += VB with a carry, VA -= VB with a borrow
+            0x8A, 0xB7, 0x8A, 0xB6, // VA = VB - VA, VA = VB >> 1 or VA >> 1
+            0x8A, 0xBE, 0x7A, 0xF0, // VA = VB << 1 or VA << 1, VA += 0xF0
+            0x8A, 0xF4, 0x8F, 0xA5, // VF as Y, then as X
+            0x6F, 0x81, 0x8F, 0xF6, // VF shifted into itself
+            0x8A, 0xB1, 0x8A, 0xB2, // OR and AND, which reset VF on the VIP
+            0x6F, 0x33, 0x8A, 0xB3,
+            0xFA, 0x15, 0xFB, 0x18, // the timers
+            0xFC, 0x07, 0x12, 0x00  // read the delay timer and go again
+        ];
+        for preset in ["modern", "vip", "schip"].iter() {
+            run_both(&program, preset, 20);
+        }
+    }
+
+    #[test]
+    fn skips() {
+        let program = [
+            0x70, 0x01, 0x71, 0x03, // V0 += 1, V1 += 3
+            0x30, 0x05, 0x62, 0x11, // taken once V0 is 5
+            0x40, 0x05, 0x63, 0x22, // taken until then
+            0x50, 0x10, 0x64, 0x33,
+            0x90, 0x10, 0x65, 0x44,
+            0xA0, 0x00, 0xD2, 0x35, // draws through the interpreter in between
+            0x12, 0x00
+        ];
+        run_both(&program, "modern", 10);
+    }
+
+    #[test]
+    fn stores_and_loads() {
+        let program = [
+            0x60, 0x11, 0x61, 0x22, 0x62, 0x33, 0x63, 0x44,
+            0xA3, 0x00, 0xF3, 0x55, // V0 to V3 into 0x300, I moves on with the VIP quirk
+            0xF3, 0x55, 0xA3, 0x00, // again, then back
+            0xF7, 0x65, 0xF1, 0x65, // V0 to V7 and V0 to V1 out of it
+            0x6A, 0xFE, 0xA3, 0x10,
+            0xFA, 0x33, 0xF2, 0x65, // BCD of 254 and read it back
+            0xFA, 0x1E, 0xFA, 0x29, // I += VA, I = font of VA
+            0x70, 0x01, 0x12, 0x08
+        ];
+        run_both(&program, "modern", 20);
+        run_both(&program, "vip", 20);
+    }
+
+    #[test]
+    fn self_modifying_code() {
+        // rewrites the 7501 at 0x20A to add the loop counter instead of 1,
+        // every time round the loop
+        let program = [
+            0x60, 0x75, // V0 = 0x75, the first byte of 75KK
+            0x71, 0x01, // V1 += 1
+            0xA2, 0x0A, 0xF1, 0x55, // write V0 and V1 over 0x20A
+            0x31, 0x40, // leave once V1 is 0x40
+            0x75, 0x01, // V5 += V1, after the rewrite
+            0x31, 0x40,
+            0x12, 0x02,
+            0x12, 0x10
+        ];
+        run_both(&program, "modern", 60);
+        run_both(&program, "vip", 60);
+    }
+
+    #[test]
+    fn calls() {
+        let program = [
+            0x60, 0x00,
+            0x22, 0x10, // call 0x210, which calls 0x220
+            0x30, 0x20, 0x12, 0x02, // until V0 is 0x20
+            0x12, 0x08,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x70, 0x01, 0x22, 0x20, 0x00, 0xEE, // 0x210
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x70, 0x01, 0x81, 0x04, 0x00, 0xEE // 0x220
+        ];
+        run_both(&program, "modern", 10);
+    }
+}
@@ -1,25 +1,398 @@
-extern crate device_query;
-use device_query::Keycode;
-
-pub fn convert_input(key: &Keycode) -> Option<u8> {
-    match key {
-        Keycode::Key1 => Some(0x1),
-        Keycode::Key2 => Some(0x2),
-        Keycode::Key3 => Some(0x3),
-        Keycode::Key4 => Some(0xc),
-        Keycode::Q => Some(0x4),
-        Keycode::W => Some(0x5),
-        Keycode::E => Some(0x6),
-        Keycode::R => Some(0xd),
-        Keycode::A => Some(0x7),
-        Keycode::S => Some(0x8),
-        Keycode::D => Some(0x9),
-        Keycode::F => Some(0xe),
-        Keycode::Z => Some(0xa),
-        Keycode::X => Some(0x0),
-        Keycode::C => Some(0xb),
-        Keycode::V => Some(0xf),
-        Keycode::Escape => Some(0x10),
-        _   => None
-    }
-}
\ No newline at end of file
+extern crate sdl2;
+extern crate serde;
+extern crate toml;
+
+use sdl2::keyboard::{Keycode, Scancode};
+use serde::Deserialize;
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use super::config::{self, Config};
+use super::controller::{ControllerBindings, ControllerFile};
+use super::rom_loader::RomLoader;
+
+// The left-hand 4x4 block laid over the COSMAC hex keypad. The scancodes are
+// physical key positions, so on AZERTY the top row of letters is still 4 5 6 D.
+// The keycodes are the same keys as printed on a US keyboard, which is what
+// the keycode mode binds whatever the layout.
+const DEFAULT_KEYPAD: [(Scancode, Keycode, u8); super::KEYPAD_SIZE] = [
+    (Scancode::Num1, Keycode::Num1, 0x1), (Scancode::Num2, Keycode::Num2, 0x2),
+    (Scancode::Num3, Keycode::Num3, 0x3), (Scancode::Num4, Keycode::Num4, 0xc),
+    (Scancode::Q, Keycode::Q, 0x4), (Scancode::W, Keycode::W, 0x5),
+    (Scancode::E, Keycode::E, 0x6), (Scancode::R, Keycode::R, 0xd),
+    (Scancode::A, Keycode::A, 0x7), (Scancode::S, Keycode::S, 0x8),
+    (Scancode::D, Keycode::D, 0x9), (Scancode::F, Keycode::F, 0xe),
+    (Scancode::Z, Keycode::Z, 0xa), (Scancode::X, Keycode::X, 0x0),
+    (Scancode::C, Keycode::C, 0xb), (Scancode::V, Keycode::V, 0xf)
+];
+
+const DEFAULT_HOTKEYS: [(Scancode, Keycode, Hotkey); 14] = [
+    (Scancode::Escape, Keycode::Escape, Hotkey::Quit),
+    (Scancode::P, Keycode::P, Hotkey::Pause),
+    (Scancode::N, Keycode::N, Hotkey::FrameAdvance),
+    (Scancode::Tab, Keycode::Tab, Hotkey::Turbo),
+    (Scancode::M, Keycode::M, Hotkey::SlowMotion),
+    (Scancode::Backspace, Keycode::Backspace, Hotkey::Reset),
+    (Scancode::F5, Keycode::F5, Hotkey::SaveState),
+    (Scancode::F8, Keycode::F8, Hotkey::LoadState),
+    (Scancode::F11, Keycode::F11, Hotkey::Fullscreen),
+    (Scancode::F2, Keycode::F2, Hotkey::CyclePalette),
+    (Scancode::F1, Keycode::F1, Hotkey::Menu),
+    (Scancode::H, Keycode::H, Hotkey::Help),
+    (Scancode::F9, Keycode::F9, Hotkey::MemoryViewer),
+    (Scancode::F6, Keycode::F6, Hotkey::ToggleCheats)
+];
+
+// The scancode and US keycode pairs of every default binding
+fn default_pairs() -> impl Iterator<Item = (Scancode, Keycode)> {
+    DEFAULT_KEYPAD.iter().map(|(scancode, keycode, _)| (*scancode, *keycode))
+        .chain(DEFAULT_HOTKEYS.iter().map(|(scancode, keycode, _)| (*scancode, *keycode)))
+}
+
+// Whether bindings refer to where a key is (scancode) or what is printed on it (keycode)
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum KeyMode {
+    Scancode,
+    Keycode
+}
+
+impl KeyMode {
+    pub fn from_name(name: &str) -> Option<KeyMode> {
+        match name {
+            "scancode" => Some(KeyMode::Scancode),
+            "keycode" => Some(KeyMode::Keycode),
+            _ => None
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum HostKey {
+    Scancode(Scancode),
+    Keycode(Keycode)
+}
+
+// Emulator controls, bound separately from the CHIP-8 keypad
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Hotkey {
+    Quit,
+    Pause,
+    FrameAdvance, // runs one frame while paused
+    Turbo, // only while held down
+    SlowMotion, // cycles through the slow motion speeds
+    Reset,
+    SaveState,
+    LoadState,
+    Fullscreen,
+    CyclePalette, // remembered for the ROM when save_rom_settings is on
+    Menu, // back to the ROM menu
+    Help, // shows the game's controls, pausing it
+    MemoryViewer, // opens or closes the memory viewer window
+    ToggleCheats // turns the codes from the game's cheat file on or off
+}
+
+impl Hotkey {
+    pub fn from_name(name: &str) -> Option<Hotkey> {
+        match name {
+            "quit" => Some(Hotkey::Quit),
+            "pause" => Some(Hotkey::Pause),
+            "frame_advance" => Some(Hotkey::FrameAdvance),
+            "turbo" => Some(Hotkey::Turbo),
+            "slow_motion" => Some(Hotkey::SlowMotion),
+            "reset" => Some(Hotkey::Reset),
+            "save_state" => Some(Hotkey::SaveState),
+            "load_state" => Some(Hotkey::LoadState),
+            "fullscreen" => Some(Hotkey::Fullscreen),
+            "cycle_palette" => Some(Hotkey::CyclePalette),
+            "menu" => Some(Hotkey::Menu),
+            "help" => Some(Hotkey::Help),
+            "memory_viewer" => Some(Hotkey::MemoryViewer),
+            "toggle_cheats" => Some(Hotkey::ToggleCheats),
+            _ => None
+        }
+    }
+}
+
+/*
+ * Layout of a bindings file. Both tables map to lists of SDL key names, e.g.
+ *
+ *   mode = "scancode"
+ *
+ *   [keypad]
+ *   "5" = ["W", "Up"]
+ *
+ *   [hotkeys]
+ *   pause = ["P", "Space"]
+ *
+ * Anything listed replaces the default host keys for that CHIP-8 key or hotkey.
+ * In scancode mode the names are the US layout names of the key positions.
+ * Controller mappings live in the same file, see controller.rs.
+ */
+#[derive(Deserialize, Default, Clone)]
+pub struct BindingsFile {
+    pub mode: Option<String>,
+    #[serde(default)]
+    pub keypad: HashMap<String, Vec<String>>,
+    #[serde(default)]
+    pub hotkeys: HashMap<String, Vec<String>>,
+    pub controller: Option<ControllerFile>
+}
+
+impl BindingsFile {
+    pub fn load(path: &Path) -> Result<BindingsFile, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|error| format!("{}: {}", path.display(), error))?;
+        toml::from_str(&text).map_err(|error| format!("{}: {}", path.display(), error))
+    }
+}
+
+#[derive(Clone)]
+pub struct KeyBindings {
+    mode: KeyMode,
+    keypad: HashMap<HostKey, u8>,
+    hotkeys: HashMap<HostKey, Hotkey>,
+    controller: ControllerBindings
+}
+
+impl KeyBindings {
+    pub fn new() -> KeyBindings {
+        KeyBindings {
+            mode: KeyMode::Scancode,
+            keypad: DEFAULT_KEYPAD.iter().map(|(scancode, _, key)| (HostKey::Scancode(*scancode), *key)).collect(),
+            hotkeys: DEFAULT_HOTKEYS.iter().map(|(scancode, _, hotkey)| (HostKey::Scancode(*scancode), *hotkey)).collect(),
+            controller: ControllerBindings::new()
+        }
+    }
+
+    // Switching to keycodes puts the defaults on the keys labelled as on a US
+    // keyboard, 1234/QWER/ASDF/ZXCV, wherever the layout puts them. Other keys
+    // are converted by SDL through the current layout.
+    pub fn set_mode(&mut self, mode: KeyMode) {
+        if mode == self.mode {
+            return;
+        }
+        let convert = |host_key: &HostKey| match (mode, *host_key) {
+            (KeyMode::Keycode, HostKey::Scancode(scancode)) => default_pairs()
+                .find(|(default, _)| *default == scancode)
+                .map(|(_, keycode)| keycode)
+                .or_else(|| Keycode::from_scancode(scancode))
+                .map(HostKey::Keycode),
+            (KeyMode::Scancode, HostKey::Keycode(keycode)) => default_pairs()
+                .find(|(_, default)| *default == keycode)
+                .map(|(scancode, _)| scancode)
+                .or_else(|| Scancode::from_keycode(keycode))
+                .map(HostKey::Scancode),
+            (_, host_key) => Some(host_key)
+        };
+        self.keypad = self.keypad.iter()
+            .filter_map(|(host_key, key)| convert(host_key).map(|host_key| (host_key, *key)))
+            .collect();
+        self.hotkeys = self.hotkeys.iter()
+            .filter_map(|(host_key, hotkey)| convert(host_key).map(|host_key| (host_key, *hotkey)))
+            .collect();
+        self.mode = mode;
+    }
+
+    pub fn get_mode(&self) -> KeyMode {
+        self.mode
+    }
+
+    // Defaults, then the user's bindings.toml and the [bindings] of config.toml,
+    // then a per-ROM file named after either the SHA-1 of the ROM or its file
+    // name, and finally the [rom.<sha1>.bindings] of config.toml.
+    // Per-ROM files are also where controller profiles for a game go.
+    pub fn for_rom(rom_loader: &RomLoader, config: &Config) -> KeyBindings {
+        let mut bindings = KeyBindings::new();
+        let directory = config::config_dir();
+        let files = |names: &[String]| -> Vec<BindingsFile> {
+            let directory = match &directory {
+                Some(directory) => directory,
+                None => return Vec::new()
+            };
+            names.iter()
+                .map(|name| directory.join(name))
+                .filter(|path| path.is_file())
+                .filter_map(|path| BindingsFile::load(&path)
+                    .map_err(|error| eprintln!("Ignoring key bindings in {}", error))
+                    .ok())
+                .collect()
+        };
+        let mut layers = files(&["bindings.toml".to_string()]);
+        layers.extend(config.defaults.bindings.clone());
+        layers.extend(files(&[
+            format!("bindings/{}.toml", rom_loader.get_hash()),
+            format!("bindings/{}.toml", rom_loader.get_stem())
+        ]));
+        layers.extend(config.rom_bindings(rom_loader.get_hash()).cloned());
+        for layer in layers.iter() {
+            if let Err(error) = bindings.apply(layer) {
+                eprintln!("Ignoring key bindings: {}", error);
+            }
+        }
+        bindings
+    }
+
+    // A layer is applied to a copy that replaces these bindings once all of it
+    // has parsed, so a mistake halfway through a file leaves them as they were
+    pub fn apply(&mut self, file: &BindingsFile) -> Result<(), String> {
+        let mut bindings = self.clone();
+        if let Some(mode_name) = &file.mode {
+            let mode = KeyMode::from_name(mode_name)
+                .ok_or_else(|| format!("{} is not a key mode, expected scancode or keycode", mode_name))?;
+            bindings.set_mode(mode);
+        }
+        for (key_name, host_keys) in file.keypad.iter() {
+            let key = u8::from_str_radix(key_name, 16).ok()
+                .filter(|key| (*key as usize) < super::KEYPAD_SIZE)
+                .ok_or_else(|| format!("{} is not a CHIP-8 key, expected 0 to F", key_name))?;
+            let host_keys = parse_host_keys(bindings.mode, host_keys)?;
+            bindings.keypad.retain(|_, bound| *bound != key);
+            for host_key in host_keys {
+                bindings.hotkeys.remove(&host_key);
+                bindings.keypad.insert(host_key, key);
+            }
+        }
+        for (hotkey_name, host_keys) in file.hotkeys.iter() {
+            let hotkey = Hotkey::from_name(hotkey_name)
+                .ok_or_else(|| format!("{} is not a hotkey", hotkey_name))?;
+            let host_keys = parse_host_keys(bindings.mode, host_keys)?;
+            bindings.hotkeys.retain(|_, bound| *bound != hotkey);
+            for host_key in host_keys {
+                bindings.keypad.remove(&host_key);
+                bindings.hotkeys.insert(host_key, hotkey);
+            }
+        }
+        if let Some(controller) = &file.controller {
+            bindings.controller.apply(controller)?;
+        }
+        *self = bindings;
+        Ok(())
+    }
+
+    // Picks whichever half of an SDL key event this binding mode cares about
+    pub fn host_key(&self, keycode: Option<Keycode>, scancode: Option<Scancode>) -> Option<HostKey> {
+        match self.mode {
+            KeyMode::Scancode => scancode.map(HostKey::Scancode),
+            KeyMode::Keycode => keycode.map(HostKey::Keycode)
+        }
+    }
+
+    pub fn keypad_key(&self, key: HostKey) -> Option<u8> {
+        self.keypad.get(&key).cloned()
+    }
+
+    // Names of the host keys bound to a CHIP-8 key, for showing the controls
+    pub fn keypad_key_names(&self, chip8_key: u8) -> Vec<String> {
+        let mut names: Vec<String> = self.keypad.iter()
+            .filter(|(_, key)| **key == chip8_key)
+            .map(|(host_key, _)| match host_key {
+                HostKey::Scancode(scancode) => scancode.name().to_string(),
+                HostKey::Keycode(keycode) => keycode.name()
+            })
+            .collect();
+        names.sort();
+        names
+    }
+
+    pub fn hotkey(&self, key: HostKey) -> Option<Hotkey> {
+        self.hotkeys.get(&key).cloned()
+    }
+
+    pub fn get_controller(&self) -> &ControllerBindings {
+        &self.controller
+    }
+}
+
+fn parse_host_keys(mode: KeyMode, names: &[String]) -> Result<Vec<HostKey>, String> {
+    names.iter()
+        .map(|name| {
+            let host_key = match mode {
+                KeyMode::Scancode => Scancode::from_name(name).map(HostKey::Scancode),
+                KeyMode::Keycode => Keycode::from_name(name).map(HostKey::Keycode)
+            };
+            host_key.ok_or_else(|| format!("{} is not a key name", name))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(text: &str) -> BindingsFile {
+        toml::from_str(text).unwrap()
+    }
+
+    fn scancode(scancode: Scancode) -> HostKey {
+        HostKey::Scancode(scancode)
+    }
+
+    #[test]
+    fn defaults() {
+        let bindings = KeyBindings::new();
+        assert_eq!(bindings.get_mode(), KeyMode::Scancode);
+        assert_eq!(bindings.keypad_key(scancode(Scancode::W)), Some(0x5));
+        assert_eq!(bindings.keypad_key(scancode(Scancode::Num4)), Some(0xc));
+        assert_eq!(bindings.hotkey(scancode(Scancode::P)), Some(Hotkey::Pause));
+        assert_eq!(bindings.keypad_key_names(0x5), vec!["W".to_string()]);
+    }
+
+    #[test]
+    fn overrides_bindings() {
+        let mut bindings = KeyBindings::new();
+        bindings.apply(&file("
+            [keypad]
+            \"5\" = [\"Up\", \"Space\"]
+            [hotkeys]
+            pause = [\"W\"]
+        ")).unwrap();
+        // the listed keys replace the defaults of that key or hotkey
+        assert_eq!(bindings.keypad_key(scancode(Scancode::Up)), Some(0x5));
+        assert_eq!(bindings.keypad_key(scancode(Scancode::Space)), Some(0x5));
+        assert_eq!(bindings.keypad_key_names(0x5), vec!["Space".to_string(), "Up".to_string()]);
+        assert_eq!(bindings.hotkey(scancode(Scancode::P)), None);
+        // and a host key does one thing only
+        assert_eq!(bindings.keypad_key(scancode(Scancode::W)), None);
+        assert_eq!(bindings.hotkey(scancode(Scancode::W)), Some(Hotkey::Pause));
+        assert_eq!(bindings.keypad_key(scancode(Scancode::Q)), Some(0x4));
+
+        // a later layer moves the key back to the keypad
+        bindings.apply(&file("[keypad]\n\"6\" = [\"W\"]")).unwrap();
+        assert_eq!(bindings.keypad_key(scancode(Scancode::W)), Some(0x6));
+        assert_eq!(bindings.hotkey(scancode(Scancode::W)), None);
+        assert_eq!(bindings.keypad_key(scancode(Scancode::E)), None);
+    }
+
+    #[test]
+    fn keycode_mode() {
+        let mut bindings = KeyBindings::new();
+        bindings.apply(&file("mode = \"keycode\"\n[keypad]\n\"f\" = [\"Return\"]")).unwrap();
+        assert_eq!(bindings.get_mode(), KeyMode::Keycode);
+        assert_eq!(bindings.keypad_key(HostKey::Keycode(Keycode::Q)), Some(0x4));
+        assert_eq!(bindings.keypad_key(HostKey::Keycode(Keycode::Return)), Some(0xf));
+        assert_eq!(bindings.keypad_key(scancode(Scancode::Q)), None);
+        assert_eq!(bindings.hotkey(HostKey::Keycode(Keycode::Escape)), Some(Hotkey::Quit));
+        assert_eq!(bindings.host_key(Some(Keycode::Q), Some(Scancode::A)), Some(HostKey::Keycode(Keycode::Q)));
+    }
+
+    #[test]
+    fn bad_layers_change_nothing() {
+        // each of these has a good keypad binding before the mistake
+        let layers = [
+            "mode = \"azerty\"\n[keypad]\n\"5\" = [\"Up\"]",
+            "[keypad]\n\"5\" = [\"Up\"]\n\"10\" = [\"Down\"]",
+            "[keypad]\n\"5\" = [\"Up\"]\n\"6\" = [\"Nowhere\"]",
+            "[keypad]\n\"5\" = [\"Up\"]\n[hotkeys]\nrewind = [\"T\"]",
+            "[keypad]\n\"5\" = [\"Up\"]\n[controller.buttons]\nturbo = \"1\""
+        ];
+        for layer in layers.iter() {
+            let mut bindings = KeyBindings::new();
+            assert!(bindings.apply(&file(layer)).is_err(), "{}", layer);
+            assert_eq!(bindings.get_mode(), KeyMode::Scancode);
+            assert_eq!(bindings.keypad_key(scancode(Scancode::Up)), None, "{}", layer);
+            assert_eq!(bindings.keypad_key(scancode(Scancode::W)), Some(0x5), "{}", layer);
+        }
+    }
+}
@@ -0,0 +1,59 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+
+/*
+ * The cpu and what it needs build without std, see the std feature in
+ * Cargo.toml. Everything else (files, SDL, the JIT) comes with std.
+ */
+
+#[cfg(feature = "std")]
+pub mod analyzer;
+#[cfg(feature = "std")]
+pub mod audio;
+#[cfg(feature = "std")]
+pub mod cheats;
+#[cfg(feature = "std")]
+pub mod config;
+#[cfg(feature = "std")]
+pub mod controller;
+pub mod cpu;
+#[cfg(feature = "std")]
+pub mod display;
+#[cfg(feature = "std")]
+pub mod engine;
+pub mod font;
+#[cfg(feature = "std")]
+pub mod frontend;
+#[cfg(feature = "std")]
+pub mod rom_loader;
+#[cfg(feature = "std")]
+pub mod input;
+pub mod instruction;
+#[cfg(all(feature = "std", target_arch = "x86_64", target_os = "linux"))]
+pub mod jit;
+#[cfg(feature = "std")]
+pub mod keys;
+#[cfg(feature = "std")]
+pub mod lint;
+#[cfg(feature = "std")]
+pub mod memview;
+#[cfg(feature = "std")]
+pub mod menu;
+#[cfg(feature = "std")]
+pub mod patch;
+#[cfg(feature = "std")]
+pub mod profiler;
+pub mod quirks;
+pub mod rng;
+#[cfg(feature = "std")]
+pub mod romdb;
+#[cfg(feature = "std")]
+pub mod text;
+
+pub const WIDTH: usize = 64;
+pub const HEIGHT: usize = 32;
+pub const RAM_SIZE: usize = 4096;
+pub const REGISTER_COUNT: usize = 16;
+pub const STACK_SIZE: usize = 16;
+pub const KEYPAD_SIZE: usize = 16;
+pub const INITIAL_PC: u16 = 0x200;
+pub const ROM_SIZE: usize = 4096; // completely arbitrary number
@@ -0,0 +1,331 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use super::instruction::Instruction;
+use super::quirks::Quirks;
+
+// Stop following paths after this many, whatever the program looks like
+const STATE_LIMIT: usize = 100_000;
+// After this many different values of I at one instruction, e.g. in a loop
+// storing to consecutive addresses, I is treated as unknown there
+const I_VALUE_LIMIT: usize = 16;
+
+/*
+ * Checks a ROM for things that would go wrong in this emulator, by walking
+ * every path from the entry point. Along each path the call depth and, where
+ * it comes from an ANNN, the value of I are tracked. Register values are not,
+ * so BNNN jumps end a path and I is forgotten after FX1E or FX29.
+ */
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Severity {
+    Note,
+    Warning,
+    Error
+}
+
+impl Severity {
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            Severity::Note => "note",
+            Severity::Warning => "warning",
+            Severity::Error => "error"
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct Diagnostic {
+    pub address: u16,
+    pub severity: Severity,
+    pub message: String
+}
+
+// One point on a path through the program
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct State {
+    address: u16,
+    depth: usize, // how many calls deep
+    i: Option<u16> // None when it cannot be known
+}
+
+struct Linter<'a> {
+    rom: &'a [u8],
+    quirks: Quirks,
+    diagnostics: BTreeSet<Diagnostic>,
+    code: HashSet<u16>, // addresses of every byte executed as an instruction
+    data: HashSet<u16>, // addresses read or written through I
+    writes: Vec<(u16, u16, u16)> // instruction address, first and last byte written
+}
+
+impl<'a> Linter<'a> {
+    fn end(&self) -> u16 {
+        super::INITIAL_PC + self.rom.len() as u16
+    }
+
+    fn in_rom(&self, address: u16) -> bool {
+        address >= super::INITIAL_PC && address + 1 < self.end()
+    }
+
+    fn report(&mut self, address: u16, severity: Severity, message: String) {
+        self.diagnostics.insert(Diagnostic { address: address, severity: severity, message: message });
+    }
+
+    // Checks that `length` bytes from I stay in memory and remembers them as data
+    fn access(&mut self, address: u16, i: Option<u16>, length: u16, what: &str) -> Option<(u16, u16)> {
+        let i = i?;
+        let last = i as usize + length as usize - 1;
+        if last >= super::RAM_SIZE {
+            self.report(address, Severity::Error,
+                        format!("{} at I={:#05x} reaches {:#06x}, past the end of memory", what, i, last));
+            return None;
+        }
+        for byte in i..=last as u16 {
+            self.data.insert(byte);
+        }
+        Some((i, last as u16))
+    }
+
+    fn jump(&mut self, address: u16, target: u16, pending: &mut Vec<State>, state: State) {
+        if self.in_rom(target) {
+            pending.push(State { address: target, ..state });
+        } else {
+            self.report(address, Severity::Error,
+                        format!("jumps to {:#05x}, outside the ROM ({:#05x}-{:#05x})", target, super::INITIAL_PC, self.end() - 1));
+        }
+    }
+
+    // Follows one instruction, queueing the states it can lead to
+    fn step(&mut self, state: State, pending: &mut Vec<State>) {
+        let address = state.address;
+        let offset = (address - super::INITIAL_PC) as usize;
+        let opcode = (self.rom[offset] as u16) << 8 | self.rom[offset + 1] as u16;
+        self.code.insert(address);
+        self.code.insert(address + 1);
+        let instruction = Instruction::decode(opcode);
+        if let Instruction::Unknown(_) = instruction {
+            self.report(address, Severity::Error, format!("{:04x} is not a supported instruction and stops the emulator", opcode));
+            return;
+        }
+
+        let next = State { address: address + 2, ..state };
+        let mut fall_through = true;
+        match instruction {
+            Instruction::Ret => {
+                if state.depth == 0 {
+                    self.report(address, Severity::Error, "returns with nothing on the stack".to_string());
+                }
+                // paths carry on after the call that led here
+                fall_through = false;
+            }
+            Instruction::Jp(nnn) => {
+                self.jump(address, nnn, pending, state);
+                fall_through = false;
+            }
+            Instruction::Call(nnn) => {
+                if state.depth >= super::STACK_SIZE {
+                    self.report(address, Severity::Error,
+                                format!("calls can nest deeper than the {} entry stack", super::STACK_SIZE));
+                } else {
+                    self.jump(address, nnn, pending, State { depth: state.depth + 1, ..state });
+                }
+            }
+            _ if instruction.is_skip() => {
+                pending.push(State { address: address + 4, ..state });
+            }
+            Instruction::LdI(nnn) => {
+                pending.push(State { i: Some(nnn), ..next });
+                fall_through = false;
+            }
+            Instruction::JpV0(_) => {
+                self.report(address, Severity::Note, "computed jump, the code it leads to is not checked".to_string());
+                fall_through = false;
+            }
+            Instruction::Drw(_, _, n) if n > 0 => {
+                self.access(address, state.i, n as u16, "sprite");
+            }
+            Instruction::AddI(_) | Instruction::LdFont(_) => {
+                pending.push(State { i: None, ..next });
+                fall_through = false;
+            }
+            Instruction::LdBcd(_) => {
+                if let Some((first, last)) = self.access(address, state.i, 3, "BCD store") {
+                    self.writes.push((address, first, last));
+                }
+            }
+            Instruction::Store(x) | Instruction::Load(x) => {
+                let count = x.0 as u16 + 1;
+                let range = self.access(address, state.i, count, "register store/load");
+                if let (Instruction::Store(_), Some((first, last))) = (instruction, range) {
+                    self.writes.push((address, first, last));
+                }
+                if self.quirks.load_store_increments_i {
+                    let i = state.i.map(|i| i + count).filter(|i| (*i as usize) < super::RAM_SIZE);
+                    pending.push(State { i: i, ..next });
+                    fall_through = false;
+                }
+            }
+            _ => {}
+        }
+        if fall_through {
+            pending.push(next);
+        }
+    }
+}
+
+pub fn lint(rom: &[u8], quirks: Quirks) -> Vec<Diagnostic> {
+    let mut linter = Linter {
+        rom: rom,
+        quirks: quirks,
+        diagnostics: BTreeSet::new(),
+        code: HashSet::new(),
+        data: HashSet::new(),
+        writes: Vec::new()
+    };
+    let mut seen = HashSet::new();
+    let mut i_values: HashMap<(u16, usize), usize> = HashMap::new();
+    let mut pending = vec![State { address: super::INITIAL_PC, depth: 0, i: None }];
+    while let Some(mut state) = pending.pop() {
+        if seen.contains(&state) {
+            continue;
+        }
+        let count = i_values.entry((state.address, state.depth)).or_insert(0);
+        *count += 1;
+        if *count > I_VALUE_LIMIT {
+            state.i = None;
+            if !seen.insert(state) {
+                continue;
+            }
+        }
+        seen.insert(state);
+        if seen.len() > STATE_LIMIT {
+            linter.report(state.address, Severity::Note, "too many paths, stopped checking here".to_string());
+            break;
+        }
+        // skips and fall-through can run past the last instruction
+        if !linter.in_rom(state.address) {
+            let previous = state.address.saturating_sub(2).max(super::INITIAL_PC);
+            linter.report(previous, Severity::Error, "execution can run past the end of the ROM".to_string());
+            continue;
+        }
+        linter.step(state, &mut pending);
+    }
+
+    // stores into bytes that also run as instructions
+    for (address, first, last) in linter.writes.clone() {
+        if let Some(target) = (first..=last).find(|byte| linter.code.contains(byte)) {
+            linter.report(address, Severity::Warning, format!("writes over code at {:#05x}", target));
+        }
+    }
+
+    // bytes neither run nor read through I are likely dead code or forgotten data
+    let end = linter.end();
+    let mut address = super::INITIAL_PC;
+    while address < end {
+        if linter.code.contains(&address) || linter.data.contains(&address) {
+            address += 1;
+            continue;
+        }
+        let start = address;
+        while address < end && !linter.code.contains(&address) && !linter.data.contains(&address) {
+            address += 1;
+        }
+        linter.report(start, Severity::Note,
+                      format!("{:#05x}-{:#05x} ({} bytes) is never run or read through a known I", start, address - 1, address - start));
+    }
+    linter.diagnostics.into_iter().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn linted(opcodes: &[u16], quirks: Quirks) -> Vec<Diagnostic> {
+        let rom: Vec<u8> = opcodes.iter().flat_map(|opcode| vec![(opcode >> 8) as u8, *opcode as u8]).collect();
+        lint(&rom, quirks)
+    }
+
+    // The only diagnostic, as address, severity and message
+    fn single(opcodes: &[u16]) -> (u16, Severity, String) {
+        let diagnostics = linted(opcodes, Quirks::new());
+        assert_eq!(diagnostics.len(), 1, "{:?}", diagnostics);
+        let diagnostic = diagnostics[0].clone();
+        (diagnostic.address, diagnostic.severity, diagnostic.message)
+    }
+
+    #[test]
+    fn clean_program() {
+        // a call, a sprite at 0x20c and a loop to keep it on screen
+        let program = [0x6005, 0xA20C, 0x220A, 0xD012, 0x1208, 0x00EE, 0x8080];
+        assert_eq!(linted(&program, Quirks::new()), vec![]);
+    }
+
+    #[test]
+    fn unsupported_instruction() {
+        assert_eq!(single(&[0x5001]),
+                   (0x200, Severity::Error, "5001 is not a supported instruction and stops the emulator".to_string()));
+    }
+
+    #[test]
+    fn jump_outside_rom() {
+        assert_eq!(single(&[0x1400]),
+                   (0x200, Severity::Error, "jumps to 0x400, outside the ROM (0x200-0x201)".to_string()));
+    }
+
+    #[test]
+    fn calls_too_deep() {
+        // calls itself, then loops forever after every return
+        assert_eq!(single(&[0x2200, 0x1202]),
+                   (0x200, Severity::Error, "calls can nest deeper than the 16 entry stack".to_string()));
+    }
+
+    #[test]
+    fn return_without_call() {
+        assert_eq!(single(&[0x00EE]), (0x200, Severity::Error, "returns with nothing on the stack".to_string()));
+    }
+
+    #[test]
+    fn runs_past_rom() {
+        assert_eq!(single(&[0x6000]), (0x200, Severity::Error, "execution can run past the end of the ROM".to_string()));
+    }
+
+    #[test]
+    fn computed_jump() {
+        assert_eq!(single(&[0xB300]),
+                   (0x200, Severity::Note, "computed jump, the code it leads to is not checked".to_string()));
+    }
+
+    #[test]
+    fn past_memory() {
+        assert_eq!(single(&[0xAFFF, 0xD012, 0x1204]),
+                   (0x202, Severity::Error, "sprite at I=0xfff reaches 0x1000, past the end of memory".to_string()));
+        assert_eq!(single(&[0xAFFE, 0xF033, 0x1204]),
+                   (0x202, Severity::Error, "BCD store at I=0xffe reaches 0x1000, past the end of memory".to_string()));
+        assert_eq!(single(&[0xAFFE, 0xF255, 0x1204]),
+                   (0x202, Severity::Error, "register store/load at I=0xffe reaches 0x1000, past the end of memory".to_string()));
+    }
+
+    #[test]
+    fn writes_over_code() {
+        assert_eq!(single(&[0xA200, 0xF033, 0x1204]),
+                   (0x202, Severity::Warning, "writes over code at 0x200".to_string()));
+    }
+
+    #[test]
+    fn unused_bytes() {
+        assert_eq!(single(&[0x1204, 0x1234, 0x1204]),
+                   (0x202, Severity::Note, "0x202-0x203 (2 bytes) is never run or read through a known I".to_string()));
+    }
+
+    #[test]
+    fn too_many_paths() {
+        // every pass round the loop moves I along and either calls or jumps
+        // back, so each address is reached at every depth with many values of I
+        let mut program = vec![0xA300];
+        program.extend(vec![0x6000; 400]);
+        program.extend(&[0xF055, 0x3000, 0x2202, 0x1202]);
+        let diagnostics = linted(&program, Quirks::from_preset("vip").unwrap());
+        assert!(diagnostics.iter().any(|diagnostic| {
+            diagnostic.severity == Severity::Note && diagnostic.message == "too many paths, stopped checking here"
+        }), "{:?}", diagnostics);
+    }
+}
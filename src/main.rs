@@ -1,40 +1,474 @@
-mod cpu;
-mod display;
-mod font;
-mod rom_loader;
-mod keys;
-
-use cpu::Cpu;
-use display::Display;
-use rom_loader::RomLoader;
-
-const WIDTH: usize = 64;
-const HEIGHT: usize = 32;
-const RAM_SIZE: usize = 4096;
-const REGISTER_COUNT: usize = 16;
-const STACK_SIZE: usize = 16;
-const KEYPAD_SIZE: usize = 16;
-const INITIAL_PC: u16 = 0x200;
-const ROM_SIZE: usize = 4096; // completely arbitrary number
+extern crate rusty_chip8;
+extern crate toml;
 
-fn main() {
-    // TODO: setup graphics and input and sound
-    let args: Vec<String> = std::env::args().collect();
-    let rom_loader = RomLoader::new(args[1].clone());
-    let mut display = Display::new();
+mod cli;
+
+use rusty_chip8::cheats::{self, CheatSearch};
+use rusty_chip8::config::{Config, Resolved, Settings};
+use rusty_chip8::cpu::Cpu;
+use rusty_chip8::display::Palette;
+use rusty_chip8::engine::Engine;
+use rusty_chip8::frontend::Frontend;
+use rusty_chip8::keys::{Hotkey, KeyBindings};
+use rusty_chip8::menu::{self, Menu, MenuInput};
+use rusty_chip8::patch;
+use rusty_chip8::profiler::Profiler;
+use rusty_chip8::rom_loader::RomLoader;
+use rusty_chip8::romdb::{self, RomDatabase};
+
+use cli::{Command, Options};
+
+use std::fs;
+use std::io::{self, BufRead};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const FRAME_RATE: u64 = 60;
+
+// Everything that belongs to the game being played
+struct Game {
+    rom_loader: RomLoader,
+    resolved: Resolved,
+    cpu: Cpu,
+    engine: Engine, // flushed whenever cpu is replaced
+    saved_state: Option<Cpu>,
+    palette_name: Option<String>,
+    cycles_per_frame: u32,
+    cheats: CheatSearch, // kept over resets, not over save states
+    profiler: Option<Profiler> // with --profile
+}
+
+// Why the emulator loop stopped running a game
+enum GameExit {
+    Quit,
+    Menu
+}
+
+enum MenuChoice {
+    Play(PathBuf),
+    Resume,
+    Quit
+}
+
+// The profiler needs to see every instruction, so it always uses the interpreter
+fn run_profiled(profiler: &mut Profiler, cpu: &mut Cpu, cycles: u32) -> Result<(), String> {
+    for _ in 0..cycles {
+        profiler.record(cpu);
+        cpu.emulate_cycle().map_err(|error| error.to_string())?;
+    }
+    Ok(())
+}
+
+// Run a frame's worth of instructions, then tick the timers, like the 60Hz
+// vblank on the original hardware. An error stops the game, the caller still
+// writes its profile before exiting.
+fn run_frame(game: &mut Game) -> Result<(), String> {
+    game.cheats.apply(&mut game.cpu);
+    let result = match &mut game.profiler {
+        Some(profiler) => run_profiled(profiler, &mut game.cpu, game.cycles_per_frame),
+        None => game.engine.run(&mut game.cpu, game.cycles_per_frame)
+    };
+    if let Err(error) = result {
+        // the frame the game stopped in still counts
+        if let Some(profiler) = &mut game.profiler {
+            profiler.end_frame();
+        }
+        return Err(error);
+    }
+    game.cpu.update_timer();
+    if let Some(profiler) = &mut game.profiler {
+        profiler.end_frame();
+    }
+    Ok(())
+}
+
+// Prints the profile of a game that is being left, and writes it as JSON
+fn end_game(options: &Options, game: &Game) {
+    let profiler = match &game.profiler {
+        Some(profiler) => profiler,
+        None => return
+    };
+    let report = profiler.report(options.profile_top);
+    print!("\nProfile of {}: {}", game.rom_loader.get_stem(), report.text);
+    if let Some(path) = &options.profile_json {
+        if let Err(error) = fs::write(path, report.json) {
+            eprintln!("Could not write the profile to {}: {}", path, error);
+        }
+    }
+}
+
+fn new_cpu(options: &Options, resolved: &Resolved, rom_loader: &RomLoader) -> Result<Cpu, String> {
     let mut cpu = Cpu::new();
-    cpu.load_program(&rom_loader);
+    cpu.set_quirks(resolved.quirks);
+    if let Some(seed) = options.seed {
+        cpu.set_seed(seed);
+    }
+    cpu.load_program_at(rom_loader, options.load_address)?;
+    Ok(cpu)
+}
+
+// built-in defaults, then the ROM database or the analyzer, then config.toml,
+// then the config's [rom.<sha1>] section and rom-settings.toml, then the command line
+fn start_game(options: &Options, config: &Config, database: &RomDatabase, path: &str) -> Result<Game, String> {
+    let mut rom_loader = RomLoader::new(path.to_string())?;
+    for patch_path in patch::find_patches(Path::new(path)) {
+        rom_loader.apply_patch(&patch_path)?;
+        eprintln!("Applied {}", patch_path.display());
+    }
+    let mut cheats = CheatSearch::new();
+    if let Some(cheat_path) = cheats::find_cheat_file(Path::new(path)) {
+        cheats.set_codes(cheats::load_codes(&cheat_path)?);
+        eprintln!("Loaded {} cheat codes from {}", cheats.get_codes().len(), cheat_path.display());
+    }
+    let recommended = match database.get(rom_loader.get_hash()) {
+        Some(info) => info.settings(),
+        None if config.detect_quirks.unwrap_or(true) => {
+            let analysis = rom_loader.analyze(options.load_address);
+            eprintln!("{} {}", rom_loader.get_stem(), analysis.summary());
+            for reason in analysis.reasons.iter() {
+                eprintln!("  {}", reason);
+            }
+            analysis.settings()
+        }
+        None => Settings::default()
+    };
+    let resolved = config.for_rom(rom_loader.get_hash(), &recommended).overlay(&options.settings).resolve()
+        .map_err(|error| format!("settings for {}: {}", rom_loader.get_file_name(), error))?;
+    let mut cpu = new_cpu(options, &resolved, &rom_loader)?;
+    cheats.apply_on_load(&mut cpu);
+    let cycles_per_frame = (resolved.ips / FRAME_RATE as u32).max(1);
+    Ok(Game {
+        cycles_per_frame: cycles_per_frame,
+        palette_name: resolved.palette_name.clone(),
+        rom_loader: rom_loader,
+        resolved: resolved,
+        cpu: cpu,
+        engine: Engine::new(options.engine)?,
+        saved_state: None,
+        cheats: cheats,
+        profiler: if options.profile { Some(Profiler::new(cycles_per_frame)) } else { None }
+    })
+}
+
+// Prints the screen as text, handy for scripts and quick checks
+fn print_display(cpu: &Cpu) {
+    for row in cpu.get_display().display.iter() {
+        let line: String = row.iter().map(|pixel| if *pixel != 0 { '#' } else { '.' }).collect();
+        println!("{}", line);
+    }
+}
+
+fn run_headless(options: &Options, mut game: Game) {
+    let result = (0..options.frames.unwrap_or(0)).try_for_each(|_| run_frame(&mut game));
+    match result {
+        Ok(()) => {
+            print_display(&game.cpu);
+            end_game(options, &game);
+        }
+        Err(error) => {
+            eprintln!("error: {}", error);
+            end_game(options, &game);
+            process::exit(1);
+        }
+    }
+}
+
+// Reads cheat commands from stdin on its own thread, so waiting for a line
+// never holds up the emulator
+fn spawn_console() -> Receiver<String> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let stdin = io::stdin();
+        for line in stdin.lock().lines() {
+            match line {
+                Ok(line) => if sender.send(line).is_err() { break },
+                Err(_) => break
+            }
+        }
+    });
+    println!("Cheat console ready, type 'help' for the commands");
+    receiver
+}
+
+// Commands typed since the last frame, run against the current game
+fn run_console(console: &Receiver<String>, game: &mut Game) {
+    for line in console.try_iter() {
+        match game.cheats.execute(&game.cpu, &line) {
+            Ok(output) => {
+                if !output.is_empty() {
+                    println!("{}", output);
+                }
+            }
+            Err(error) => println!("error: {}", error)
+        }
+    }
+}
+
+// The next palette preset after `current`, custom colours start from the first one
+fn next_palette(current: Option<&str>) -> &'static str {
+    let names = Palette::preset_names();
+    let index = names.iter().position(|name| Some(*name) == current)
+        .map_or(0, |index| (index + 1) % names.len());
+    names[index]
+}
+
+// The whole path rarely fits on screen, the file name is enough there
+fn short_error(error: &str, path: &str) -> String {
+    match Path::new(path).file_name() {
+        Some(name) => error.replace(path, &name.to_string_lossy()),
+        None => error.to_string()
+    }
+}
+
+// Hands the game's own settings and key bindings to the frontend
+fn enter_game(options: &Options, frontend: &mut Frontend, config: &Config, database: &RomDatabase, game: &Game) {
+    let mut bindings = KeyBindings::for_rom(&game.rom_loader, config);
+    if let Some(mode) = game.resolved.key_mode {
+        bindings.set_mode(mode);
+    }
+    let hash = game.rom_loader.get_hash();
+    let info = database.get(hash);
+    frontend.configure(&game.resolved.frontend);
+    frontend.set_help(romdb::help_lines(hash, info, &bindings));
+    frontend.set_bindings(bindings);
+    frontend.set_rom_range(options.load_address, game.rom_loader.get_length());
+    let title = info.and_then(|info| info.title.clone()).unwrap_or_else(|| game.rom_loader.get_stem());
+    frontend.set_title(Some(&title));
+    if let Err(error) = menu::add_recent(Path::new(game.rom_loader.get_file_name())) {
+        eprintln!("Could not update the recent ROMs: {}", error);
+    }
+}
+
+fn run_game(options: &Options, config: &Config, database: &RomDatabase, frontend: &mut Frontend, console: Option<&Receiver<String>>, game: &mut Game, frames: &mut u64) -> Result<GameExit, String> {
+    let frame_duration = Duration::from_micros(1_000_000 / FRAME_RATE);
+    let mut next_frame = Instant::now();
+    while !frontend.should_quit() && options.frames.map_or(true, |limit| *frames < limit) {
+        frontend.handle_events(&mut game.cpu);
+        // a ROM dropped onto the window replaces the game, a bad one leaves it running
+        if let Some(path) = frontend.take_dropped_file() {
+            match start_game(options, config, database, &path) {
+                Ok(new_game) => {
+                    frontend.release_all(&mut game.cpu);
+                    end_game(options, game);
+                    *game = new_game;
+                    enter_game(options, frontend, config, database, game);
+                }
+                Err(error) => {
+                    eprintln!("error: {}", error);
+                    frontend.show_message(short_error(&error, &path));
+                }
+            }
+        }
+        for action in frontend.take_actions() {
+            match action {
+                Hotkey::Reset => {
+                    // the ROM already loaded once, so it fits
+                    game.cpu = new_cpu(options, &game.resolved, &game.rom_loader).unwrap();
+                    game.engine.flush();
+                    game.cheats.apply_on_load(&mut game.cpu);
+                    frontend.release_all(&mut game.cpu);
+                }
+                Hotkey::FrameAdvance => {
+                    run_frame(game)?;
+                    frontend.end_frame(&game.cpu);
+                    frontend.draw(&game.cpu);
+                }
+                Hotkey::CyclePalette => {
+                    let name = next_palette(game.palette_name.as_ref().map(|name| name.as_str()));
+                    frontend.set_palette(Palette::from_name(name).unwrap());
+                    if frontend.is_paused() {
+                        frontend.draw(&game.cpu);
+                    }
+                    game.palette_name = Some(name.to_string());
+                    let value = toml::Value::String(name.to_string());
+                    if let Err(error) = config.save_rom_setting(game.rom_loader.get_hash(), "palette", value) {
+                        eprintln!("Could not save the palette: {}", error);
+                    }
+                }
+                Hotkey::ToggleCheats if game.cheats.get_codes().is_empty() => {
+                    frontend.show_message("NO CHEAT FILE".to_string());
+                }
+                Hotkey::ToggleCheats => {
+                    let enabled = game.cheats.toggle_codes();
+                    frontend.show_message(if enabled { "CHEATS ON" } else { "CHEATS OFF" }.to_string());
+                }
+                Hotkey::SaveState => game.saved_state = Some(game.cpu.clone()),
+                Hotkey::LoadState => {
+                    if let Some(state) = &game.saved_state {
+                        game.cpu = state.clone();
+                        game.engine.flush();
+                        frontend.release_all(&mut game.cpu);
+                    }
+                }
+                Hotkey::Menu => {
+                    frontend.release_all(&mut game.cpu);
+                    return Ok(GameExit::Menu);
+                }
+                _ => {}
+            }
+        }
+
+        if let Some(console) = console {
+            run_console(console, game);
+        }
+
+        if frontend.is_paused() {
+            frontend.present(&game.cpu);
+        } else if frontend.is_turbo() {
+            // As many frames as fit in one frame of real time, only the last is shown
+            let deadline = Instant::now() + frame_duration;
+            while Instant::now() < deadline {
+                run_frame(game)?;
+                frontend.end_frame(&game.cpu);
+                *frames += 1;
+            }
+            frontend.draw(&game.cpu);
+            next_frame = Instant::now();
+            continue;
+        } else {
+            run_frame(game)?;
+            frontend.end_frame(&game.cpu);
+            *frames += 1;
+            frontend.draw(&game.cpu);
+        }
+
+        // slow motion stretches each frame, e.g. 50% speed shows a frame every 1/30s
+        next_frame += frame_duration * 100 / frontend.get_speed();
+        let now = Instant::now();
+        if next_frame > now {
+            std::thread::sleep(next_frame - now);
+        } else {
+            next_frame = now;
+        }
+    }
+    Ok(GameExit::Quit)
+}
+
+// Backing out of the menu returns to the game when there is one
+fn run_menu(frontend: &mut Frontend, menu: &mut Menu, resumable: bool) -> MenuChoice {
+    let frame_duration = Duration::from_micros(1_000_000 / FRAME_RATE);
+    while !frontend.should_quit() {
+        let inputs = frontend.menu_events();
+        if let Some(path) = frontend.take_dropped_file() {
+            return MenuChoice::Play(PathBuf::from(path));
+        }
+        for input in inputs {
+            if input == MenuInput::Back {
+                return if resumable { MenuChoice::Resume } else { MenuChoice::Quit };
+            }
+            if let Some(path) = menu.handle_input(input, frontend.get_menu_rows()) {
+                return MenuChoice::Play(path);
+            }
+        }
+        frontend.draw_menu(menu);
+        std::thread::sleep(frame_duration);
+    }
+    MenuChoice::Quit
+}
+
+fn run_sdl(options: &Options, config: &Config, database: &RomDatabase, resolved: &Resolved, mut game: Option<Game>, directory: PathBuf) {
+    let mut frontend = Frontend::new(&resolved.frontend, KeyBindings::new());
+    let console = if options.console { Some(spawn_console()) } else { None };
+    if let Some(game) = &game {
+        enter_game(options, &mut frontend, config, database, game);
+    }
+    let mut menu: Option<Menu> = None;
+    let mut in_menu = game.is_none();
+    let mut frames = 0;
     loop {
-        cpu.emulate_cycle();
-        if cpu.get_draw_flag() {
-            display.draw(cpu.get_display());
-        }
-        std::thread::sleep(std::time::Duration::from_millis(1));
-    }
-    // loop {
-    //     cpu.emulate_cycle();
-    //     if cpu.get_draw_flag() {
-    //         display.draw(&cpu.get_display());
-    //     }
-    // }
+        if !in_menu {
+            if let Some(game) = &mut game {
+                match run_game(options, config, database, &mut frontend, console.as_ref(), game, &mut frames) {
+                    Ok(GameExit::Quit) => {
+                        end_game(options, game);
+                        return;
+                    }
+                    Ok(GameExit::Menu) => in_menu = true,
+                    Err(error) => {
+                        eprintln!("error: {}", error);
+                        end_game(options, game);
+                        process::exit(1);
+                    }
+                }
+            }
+        }
+
+        // scanned when first shown, refreshed after that to pick up new files
+        if let Some(menu) = &mut menu {
+            menu.refresh(database);
+        }
+        let menu = menu.get_or_insert_with(|| Menu::new(directory.clone(), database, options.load_address));
+        frontend.set_title(None);
+        match run_menu(&mut frontend, menu, game.is_some()) {
+            MenuChoice::Play(path) => match start_game(options, config, database, &path.to_string_lossy()) {
+                Ok(new_game) => {
+                    enter_game(options, &mut frontend, config, database, &new_game);
+                    menu.set_message(None);
+                    if let Some(game) = &game {
+                        end_game(options, game);
+                    }
+                    game = Some(new_game);
+                    in_menu = false;
+                }
+                Err(error) => {
+                    eprintln!("error: {}", error);
+                    menu.set_message(Some(short_error(&error, &path.to_string_lossy())));
+                }
+            },
+            MenuChoice::Resume => in_menu = false,
+            MenuChoice::Quit => {
+                if let Some(game) = &game {
+                    end_game(options, game);
+                }
+                return;
+            }
+        }
+    }
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let options = match cli::parse(&args) {
+        Ok(Command::Run(options)) => options,
+        Ok(Command::Help) => {
+            print!("{}", cli::USAGE);
+            return;
+        }
+        Ok(Command::Version) => {
+            println!("rusty-chip8 {}", env!("CARGO_PKG_VERSION"));
+            return;
+        }
+        Err(error) => {
+            eprintln!("error: {}\n\n{}", error, cli::USAGE);
+            process::exit(2);
+        }
+    };
+    // settings shared by every game, these also open the window
+    let config = Config::load();
+    let database = RomDatabase::load();
+    let resolved = config.defaults.overlay(&options.settings).resolve().unwrap_or_else(|error| {
+        eprintln!("error: {}", error);
+        process::exit(2);
+    });
+
+    let mut directory = config.get_rom_dir();
+    let game = match &options.rom_path {
+        Some(path) if Path::new(path).is_dir() && !options.headless => {
+            directory = PathBuf::from(path);
+            None
+        }
+        Some(path) => Some(start_game(&options, &config, &database, path).unwrap_or_else(|error| {
+            eprintln!("error: {}", error);
+            process::exit(1);
+        })),
+        None => None
+    };
+    match game {
+        // the command line makes sure headless runs have a ROM
+        Some(game) if options.headless => run_headless(&options, game),
+        game => run_sdl(&options, &config, &database, &resolved, game, directory)
+    }
 }
@@ -0,0 +1,238 @@
+extern crate sdl2;
+
+use sdl2::VideoSubsystem;
+use sdl2::keyboard::Keycode;
+use sdl2::pixels::Color;
+use sdl2::rect::Rect;
+use sdl2::render::Canvas;
+use sdl2::video::Window;
+
+use super::cpu::Cpu;
+use super::font::FONT_SET;
+use super::text;
+
+const BYTES_PER_ROW: usize = 16;
+const VISIBLE_ROWS: usize = 32;
+const SCALE: u32 = 2;
+const MARGIN: i32 = 8;
+// Most bytes an instruction reads from I, a 15 row sprite or FX65 with X = F
+const I_RANGE: usize = 16;
+
+const BACKGROUND: Color = Color::RGB(16, 16, 24);
+const TEXT: Color = Color::RGB(200, 200, 200);
+const FONT_TEXT: Color = Color::RGB(110, 140, 220);
+const ROM_TEXT: Color = Color::RGB(255, 255, 255);
+const FREE_TEXT: Color = Color::RGB(110, 110, 110);
+const CHANGED_TEXT: Color = Color::RGB(255, 90, 90);
+const PC_BACKGROUND: Color = Color::RGB(30, 110, 50);
+const I_BACKGROUND: Color = Color::RGB(110, 80, 20);
+const CURSOR_BACKGROUND: Color = Color::RGB(200, 200, 200);
+
+/*
+ * A second window listing the cpu's memory as hex and ASCII, 16 bytes a row:
+ *
+ *   - the font is blue, the loaded ROM white and the rest grey
+ *   - the two bytes at PC are on green, the bytes I points at on brown
+ *   - bytes that changed since the last frame are red
+ *
+ * The arrow keys and PAGE UP/DOWN move the cursor, HOME jumps to PC and END
+ * to I. While the game is paused, typing two hex digits writes a byte at the
+ * cursor. Other keys work as hotkeys but never reach the CHIP-8 keypad.
+ */
+
+pub struct MemoryViewer {
+    canvas: Canvas<Window>,
+    cursor: u16,
+    first_row: usize, // scrolled so the cursor stays visible
+    pending_digit: Option<u8>, // the high nibble of a byte being typed
+    previous: Vec<u8>, // memory after the last emulated frame
+    changed: Vec<bool>, // which bytes that frame wrote
+    rom_range: (u16, u16) // first and last address of the loaded ROM
+}
+
+impl MemoryViewer {
+    pub fn new(video_subsystem: &VideoSubsystem) -> Result<MemoryViewer, String> {
+        let advance = text::char_advance(SCALE);
+        let line_height = (text::GLYPH_HEIGHT + 3) * SCALE;
+        // "0200  " + 16 "00 " + " " + 16 ASCII characters
+        let width = advance * (6 + BYTES_PER_ROW as u32 * 3 + 1 + BYTES_PER_ROW as u32) + MARGIN as u32 * 2;
+        let height = line_height * (VISIBLE_ROWS as u32 + 2) + MARGIN as u32 * 2;
+        let window = video_subsystem.window("CHIP8 memory", width, height)
+            .build()
+            .map_err(|error| error.to_string())?;
+        let canvas = window.into_canvas().build().map_err(|error| error.to_string())?;
+        Ok(MemoryViewer {
+            canvas: canvas,
+            cursor: super::INITIAL_PC,
+            first_row: 0,
+            pending_digit: None,
+            previous: Vec::new(),
+            changed: vec![false; super::RAM_SIZE],
+            rom_range: (super::INITIAL_PC, super::INITIAL_PC)
+        })
+    }
+
+    pub fn get_window_id(&self) -> u32 {
+        self.canvas.window().id()
+    }
+
+    // Called for every new game, which also forgets the old game's memory
+    pub fn set_rom_range(&mut self, start: u16, length: usize) {
+        self.rom_range = (start, start + length.max(1) as u16 - 1);
+        self.previous.clear();
+    }
+
+    fn move_cursor(&mut self, offset: i32) {
+        let address = (self.cursor as i32 + offset).max(0).min(super::RAM_SIZE as i32 - 1);
+        self.cursor = address as u16;
+        self.pending_digit = None;
+    }
+
+    // Keys pressed while the viewer window has focus, false for keys it does
+    // not use. Editing needs the game paused so the cpu does not overwrite
+    // the byte straight away.
+    pub fn handle_key(&mut self, keycode: Keycode, cpu: &mut Cpu, paused: bool) -> bool {
+        let row = BYTES_PER_ROW as i32;
+        match keycode {
+            Keycode::Left => self.move_cursor(-1),
+            Keycode::Right => self.move_cursor(1),
+            Keycode::Up => self.move_cursor(-row),
+            Keycode::Down => self.move_cursor(row),
+            Keycode::PageUp => self.move_cursor(-row * VISIBLE_ROWS as i32),
+            Keycode::PageDown => self.move_cursor(row * VISIBLE_ROWS as i32),
+            Keycode::Home => {
+                self.cursor = cpu.get_pc() % super::RAM_SIZE as u16;
+                self.pending_digit = None;
+            }
+            Keycode::End => {
+                self.cursor = cpu.get_i() % super::RAM_SIZE as u16;
+                self.pending_digit = None;
+            }
+            _ => {
+                let digit = match hex_digit(keycode) {
+                    Some(digit) => digit,
+                    None => return false
+                };
+                if !paused {
+                    return true;
+                }
+                match self.pending_digit.take() {
+                    Some(high) => {
+                        cpu.set_memory(self.cursor, high << 4 | digit);
+                        self.move_cursor(1);
+                    }
+                    None => self.pending_digit = Some(digit)
+                }
+            }
+        }
+        true
+    }
+
+    fn text_color(&self, address: usize) -> Color {
+        if self.changed[address] {
+            CHANGED_TEXT
+        } else if address < FONT_SET.len() {
+            FONT_TEXT
+        } else if address >= self.rom_range.0 as usize && address <= self.rom_range.1 as usize {
+            ROM_TEXT
+        } else {
+            FREE_TEXT
+        }
+    }
+
+    fn highlight(&self, address: usize, cpu: &Cpu) -> Option<Color> {
+        let pc = cpu.get_pc() as usize;
+        let i = cpu.get_i() as usize;
+        if address == self.cursor as usize {
+            Some(CURSOR_BACKGROUND)
+        } else if address == pc || address == pc + 1 {
+            Some(PC_BACKGROUND)
+        } else if address >= i && address < i + I_RANGE {
+            Some(I_BACKGROUND)
+        } else {
+            None
+        }
+    }
+
+    // Only a newly emulated frame updates which bytes count as changed, so
+    // they stay marked while the game is paused
+    pub fn draw(&mut self, cpu: &Cpu, paused: bool, new_frame: bool) {
+        let memory = cpu.get_memory();
+        if new_frame {
+            for (address, value) in memory.iter().enumerate() {
+                self.changed[address] = self.previous.get(address).map_or(false, |previous| previous != value);
+            }
+            self.previous = memory.to_vec();
+        }
+        let advance = text::char_advance(SCALE) as i32;
+        let line_height = ((text::GLYPH_HEIGHT + 3) * SCALE) as i32;
+        let cursor_row = self.cursor as usize / BYTES_PER_ROW;
+        if cursor_row < self.first_row {
+            self.first_row = cursor_row;
+        } else if cursor_row >= self.first_row + VISIBLE_ROWS {
+            self.first_row = cursor_row + 1 - VISIBLE_ROWS;
+        }
+
+        self.canvas.set_draw_color(BACKGROUND);
+        self.canvas.clear();
+        let editing = match (paused, self.pending_digit) {
+            (true, Some(digit)) => format!("typing {:x}_", digit),
+            (true, None) => "type hex to edit".to_string(),
+            (false, _) => "pause to edit".to_string()
+        };
+        let header = format!("pc {:03x}  i {:03x}  cursor {:03x}={:02x}  {}",
+                             cpu.get_pc(), cpu.get_i(), self.cursor, memory[self.cursor as usize % memory.len()], editing);
+        text::draw_text(&mut self.canvas, &header, MARGIN, MARGIN, SCALE, TEXT);
+
+        for row in 0..VISIBLE_ROWS {
+            let base = (self.first_row + row) * BYTES_PER_ROW;
+            if base >= memory.len() {
+                break;
+            }
+            let y = MARGIN + (row as i32 + 2) * line_height;
+            text::draw_text(&mut self.canvas, &format!("{:04x}", base), MARGIN, y, SCALE, TEXT);
+            for column in 0..BYTES_PER_ROW {
+                let address = base + column;
+                let value = memory[address];
+                let hex_x = MARGIN + (6 + column as i32 * 3) * advance;
+                let ascii_x = MARGIN + (6 + BYTES_PER_ROW as i32 * 3 + 1 + column as i32) * advance;
+                let mut color = self.text_color(address);
+                if let Some(background) = self.highlight(address, cpu) {
+                    self.canvas.set_draw_color(background);
+                    let _ = self.canvas.fill_rect(Rect::new(hex_x - SCALE as i32, y - SCALE as i32,
+                                                            (advance * 2) as u32 + SCALE, line_height as u32 - SCALE));
+                    if background == CURSOR_BACKGROUND {
+                        color = BACKGROUND;
+                    }
+                }
+                text::draw_text(&mut self.canvas, &format!("{:02x}", value), hex_x, y, SCALE, color);
+                let character = if value.is_ascii_graphic() { value as char } else { '.' };
+                text::draw_text(&mut self.canvas, &character.to_string(), ascii_x, y, SCALE, color);
+            }
+        }
+        self.canvas.present();
+    }
+}
+
+fn hex_digit(keycode: Keycode) -> Option<u8> {
+    let digit = match keycode {
+        Keycode::Num0 | Keycode::Kp0 => 0x0,
+        Keycode::Num1 | Keycode::Kp1 => 0x1,
+        Keycode::Num2 | Keycode::Kp2 => 0x2,
+        Keycode::Num3 | Keycode::Kp3 => 0x3,
+        Keycode::Num4 | Keycode::Kp4 => 0x4,
+        Keycode::Num5 | Keycode::Kp5 => 0x5,
+        Keycode::Num6 | Keycode::Kp6 => 0x6,
+        Keycode::Num7 | Keycode::Kp7 => 0x7,
+        Keycode::Num8 | Keycode::Kp8 => 0x8,
+        Keycode::Num9 | Keycode::Kp9 => 0x9,
+        Keycode::A => 0xa,
+        Keycode::B => 0xb,
+        Keycode::C => 0xc,
+        Keycode::D => 0xd,
+        Keycode::E => 0xe,
+        Keycode::F => 0xf,
+        _ => return None
+    };
+    Some(digit)
+}
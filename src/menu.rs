@@ -0,0 +1,216 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use super::analyzer::Platform;
+use super::config;
+use super::rom_loader::RomLoader;
+use super::romdb::RomDatabase;
+
+// How many recently played ROMs are remembered
+pub const RECENT_LIMIT: usize = 8;
+const EXTENSIONS: [&str; 4] = ["ch8", "c8", "sc8", "xo8"];
+
+/*
+ * The ROM browser shown when the emulator is started without a ROM, or when
+ * leaving a game with the menu hotkey. Recently played ROMs are listed above
+ * every ROM found in the ROM directory.
+ */
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MenuInput {
+    Up,
+    Down,
+    PageUp,
+    PageDown,
+    Select,
+    Back
+}
+
+pub struct RomEntry {
+    pub path: PathBuf,
+    pub title: String,
+    pub size: usize,
+    pub platform: Platform
+}
+
+impl RomEntry {
+    // Title and platform come from the ROM database when it knows the ROM
+    pub fn new(path: &Path, database: &RomDatabase, load_address: u16) -> Result<RomEntry, String> {
+        let rom_loader = RomLoader::new(path.to_string_lossy().into_owned())?;
+        let info = database.get(rom_loader.get_hash());
+        Ok(RomEntry {
+            path: path.to_path_buf(),
+            // e.g. "space_invaders.ch8" is shown as "space invaders"
+            title: info.and_then(|info| info.title.clone())
+                .unwrap_or_else(|| rom_loader.get_stem().replace('_', " ")),
+            size: rom_loader.get_length(),
+            platform: info.and_then(|info| info.get_platform())
+                .unwrap_or_else(|| rom_loader.analyze(load_address).platform)
+        })
+    }
+}
+
+// One row of the menu as it should be drawn
+pub struct MenuLine {
+    pub text: String,
+    pub selected: bool,
+    pub heading: bool
+}
+
+pub struct Menu {
+    directory: PathBuf,
+    load_address: u16, // where games are started, which the analyzer needs to follow jumps
+    recent: Vec<RomEntry>,
+    roms: Vec<RomEntry>,
+    selected: usize, // into recent followed by roms
+    message: Option<String>
+}
+
+impl Menu {
+    pub fn new(directory: PathBuf, database: &RomDatabase, load_address: u16) -> Menu {
+        let mut menu = Menu {
+            directory: directory,
+            load_address: load_address,
+            recent: Vec::new(),
+            roms: Vec::new(),
+            selected: 0,
+            message: None
+        };
+        menu.refresh(database);
+        menu
+    }
+
+    // Reads the ROM directory and the recent list again, e.g. after a game was played
+    pub fn refresh(&mut self, database: &RomDatabase) {
+        self.recent = load_recent().iter()
+            .filter_map(|path| RomEntry::new(path, database, self.load_address).ok())
+            .collect();
+        self.roms = match fs::read_dir(&self.directory) {
+            Ok(entries) => entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.path())
+                .filter(|path| path.is_file() && is_rom(path))
+                .filter_map(|path| RomEntry::new(&path, database, self.load_address).ok())
+                .collect(),
+            Err(error) => {
+                self.message = Some(format!("cannot read {}: {}", self.directory.display(), error));
+                Vec::new()
+            }
+        };
+        self.roms.sort_by_key(|entry| entry.title.to_lowercase());
+        self.selected = self.selected.min(self.len().saturating_sub(1));
+    }
+
+    fn len(&self) -> usize {
+        self.recent.len() + self.roms.len()
+    }
+
+    fn entry(&self, index: usize) -> Option<&RomEntry> {
+        if index < self.recent.len() {
+            self.recent.get(index)
+        } else {
+            self.roms.get(index - self.recent.len())
+        }
+    }
+
+    pub fn get_directory(&self) -> &Path {
+        &self.directory
+    }
+
+    // A line shown at the bottom, e.g. why a ROM could not be started
+    pub fn set_message(&mut self, message: Option<String>) {
+        self.message = message;
+    }
+
+    pub fn get_message(&self) -> Option<&str> {
+        self.message.as_ref().map(|message| message.as_str())
+    }
+
+    // Moves the selection, returning the ROM to start when one was picked
+    pub fn handle_input(&mut self, input: MenuInput, page_size: usize) -> Option<PathBuf> {
+        let last = self.len().saturating_sub(1);
+        match input {
+            MenuInput::Up => self.selected = self.selected.saturating_sub(1),
+            MenuInput::Down => self.selected = (self.selected + 1).min(last),
+            MenuInput::PageUp => self.selected = self.selected.saturating_sub(page_size.max(1)),
+            MenuInput::PageDown => self.selected = (self.selected + page_size.max(1)).min(last),
+            MenuInput::Select => return self.entry(self.selected).map(|entry| entry.path.clone()),
+            MenuInput::Back => {}
+        }
+        None
+    }
+
+    // Every row of the menu, each ROM formatted to fit `columns` characters
+    pub fn lines(&self, columns: usize) -> Vec<MenuLine> {
+        let mut lines = Vec::new();
+        let heading = |text: String| MenuLine { text: text, selected: false, heading: true };
+        if !self.recent.is_empty() {
+            lines.push(heading("Recent".to_string()));
+            for (index, entry) in self.recent.iter().enumerate() {
+                lines.push(self.line(entry, index, columns));
+            }
+        }
+        lines.push(heading(format!("ROMs in {}", self.directory.display())));
+        if self.roms.is_empty() {
+            lines.push(MenuLine { text: "  no ROMs found".to_string(), selected: false, heading: false });
+        }
+        for (index, entry) in self.roms.iter().enumerate() {
+            lines.push(self.line(entry, self.recent.len() + index, columns));
+        }
+        lines
+    }
+
+    fn line(&self, entry: &RomEntry, index: usize, columns: usize) -> MenuLine {
+        // title, then platform and size in fixed width columns on the right
+        let details = format!(" {:<7} {:>5}B", entry.platform.get_name(), entry.size);
+        let title_width = columns.saturating_sub(details.len() + 2);
+        let title: String = entry.title.chars().take(title_width).collect();
+        MenuLine {
+            text: format!("  {:<width$}{}", title, details, width = title_width),
+            selected: index == self.selected,
+            heading: false
+        }
+    }
+}
+
+// Judged by the extension, e.g. game.ch8
+pub fn is_rom(path: &Path) -> bool {
+    path.extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase())
+        .map_or(false, |extension| EXTENSIONS.contains(&extension.as_str()))
+}
+
+fn recent_file() -> Option<PathBuf> {
+    config::config_dir().map(|directory| directory.join("recent.txt"))
+}
+
+// Most recent first, one path per line
+pub fn load_recent() -> Vec<PathBuf> {
+    let text = match recent_file().and_then(|path| fs::read_to_string(path).ok()) {
+        Some(text) => text,
+        None => return Vec::new()
+    };
+    text.lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(PathBuf::from)
+        .filter(|path| path.is_file())
+        .take(RECENT_LIMIT)
+        .collect()
+}
+
+pub fn add_recent(path: &Path) -> Result<(), String> {
+    let file = recent_file().ok_or_else(|| "no config directory".to_string())?;
+    let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    let mut recent = load_recent();
+    recent.retain(|other| other.canonicalize().map_or(true, |other| other != path));
+    recent.insert(0, path);
+    recent.truncate(RECENT_LIMIT);
+
+    if let Some(directory) = file.parent() {
+        fs::create_dir_all(directory).map_err(|error| error.to_string())?;
+    }
+    let text: String = recent.iter()
+        .map(|path| format!("{}\n", path.display()))
+        .collect();
+    fs::write(&file, text).map_err(|error| format!("{}: {}", file.display(), error))
+}
@@ -0,0 +1,299 @@
+use std::path::{Path, PathBuf};
+
+use super::{INITIAL_PC, RAM_SIZE};
+
+/*
+ * Applies ROM patches, the way fixes and translations of old games are
+ * shared. Two formats are understood:
+ *
+ *   - IPS: "PATCH", then records of a 3 byte offset, a 2 byte length and the
+ *     bytes to write there (a zero length means a run of one repeated byte),
+ *     up to "EOF". IPS has no checksums, a patch for another ROM goes unnoticed.
+ *   - BPS: "BPS1", the sizes, then commands copying from the source, the patch
+ *     or the target built so far. It ends with CRC32s of the source, target
+ *     and patch, which are all checked.
+ *
+ * A patch is picked up when it sits next to the ROM with the same name, e.g.
+ * PONG.ips or PONG.bps for PONG.ch8.
+ */
+
+pub const EXTENSIONS: [&str; 2] = ["ips", "bps"];
+
+// The usual CRC-32 (IEEE), as zip and BPS use it
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+// Patch files next to the ROM, in the order they are applied
+pub fn find_patches(rom_path: &Path) -> Vec<PathBuf> {
+    EXTENSIONS.iter()
+        .map(|extension| rom_path.with_extension(extension))
+        .filter(|path| path.is_file())
+        .collect()
+}
+
+// Patches a copy of the ROM, telling the formats apart by their header
+pub fn apply(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    if patch.starts_with(b"PATCH") {
+        apply_ips(rom, patch)
+    } else if patch.starts_with(b"BPS1") {
+        apply_bps(rom, patch)
+    } else {
+        Err("not an IPS or BPS patch".to_string())
+    }
+}
+
+// Reads patch bytes, failing on a truncated patch instead of panicking
+struct Reader<'a> {
+    data: &'a [u8],
+    offset: usize
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, length: usize) -> Result<&'a [u8], String> {
+        if self.offset + length > self.data.len() {
+            return Err(format!("patch ends early at byte {}", self.data.len()));
+        }
+        let bytes = &self.data[self.offset..self.offset + length];
+        self.offset += length;
+        Ok(bytes)
+    }
+
+    // Big-endian, as IPS stores its numbers
+    fn number(&mut self, length: usize) -> Result<usize, String> {
+        Ok(self.bytes(length)?.iter().fold(0, |number, byte| number << 8 | *byte as usize))
+    }
+
+    // BPS numbers: 7 bits a byte, the top bit marks the last one, and each
+    // further byte also adds one more than the previous ones could hold
+    fn varint(&mut self) -> Result<usize, String> {
+        let mut number = 0usize;
+        let mut shift = 1usize;
+        loop {
+            let byte = self.bytes(1)?[0];
+            number = number.checked_add((byte & 0x7F) as usize * shift).ok_or("number too large in patch")?;
+            if byte & 0x80 != 0 {
+                return Ok(number);
+            }
+            shift = shift.checked_mul(128).ok_or("number too large in patch")?;
+            number = number.checked_add(shift).ok_or("number too large in patch")?;
+        }
+    }
+}
+
+pub fn apply_ips(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    let mut reader = Reader { data: patch, offset: 5 };
+    let mut target = rom.to_vec();
+    loop {
+        let offset = reader.number(3)?;
+        if offset == 0x454F46 {
+            break; // "EOF"
+        }
+        let length = reader.number(2)?;
+        let (length, run) = if length == 0 {
+            (reader.number(2)?, Some(reader.bytes(1)?[0]))
+        } else {
+            (length, None)
+        };
+        // the ROM can only fill memory from 0x200 up, so the copy is never grown past that
+        if offset + length > RAM_SIZE - INITIAL_PC as usize {
+            return Err(format!("patch writes past the end of memory, up to byte {:#x} of the ROM", offset + length));
+        }
+        if target.len() < offset + length {
+            target.resize(offset + length, 0);
+        }
+        match run {
+            Some(byte) => target[offset..offset + length].iter_mut().for_each(|slot| *slot = byte),
+            None => target[offset..offset + length].copy_from_slice(reader.bytes(length)?)
+        }
+    }
+    // an extension some tools write, cutting the ROM to a new length
+    if patch.len() - reader.offset >= 3 {
+        let length = reader.number(3)?;
+        target.truncate(length);
+    }
+    Ok(target)
+}
+
+pub fn apply_bps(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    if patch.len() < 4 + 12 {
+        return Err("patch is too short to be BPS".to_string());
+    }
+    let footer = patch.len() - 12;
+    let checksum = |offset: usize| {
+        let bytes = &patch[offset..offset + 4];
+        u32::from(bytes[0]) | u32::from(bytes[1]) << 8 | u32::from(bytes[2]) << 16 | u32::from(bytes[3]) << 24
+    };
+    if crc32(&patch[..footer + 8]) != checksum(footer + 8) {
+        return Err("patch is damaged, its checksum does not match".to_string());
+    }
+    if crc32(rom) != checksum(footer) {
+        return Err(format!("patch is for a different ROM (CRC32 {:08x}, this one is {:08x})", checksum(footer), crc32(rom)));
+    }
+
+    let mut reader = Reader { data: &patch[..footer], offset: 4 };
+    let source_size = reader.varint()?;
+    let target_size = reader.varint()?;
+    let metadata_size = reader.varint()?;
+    reader.bytes(metadata_size)?;
+    if source_size != rom.len() {
+        return Err(format!("patch is for a {} byte ROM, this one is {} bytes", source_size, rom.len()));
+    }
+
+    let mut target = Vec::with_capacity(target_size);
+    let (mut source_offset, mut target_offset) = (0isize, 0isize);
+    let out_of_range = || "patch copies from outside the ROM".to_string();
+    while reader.offset < footer {
+        let command = reader.varint()?;
+        let length = (command >> 2) + 1;
+        if target.len() + length > target_size {
+            return Err("patch writes past the size it gives".to_string());
+        }
+        match command & 3 {
+            // the same bytes as the source at this position
+            0 => {
+                let start = target.len();
+                let bytes = rom.get(start..start + length).ok_or_else(out_of_range)?;
+                target.extend_from_slice(bytes);
+            }
+            // new bytes from the patch
+            1 => target.extend_from_slice(reader.bytes(length)?),
+            // copies from elsewhere in the source or, byte by byte so runs can
+            // repeat, from earlier in the target
+            mode => {
+                let offset = reader.varint()?;
+                let delta = if offset & 1 != 0 { -((offset >> 1) as isize) } else { (offset >> 1) as isize };
+                if mode == 2 {
+                    source_offset += delta;
+                    let start = source_offset as usize;
+                    let bytes = if source_offset < 0 { None } else { rom.get(start..start + length) };
+                    target.extend_from_slice(bytes.ok_or_else(out_of_range)?);
+                    source_offset += length as isize;
+                } else {
+                    target_offset += delta;
+                    if target_offset < 0 || target_offset as usize >= target.len() {
+                        return Err("patch copies from outside the target".to_string());
+                    }
+                    for _ in 0..length {
+                        let byte = target[target_offset as usize];
+                        target.push(byte);
+                        target_offset += 1;
+                    }
+                }
+            }
+        }
+    }
+    if target.len() != target_size {
+        return Err(format!("patch gives {} bytes, expected {}", target.len(), target_size));
+    }
+    if crc32(&target) != checksum(footer + 4) {
+        return Err("patched ROM does not match the patch's checksum".to_string());
+    }
+    Ok(target)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A BPS number, the reverse of Reader::varint
+    fn varint(mut number: usize, patch: &mut Vec<u8>) {
+        loop {
+            let byte = (number & 0x7F) as u8;
+            number >>= 7;
+            if number == 0 {
+                patch.push(byte | 0x80);
+                return;
+            }
+            patch.push(byte);
+            number -= 1;
+        }
+    }
+
+    fn push_crc32(data: &[u8], patch: &mut Vec<u8>) {
+        let crc = crc32(data);
+        patch.extend_from_slice(&[crc as u8, (crc >> 8) as u8, (crc >> 16) as u8, (crc >> 24) as u8]);
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn varint_limits() {
+        let mut largest = Vec::new();
+        varint(usize::MAX, &mut largest);
+        assert_eq!(Reader { data: &largest, offset: 0 }.varint(), Ok(usize::MAX));
+        // nine bytes without an end already go past 64 bits
+        let oversized = [0x7F; 9];
+        assert_eq!(Reader { data: &oversized, offset: 0 }.varint(), Err("number too large in patch".to_string()));
+        let unended = [0x00; 16];
+        assert!(Reader { data: &unended, offset: 0 }.varint().is_err());
+    }
+
+    #[test]
+    fn ips_records_runs_and_truncation() {
+        let mut patch = b"PATCH".to_vec();
+        patch.extend_from_slice(&[0x00, 0x00, 0x01, 0x00, 0x02, 0xAA, 0xBB]); // 2 bytes at 1
+        patch.extend_from_slice(&[0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x03, 0xCC]); // 3 times CC at 4
+        patch.extend_from_slice(b"EOF");
+        assert_eq!(apply(&[0; 8], &patch).unwrap(), vec![0, 0xAA, 0xBB, 0, 0xCC, 0xCC, 0xCC, 0]);
+        patch.extend_from_slice(&[0x00, 0x00, 0x06]); // cut to 6 bytes
+        assert_eq!(apply(&[0; 8], &patch).unwrap(), vec![0, 0xAA, 0xBB, 0, 0xCC, 0xCC]);
+        // a record may grow the ROM
+        assert_eq!(apply(&[0; 2], &patch).unwrap(), vec![0, 0xAA, 0xBB, 0, 0xCC, 0xCC]);
+        assert!(apply(&[0; 8], b"PATCH\x00\x00\x01\x00\x02\xAA").is_err());
+    }
+
+    #[test]
+    fn ips_past_memory() {
+        let rom_space = RAM_SIZE - INITIAL_PC as usize;
+        let record = |offset: usize, length: usize| {
+            let mut patch = b"PATCH".to_vec();
+            patch.extend_from_slice(&[(offset >> 16) as u8, (offset >> 8) as u8, offset as u8, 0x00, 0x00]);
+            patch.extend_from_slice(&[(length >> 8) as u8, length as u8, 0xEE]);
+            patch.extend_from_slice(b"EOF");
+            patch
+        };
+        assert_eq!(apply(&[], &record(rom_space - 2, 2)).unwrap().len(), rom_space);
+        assert!(apply(&[], &record(rom_space - 1, 2)).is_err());
+        assert!(apply(&[], &record(0xFF_FFFE, 0xFFFF)).is_err());
+    }
+
+    #[test]
+    fn bps_commands_and_checksums() {
+        let source = b"ABCDEFGH";
+        let target = b"ABxyzGHxyzG";
+        let mut patch = b"BPS1".to_vec();
+        varint(source.len(), &mut patch);
+        varint(target.len(), &mut patch);
+        varint(0, &mut patch); // no metadata
+        varint((2 - 1) << 2, &mut patch); // source read: AB
+        varint((3 - 1) << 2 | 1, &mut patch); // target read: xyz
+        patch.extend_from_slice(b"xyz");
+        varint((2 - 1) << 2 | 2, &mut patch); // source copy from 6: GH
+        varint(6 << 1, &mut patch);
+        varint((4 - 1) << 2 | 3, &mut patch); // target copy from 2: xyzG, reading what it writes
+        varint(2 << 1, &mut patch);
+        push_crc32(source, &mut patch);
+        push_crc32(target, &mut patch);
+        let whole = patch.clone();
+        push_crc32(&whole, &mut patch);
+        assert_eq!(apply(source, &patch).unwrap(), target.to_vec());
+
+        let error = apply(b"ABCDEFGX", &patch).unwrap_err();
+        assert!(error.contains("different ROM"), "{}", error);
+        let mut damaged = patch.clone();
+        damaged[10] ^= 1;
+        assert!(apply(source, &damaged).unwrap_err().contains("damaged"));
+    }
+}
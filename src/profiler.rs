@@ -0,0 +1,353 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+
+use super::cpu::Cpu;
+use super::instruction::Instruction;
+
+/*
+ * Counts what a ROM spends its time on, for fitting a game into the cycle
+ * budget of a slower interpreter. Before every cycle the instruction at PC is
+ * counted by address and by family (the pattern in the opcode table, e.g.
+ * DXYN), and 2NNN/00EE pairs are followed to give every subroutine an
+ * inclusive count: its own instructions plus those of everything it calls.
+ *
+ * A frame counts as idle when the ROM only waited in it: it waited for a key
+ * with FX0A, or ran nothing but timer reads, key checks, skips and jumps, the
+ * stuff of delay loops. The work in a frame is the instructions up to the
+ * last one that is not waiting, leaving out the loop the frame ended in.
+ */
+
+// Deeper call chains than this are not followed, e.g. runaway recursion
+const CALL_DEPTH_LIMIT: usize = 64;
+
+// What delay and key loops are made of
+fn is_waiting(family: &str) -> bool {
+    match family {
+        "FX07" | "FX0A" | "EX9E" | "EXA1" | "3XKK" | "4XKK" | "5XY0" | "9XY0" | "1NNN" => true,
+        _ => false
+    }
+}
+
+#[derive(Default)]
+struct Subroutine {
+    calls: u64,
+    inclusive: u64, // instructions from the call up to and including its RET
+    own: u64, // instructions run in it, not in what it calls
+    callers: BTreeMap<u16, u64>, // call site addresses
+    callees: BTreeMap<u16, u64>
+}
+
+pub struct Profiler {
+    cycles_per_frame: u32,
+    address_counts: Vec<u64>,
+    opcodes: HashMap<u16, u16>, // the last opcode run at each address, for the disassembly
+    families: BTreeMap<&'static str, u64>,
+    subroutines: BTreeMap<u16, Subroutine>,
+    calls: Vec<(u16, u64)>, // open calls: subroutine and the instruction count at the call
+    main_own: u64, // instructions outside any subroutine
+    instructions: u64,
+    frames: u64,
+    idle_frames: u64,
+    frame_instructions: u64,
+    frame_work: u64, // instructions up to the last one that was not waiting
+    min_frame: u64,
+    max_frame: u64,
+    total_work: u64,
+    max_work: u64,
+    busiest_frame: u64
+}
+
+pub struct Report {
+    pub text: String,
+    pub json: String
+}
+
+fn percent(part: u64, whole: u64) -> f64 {
+    if whole == 0 { 0.0 } else { part as f64 * 100.0 / whole as f64 }
+}
+
+// A JSON string literal, also used by chip8-bench
+pub fn json_string(text: &str) -> String {
+    let mut escaped = String::from("\"");
+    for character in text.chars() {
+        match character {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            character if (character as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", character as u32)),
+            character => escaped.push(character)
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+fn json_counts(counts: &BTreeMap<u16, u64>) -> String {
+    let entries: Vec<String> = counts.iter()
+        .map(|(address, count)| format!("{{\"address\": {}, \"count\": {}}}", address, count))
+        .collect();
+    format!("[{}]", entries.join(", "))
+}
+
+impl Profiler {
+    pub fn new(cycles_per_frame: u32) -> Profiler {
+        Profiler {
+            cycles_per_frame: cycles_per_frame,
+            address_counts: vec![0; super::RAM_SIZE],
+            opcodes: HashMap::new(),
+            families: BTreeMap::new(),
+            subroutines: BTreeMap::new(),
+            calls: Vec::new(),
+            main_own: 0,
+            instructions: 0,
+            frames: 0,
+            idle_frames: 0,
+            frame_instructions: 0,
+            frame_work: 0,
+            min_frame: u64::max_value(),
+            max_frame: 0,
+            total_work: 0,
+            max_work: 0,
+            busiest_frame: 0
+        }
+    }
+
+    // Called before every emulate_cycle with the instruction about to run
+    pub fn record(&mut self, cpu: &Cpu) {
+        if cpu.is_waiting_for_key() {
+            return;
+        }
+        let memory = cpu.get_memory();
+        let pc = cpu.get_pc() as usize % super::RAM_SIZE;
+        let opcode = (memory[pc] as u16) << 8 | memory[(pc + 1) % super::RAM_SIZE] as u16;
+        let instruction = Instruction::decode(opcode);
+        let family = instruction.get_pattern();
+        self.address_counts[pc] += 1;
+        self.opcodes.insert(pc as u16, opcode);
+        *self.families.entry(family).or_insert(0) += 1;
+        self.instructions += 1;
+        self.frame_instructions += 1;
+        if !is_waiting(family) {
+            self.frame_work = self.frame_instructions;
+        }
+
+        match self.calls.last() {
+            Some((current, _)) => self.subroutines.entry(*current).or_default().own += 1,
+            None => self.main_own += 1
+        }
+        if let (Instruction::Call(target), true) = (instruction, self.calls.len() < CALL_DEPTH_LIMIT) {
+            if let Some((current, _)) = self.calls.last() {
+                *self.subroutines.entry(*current).or_default().callees.entry(target).or_insert(0) += 1;
+            }
+            let subroutine = self.subroutines.entry(target).or_default();
+            subroutine.calls += 1;
+            *subroutine.callers.entry(pc as u16).or_insert(0) += 1;
+            // the CALL itself counts towards the subroutine
+            self.calls.push((target, self.instructions - 1));
+        } else if instruction == Instruction::Ret {
+            if let Some((target, start)) = self.calls.pop() {
+                self.subroutines.entry(target).or_default().inclusive += self.instructions - start;
+            }
+        }
+    }
+
+    pub fn end_frame(&mut self) {
+        self.frames += 1;
+        if self.frame_work == 0 {
+            self.idle_frames += 1;
+        }
+        self.min_frame = self.min_frame.min(self.frame_instructions);
+        self.max_frame = self.max_frame.max(self.frame_instructions);
+        self.total_work += self.frame_work;
+        if self.frame_work > self.max_work {
+            self.max_work = self.frame_work;
+            self.busiest_frame = self.frames;
+        }
+        self.frame_instructions = 0;
+        self.frame_work = 0;
+    }
+
+    // Subroutines with the calls still open at the end counted up to now
+    fn closed_subroutines(&self) -> Vec<(u16, u64, &Subroutine)> {
+        let mut open: HashMap<u16, u64> = HashMap::new();
+        for (target, start) in self.calls.iter() {
+            *open.entry(*target).or_insert(0) += self.instructions - start;
+        }
+        let mut subroutines: Vec<(u16, u64, &Subroutine)> = self.subroutines.iter()
+            .map(|(address, subroutine)| (*address, subroutine.inclusive + open.get(address).cloned().unwrap_or(0), subroutine))
+            .collect();
+        subroutines.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        subroutines
+    }
+
+    // The `top` most run addresses, with what ran there
+    fn hot_addresses(&self, top: usize) -> Vec<(u16, u64, u16)> {
+        let mut addresses: Vec<(u16, u64, u16)> = self.address_counts.iter().enumerate()
+            .filter(|(_, count)| **count > 0)
+            .map(|(address, count)| (address as u16, *count, self.opcodes[&(address as u16)]))
+            .collect();
+        addresses.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        addresses.truncate(top);
+        addresses
+    }
+
+    pub fn report(&self, top: usize) -> Report {
+        let frames = self.frames.max(1);
+        let min_frame = if self.frames == 0 { 0 } else { self.min_frame };
+        let average = self.instructions as f64 / frames as f64;
+        let average_work = self.total_work as f64 / frames as f64;
+        let hot = self.hot_addresses(top);
+        let mut families: Vec<(&str, u64)> = self.families.iter().map(|(family, count)| (*family, *count)).collect();
+        families.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        let subroutines = self.closed_subroutines();
+
+        let mut text = String::new();
+        let _ = writeln!(text, "{} instructions in {} frames, {} cycles a frame", self.instructions, self.frames, self.cycles_per_frame);
+        let _ = writeln!(text, "  instructions per frame: {} min, {:.1} average, {} max", min_frame, average, self.max_frame);
+        let _ = writeln!(text, "  work per frame: {:.1} average, {} max (frame {})", average_work, self.max_work, self.busiest_frame);
+        let _ = writeln!(text, "  idle frames: {} ({:.1}%)", self.idle_frames, percent(self.idle_frames, self.frames));
+
+        let _ = writeln!(text, "\nHot addresses:");
+        for (address, count, opcode) in hot.iter() {
+            let _ = writeln!(text, "  {:#05x}  {:04x}  {:<16} {:>10} {:5.1}%",
+                             address, opcode, Instruction::decode(*opcode).to_string(), count, percent(*count, self.instructions));
+        }
+
+        let _ = writeln!(text, "\nOpcodes:");
+        for (family, count) in families.iter() {
+            let _ = writeln!(text, "  {:<8} {:>10} {:5.1}%", family, count, percent(*count, self.instructions));
+        }
+
+        let _ = writeln!(text, "\nSubroutines (inclusive, own, calls):");
+        let _ = writeln!(text, "  main     {:>10} {:>10}", self.instructions, self.main_own);
+        for (address, inclusive, subroutine) in subroutines.iter() {
+            let _ = writeln!(text, "  {:#05x}    {:>10} {:>10} {:>8} {:5.1}%",
+                             address, inclusive, subroutine.own, subroutine.calls, percent(*inclusive, self.instructions));
+            let callers: Vec<String> = subroutine.callers.iter()
+                .map(|(site, count)| format!("{:#05x} ({})", site, count))
+                .collect();
+            let _ = writeln!(text, "           called from {}", callers.join(", "));
+            if !subroutine.callees.is_empty() {
+                let callees: Vec<String> = subroutine.callees.iter()
+                    .map(|(target, count)| format!("{:#05x} ({})", target, count))
+                    .collect();
+                let _ = writeln!(text, "           calls {}", callees.join(", "));
+            }
+        }
+
+        let hot_json: Vec<String> = hot.iter()
+            .map(|(address, count, opcode)| {
+                format!("{{\"address\": {}, \"count\": {}, \"opcode\": {}, \"disassembly\": {}}}",
+                        address, count, opcode, json_string(&Instruction::decode(*opcode).to_string()))
+            })
+            .collect();
+        let families_json: Vec<String> = families.iter()
+            .map(|(family, count)| format!("{{\"family\": {}, \"count\": {}}}", json_string(family), count))
+            .collect();
+        let subroutines_json: Vec<String> = subroutines.iter()
+            .map(|(address, inclusive, subroutine)| {
+                format!("{{\"address\": {}, \"calls\": {}, \"inclusive\": {}, \"own\": {}, \"callers\": {}, \"callees\": {}}}",
+                        address, subroutine.calls, inclusive, subroutine.own,
+                        json_counts(&subroutine.callers), json_counts(&subroutine.callees))
+            })
+            .collect();
+        let json = format!("{{\n  \"instructions\": {},\n  \"frames\": {},\n  \"cycles_per_frame\": {},\n  \
+                            \"instructions_per_frame\": {{\"min\": {}, \"average\": {:.3}, \"max\": {}}},\n  \
+                            \"work_per_frame\": {{\"average\": {:.3}, \"max\": {}, \"busiest_frame\": {}}},\n  \
+                            \"idle_frames\": {},\n  \"main_own\": {},\n  \"hot_addresses\": [\n    {}\n  ],\n  \
+                            \"opcodes\": [\n    {}\n  ],\n  \"subroutines\": [\n    {}\n  ]\n}}\n",
+                           self.instructions, self.frames, self.cycles_per_frame,
+                           min_frame, average, self.max_frame,
+                           average_work, self.max_work, self.busiest_frame,
+                           self.idle_frames, self.main_own, hot_json.join(",\n    "),
+                           families_json.join(",\n    "), subroutines_json.join(",\n    "));
+        Report {
+            text: text,
+            json: json
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Runs the program frame by frame, `cycles` instructions a frame
+    fn profiled(program: &[u16], cycles: u32, frames: u32) -> Profiler {
+        let rom: Vec<u8> = program.iter().flat_map(|opcode| vec![(opcode >> 8) as u8, *opcode as u8]).collect();
+        let mut cpu = Cpu::new();
+        cpu.load_rom_at(&rom, super::super::INITIAL_PC).unwrap();
+        let mut profiler = Profiler::new(cycles);
+        for _ in 0..frames {
+            for _ in 0..cycles {
+                profiler.record(&cpu);
+                cpu.emulate_cycle().unwrap();
+            }
+            profiler.end_frame();
+        }
+        profiler
+    }
+
+    #[test]
+    fn hot_addresses() {
+        // V0 = 1, then adds to it forever
+        let profiler = profiled(&[0x6001, 0x7001, 0x1202], 9, 1);
+        assert_eq!(profiler.hot_addresses(2), vec![(0x202, 4, 0x7001), (0x204, 4, 0x1202)]);
+        assert_eq!(profiler.hot_addresses(10).len(), 3);
+        assert_eq!(profiler.families["7XKK"], 4);
+        let report = profiler.report(1);
+        assert!(report.text.contains("0x202  7001  ADD V0, 0x01"), "{}", report.text);
+        assert!(report.json.contains("\"hot_addresses\": [\n    {\"address\": 514, \"count\": 4, \"opcode\": 28673"), "{}", report.json);
+    }
+
+    #[test]
+    fn call_tree() {
+        let program = [
+            0x2206, // 0x200: call A
+            0x2206, // 0x202: call A again
+            0x1204, // 0x204: loop
+            0x220C, // 0x206: A calls B
+            0x6001,
+            0x00EE,
+            0x00EE  // 0x20c: B
+        ];
+        // both calls take five instructions, then two rounds of the loop
+        let profiler = profiled(&program, 12, 1);
+        let subroutines = profiler.closed_subroutines();
+        let summary: Vec<(u16, u64, u64, u64)> = subroutines.iter()
+            .map(|(address, inclusive, subroutine)| (*address, *inclusive, subroutine.own, subroutine.calls))
+            .collect();
+        assert_eq!(summary, vec![(0x206, 10, 6, 2), (0x20C, 4, 2, 2)]);
+        assert_eq!(profiler.main_own, 4);
+        let a = &profiler.subroutines[&0x206];
+        assert_eq!(a.callers.iter().collect::<Vec<_>>(), vec![(&0x200, &1), (&0x202, &1)]);
+        assert_eq!(a.callees.iter().collect::<Vec<_>>(), vec![(&0x20C, &2)]);
+        assert_eq!(profiler.subroutines[&0x20C].callers.iter().collect::<Vec<_>>(), vec![(&0x206, &2)]);
+
+        // a call still open at the end counts up to then
+        let profiler = profiled(&program, 3, 1);
+        assert_eq!(profiler.closed_subroutines()[0].1, 3);
+    }
+
+    #[test]
+    fn idle_frames() {
+        let program = [
+            0x6005, // V0 = 5
+            0xF015, // DT = V0
+            0xF107, // 0x204: V1 = DT
+            0x3100, // skip while the timer runs
+            0x1204,
+            0x120A  // done
+        ];
+        // the first frame does work, the others only wait for the timer,
+        // which never runs down here
+        let profiler = profiled(&program, 4, 3);
+        assert_eq!((profiler.frames, profiler.idle_frames), (3, 2));
+        assert_eq!((profiler.total_work, profiler.max_work, profiler.busiest_frame), (2, 2, 1));
+        assert!(profiler.report(5).text.contains("idle frames: 2 (66.7%)"));
+
+        // waiting for a key runs nothing at all
+        let profiler = profiled(&[0xF00A], 2, 2);
+        assert_eq!((profiler.instructions, profiler.idle_frames), (1, 2));
+        assert_eq!(profiler.min_frame, 0);
+    }
+}
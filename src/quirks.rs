@@ -0,0 +1,89 @@
+/*
+ * Behaviours that differ between CHIP-8 interpreters. Games are usually
+ * written against one of them and misbehave on the others, so the cpu
+ * follows whichever preset the game needs.
+ */
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Quirks {
+    // 8XY6/8XYE shift VY and store the result in VX, instead of shifting VX
+    pub shift_uses_vy: bool,
+    // FX55/FX65 leave I pointing just past the last register
+    pub load_store_increments_i: bool,
+    // BNNN jumps to XNN + VX rather than NNN + V0
+    pub jump_uses_vx: bool,
+    // 8XY1/8XY2/8XY3 reset VF to 0
+    pub logic_resets_vf: bool,
+    // Sprites are cut off at the edge of the screen instead of wrapping around
+    pub clip_sprites: bool
+}
+
+impl Quirks {
+    // What this emulator has always done, and what most modern games expect
+    pub fn new() -> Quirks {
+        Quirks {
+            shift_uses_vy: false,
+            load_store_increments_i: false,
+            jump_uses_vx: false,
+            logic_resets_vf: false,
+            clip_sprites: false
+        }
+    }
+
+    pub fn from_preset(name: &str) -> Option<Quirks> {
+        // lowercased in place, no_std builds have nothing to allocate with
+        let mut lowercase = [0u8; 8];
+        let lowercase = lowercase.get_mut(..name.len())?;
+        lowercase.copy_from_slice(name.as_bytes());
+        lowercase.make_ascii_lowercase();
+        match &*lowercase {
+            b"modern" => Some(Quirks::new()),
+            // The original COSMAC VIP interpreter
+            b"vip" | b"chip8" => Some(Quirks {
+                shift_uses_vy: true,
+                load_store_increments_i: true,
+                jump_uses_vx: false,
+                logic_resets_vf: true,
+                clip_sprites: true
+            }),
+            // SUPER-CHIP 1.1 on the HP48
+            b"schip" => Some(Quirks {
+                shift_uses_vy: false,
+                load_store_increments_i: false,
+                jump_uses_vx: true,
+                logic_resets_vf: false,
+                clip_sprites: true
+            }),
+            b"xochip" => Some(Quirks {
+                shift_uses_vy: true,
+                load_store_increments_i: true,
+                jump_uses_vx: false,
+                logic_resets_vf: false,
+                clip_sprites: false
+            }),
+            _ => None
+        }
+    }
+
+    // One bit per quirk in the order above, for save states
+    pub fn to_bits(&self) -> u8 {
+        [self.shift_uses_vy, self.load_store_increments_i, self.jump_uses_vx, self.logic_resets_vf, self.clip_sprites]
+            .iter()
+            .enumerate()
+            .fold(0, |bits, (bit, &set)| bits | (set as u8) << bit)
+    }
+
+    pub fn from_bits(bits: u8) -> Quirks {
+        Quirks {
+            shift_uses_vy: bits & 1 != 0,
+            load_store_increments_i: bits & 2 != 0,
+            jump_uses_vx: bits & 4 != 0,
+            logic_resets_vf: bits & 8 != 0,
+            clip_sprites: bits & 16 != 0
+        }
+    }
+
+    pub fn preset_names() -> &'static [&'static str] {
+        &["modern", "vip", "schip", "xochip"]
+    }
+}
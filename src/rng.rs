@@ -0,0 +1,64 @@
+/*
+ * The random number generator behind CXKK. It is the xorshift generator rand
+ * 0.3 had as XorShiftRng, written out so the core needs no crates and no
+ * operating system: a seed gives the same numbers as it always did.
+ */
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct XorShift {
+    x: u32,
+    y: u32,
+    z: u32,
+    w: u32
+}
+
+impl XorShift {
+    // xorshift needs a seed that is not all zeroes, hence the constants
+    pub fn from_seed(seed: u64) -> XorShift {
+        XorShift {
+            x: seed as u32,
+            y: (seed >> 32) as u32,
+            z: 0x9E37_79B9,
+            w: 0x7F4A_7C15
+        }
+    }
+
+    // Seeded from the hash keys std picks at random for each process
+    #[cfg(feature = "std")]
+    pub fn from_entropy() -> XorShift {
+        use std::collections::hash_map::RandomState;
+        use std::hash::{BuildHasher, Hasher};
+        XorShift::from_seed(RandomState::new().build_hasher().finish())
+    }
+
+    // For save states
+    pub fn get_state(&self) -> [u32; 4] {
+        [self.x, self.y, self.z, self.w]
+    }
+
+    // None for the all zero state, which would only ever give zeroes
+    pub fn from_state(state: [u32; 4]) -> Option<XorShift> {
+        if state == [0; 4] {
+            return None;
+        }
+        Some(XorShift {
+            x: state[0],
+            y: state[1],
+            z: state[2],
+            w: state[3]
+        })
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        let t = self.x ^ (self.x << 11);
+        self.x = self.y;
+        self.y = self.z;
+        self.z = self.w;
+        self.w = self.w ^ (self.w >> 19) ^ (t ^ (t >> 8));
+        self.w
+    }
+
+    pub fn next_u8(&mut self) -> u8 {
+        self.next_u32() as u8
+    }
+}
@@ -1,33 +1,52 @@
+extern crate sha1;
+
 use std::fs;
+use std::path::Path;
+
+use super::analyzer::{self, Analysis};
+use super::patch;
 
 pub struct RomLoader {
     data: [u8; super::ROM_SIZE],
     file_name: String,
-    length: usize
+    length: usize,
+    hash: String // SHA-1 of the ROM file before patches, used to look up per-ROM settings
 }
 
 impl RomLoader {
-    pub fn new(file_name: String) -> RomLoader{
-        let mut data = [0u8; super::ROM_SIZE];
-        let mut length = 0usize;
-        match fs::read(&file_name) {
-            Ok(bytes) => {
-                // println!("loading in file name..."); // DEBUG
-                for (i, byte) in bytes.iter().enumerate() {
-                    // println!("byte: {}", byte); // DEBUG
-                    data[i] = *byte;
-                    length += 1;
-                }
-            }
-            Err(error) => {
-                panic!("There was something wrong!\n{}", error);
-            }
+    pub fn new(file_name: String) -> Result<RomLoader, String> {
+        let bytes = fs::read(&file_name)
+            .map_err(|error| format!("could not read {}: {}", file_name, error))?;
+        if bytes.len() > super::ROM_SIZE {
+            return Err(format!("{} is {} bytes, ROMs can be at most {} bytes", file_name, bytes.len(), super::ROM_SIZE));
         }
-        RomLoader {
+        let mut data = [0u8; super::ROM_SIZE];
+        data[..bytes.len()].copy_from_slice(&bytes);
+        let length = bytes.len();
+        let hash = sha1::Sha1::from(&data[..length]).digest().to_string();
+        Ok(RomLoader {
             data: data,
             file_name: file_name,
-            length: length
+            length: length,
+            hash: hash
+        })
+    }
+
+    // Patches the loaded bytes. The hash stays that of the file, so a fixed or
+    // translated ROM keeps its settings and database entry.
+    pub fn apply_patch(&mut self, patch_path: &Path) -> Result<(), String> {
+        let patch_name = patch_path.display();
+        let bytes = fs::read(patch_path)
+            .map_err(|error| format!("could not read {}: {}", patch_name, error))?;
+        let patched = patch::apply(&self.data[..self.length], &bytes)
+            .map_err(|error| format!("{}: {}", patch_name, error))?;
+        if patched.len() > super::ROM_SIZE {
+            return Err(format!("{} makes the ROM {} bytes, ROMs can be at most {} bytes", patch_name, patched.len(), super::ROM_SIZE));
         }
+        self.data = [0u8; super::ROM_SIZE];
+        self.data[..patched.len()].copy_from_slice(&patched);
+        self.length = patched.len();
+        Ok(())
     }
 
     pub fn get_data(&self) -> [u8; super::ROM_SIZE] {
@@ -37,4 +56,26 @@ impl RomLoader {
     pub fn get_length(&self) -> usize {
         self.length
     }
-}
\ No newline at end of file
+
+    pub fn get_hash(&self) -> &str {
+        &self.hash
+    }
+
+    // What static analysis makes of the ROM loaded at load_address, for when
+    // the ROM database does not know it
+    pub fn analyze(&self, load_address: u16) -> Analysis {
+        analyzer::analyze(&self.data[..self.length], load_address)
+    }
+
+    pub fn get_file_name(&self) -> &str {
+        &self.file_name
+    }
+
+    // The file name without directories or extension, e.g. "PONG" for roms/PONG.ch8
+    pub fn get_stem(&self) -> String {
+        Path::new(&self.file_name)
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    }
+}
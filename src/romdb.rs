@@ -0,0 +1,198 @@
+extern crate serde;
+extern crate toml;
+
+use serde::Deserialize;
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+
+use super::config::{self, Settings};
+use super::keys::KeyBindings;
+use super::analyzer::Platform;
+
+// Shipped with the emulator, the user's romdb.toml is read on top of it
+const BUNDLED: &str = include_str!("../data/romdb.toml");
+
+/*
+ * What is known about particular ROMs, keyed by the SHA-1 of the file:
+ *
+ *   [0123456789abcdef0123456789abcdef01234567]
+ *   title = "Some Game"
+ *   author = "Someone"
+ *   year = 1978
+ *   platform = "chip8"
+ *   quirks = "vip"
+ *   ips = 500
+ *   description = "Shoot the aliens before they land"
+ *
+ *   [0123456789abcdef0123456789abcdef01234567.keys]
+ *   "4" = "left"
+ *   "6" = "right"
+ *   "5" = "fire"
+ *
+ * Every field is optional. An entry in the user's file replaces the bundled
+ * entry for the same ROM as a whole.
+ */
+
+#[derive(Deserialize, Default, Clone)]
+pub struct RomInfo {
+    pub title: Option<String>,
+    pub author: Option<String>,
+    pub year: Option<u32>,
+    pub platform: Option<String>,
+    pub quirks: Option<String>,
+    pub ips: Option<u32>,
+    pub description: Option<String>,
+    // CHIP-8 key, as a hex digit, to what it does in the game
+    #[serde(default)]
+    pub keys: BTreeMap<String, String>
+}
+
+impl RomInfo {
+    // The recommended quirks and speed as a settings layer
+    pub fn settings(&self) -> Settings {
+        Settings {
+            quirks: self.quirks.clone(),
+            ips: self.ips,
+            ..Settings::default()
+        }
+    }
+
+    pub fn get_platform(&self) -> Option<Platform> {
+        self.platform.as_ref().and_then(|name| Platform::from_name(name))
+    }
+}
+
+pub struct RomDatabase {
+    entries: HashMap<String, RomInfo>
+}
+
+impl RomDatabase {
+    // The bundled entries, then $XDG_CONFIG_HOME/rusty-chip8/romdb.toml.
+    // A broken user file is reported and ignored.
+    pub fn load() -> RomDatabase {
+        let mut entries: HashMap<String, RomInfo> = toml::from_str(BUNDLED)
+            .expect("the bundled ROM database is valid");
+        if let Some(path) = config::config_dir().map(|directory| directory.join("romdb.toml")) {
+            if path.is_file() {
+                let result = fs::read_to_string(&path)
+                    .map_err(|error| error.to_string())
+                    .and_then(|text| toml::from_str::<HashMap<String, RomInfo>>(&text).map_err(|error| error.to_string()));
+                match result {
+                    Ok(user_entries) => entries.extend(user_entries),
+                    Err(error) => eprintln!("Ignoring {}: {}", path.display(), error)
+                }
+            }
+        }
+        RomDatabase {
+            // hashes are compared in lower case, like RomLoader writes them
+            entries: entries.into_iter().map(|(hash, info)| (hash.to_lowercase(), info)).collect()
+        }
+    }
+
+    pub fn get(&self, hash: &str) -> Option<&RomInfo> {
+        self.entries.get(hash)
+    }
+}
+
+// The lines of the help overlay: what the database says about the game, then
+// which host keys press each CHIP-8 key
+pub fn help_lines(hash: &str, info: Option<&RomInfo>, bindings: &KeyBindings) -> Vec<String> {
+    let mut lines = Vec::new();
+    let info = match info {
+        Some(info) => info,
+        None => {
+            // the hash is what an entry in romdb.toml needs
+            lines.push(format!("sha1 {}", hash));
+            lines.push("no notes for this rom, the keys are:".to_string());
+            for row in [[0x1, 0x2, 0x3, 0xc], [0x4, 0x5, 0x6, 0xd], [0x7, 0x8, 0x9, 0xe], [0xa, 0x0, 0xb, 0xf]].iter() {
+                let keys: Vec<String> = row.iter()
+                    .map(|key| format!("{:x}={}", key, bindings.keypad_key_names(*key).join("/")))
+                    .collect();
+                lines.push(keys.join("  "));
+            }
+            return lines;
+        }
+    };
+
+    let mut heading = info.title.clone().unwrap_or_else(|| "untitled".to_string());
+    match (&info.author, info.year) {
+        (Some(author), Some(year)) => heading += &format!(" - {} {}", author, year),
+        (Some(author), None) => heading += &format!(" - {}", author),
+        (None, Some(year)) => heading += &format!(" - {}", year),
+        (None, None) => {}
+    }
+    lines.push(heading);
+    if let Some(description) = &info.description {
+        lines.push(description.clone());
+    }
+    if info.keys.is_empty() {
+        lines.push("no key descriptions for this rom".to_string());
+    }
+    for (key, action) in info.keys.iter() {
+        let names = u8::from_str_radix(key, 16).ok()
+            .filter(|key| (*key as usize) < super::KEYPAD_SIZE)
+            .map(|key| bindings.keypad_key_names(key).join("/"))
+            .unwrap_or_default();
+        lines.push(format!("{} ({}): {}", names, key, action));
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bundled_database() {
+        let entries: HashMap<String, RomInfo> = toml::from_str(BUNDLED).unwrap();
+        // Maze by David Winter, hashed the way RomLoader does
+        let maze = [
+            0xa2, 0x1e, 0xc2, 0x01, 0x32, 0x01, 0xa2, 0x1a, 0xd0, 0x14, 0x70, 0x04,
+            0x30, 0x40, 0x12, 0x00, 0x60, 0x00, 0x71, 0x04, 0x31, 0x20, 0x12, 0x00,
+            0x12, 0x18, 0x80, 0x40, 0x20, 0x10, 0x20, 0x40, 0x80, 0x10
+        ];
+        let hash = sha1::Sha1::from(&maze[..]).digest().to_string();
+        let info = entries.get(&hash).expect("Maze is in the bundled database");
+        assert_eq!(info.title.as_deref(), Some("Maze"));
+        assert_eq!(info.get_platform(), Some(Platform::Chip8));
+        assert_eq!(info.settings().quirks.as_deref(), Some("vip"));
+        for (hash, info) in entries.iter() {
+            assert!(hash.len() == 40 && hash.chars().all(|c| c.is_ascii_hexdigit()), "bad hash {}", hash);
+            assert!(info.get_platform().is_some(), "bad platform for {}", hash);
+        }
+    }
+
+    #[test]
+    fn keypad_demo() {
+        let entries: HashMap<String, RomInfo> = toml::from_str(BUNDLED).unwrap();
+        let hash = sha1::Sha1::from(&include_bytes!("../data/keypad.ch8")[..]).digest().to_string();
+        let info = entries.get(&hash).expect("the keypad demo is in the bundled database");
+        assert_eq!(info.keys.len(), 16);
+
+        let lines = help_lines(&hash, Some(info), &KeyBindings::new());
+        assert_eq!(lines[0], "Keypad Demo - rusty-chip8 2026");
+        assert_eq!(lines[1], "Shows the hex digit of the last key pressed, to check the bindings");
+        assert_eq!(lines.len(), 2 + 16);
+        assert!(lines.contains(&"W (5): show 5".to_string()));
+        assert!(lines.contains(&"Z (A): show A".to_string()));
+    }
+
+    #[test]
+    fn unknown_rom_help() {
+        let hash = "0123456789abcdef0123456789abcdef01234567";
+        let lines = help_lines(hash, None, &KeyBindings::new());
+        assert_eq!(lines, vec![
+            format!("sha1 {}", hash),
+            "no notes for this rom, the keys are:".to_string(),
+            "1=1  2=2  3=3  c=4".to_string(),
+            "4=Q  5=W  6=E  d=R".to_string(),
+            "7=A  8=S  9=D  e=F".to_string(),
+            "a=Z  0=X  b=C  f=V".to_string()
+        ]);
+
+        let untitled = RomInfo::default();
+        assert_eq!(help_lines(hash, Some(&untitled), &KeyBindings::new()),
+                   vec!["untitled".to_string(), "no key descriptions for this rom".to_string()]);
+    }
+}
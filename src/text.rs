@@ -0,0 +1,123 @@
+extern crate sdl2;
+
+use sdl2::pixels::Color;
+use sdl2::rect::Rect;
+use sdl2::render::Canvas;
+use sdl2::video::Window;
+
+/*
+ * A tiny 3x5 bitmap font for on-screen messages. Each glyph is five rows,
+ * the lowest three bits of a row are its pixels with bit 2 on the left.
+ * Lower case letters are drawn as upper case.
+ */
+
+pub const GLYPH_WIDTH: u32 = 3;
+pub const GLYPH_HEIGHT: u32 = 5;
+const SPACING: u32 = 1;
+
+pub fn glyph(character: char) -> [u8; 5] {
+    match character.to_ascii_uppercase() {
+        'A' => [0b010, 0b101, 0b111, 0b101, 0b101],
+        'B' => [0b110, 0b101, 0b110, 0b101, 0b110],
+        'C' => [0b011, 0b100, 0b100, 0b100, 0b011],
+        'D' => [0b110, 0b101, 0b101, 0b101, 0b110],
+        'E' => [0b111, 0b100, 0b110, 0b100, 0b111],
+        'F' => [0b111, 0b100, 0b110, 0b100, 0b100],
+        'G' => [0b011, 0b100, 0b101, 0b101, 0b011],
+        'H' => [0b101, 0b101, 0b111, 0b101, 0b101],
+        'I' => [0b111, 0b010, 0b010, 0b010, 0b111],
+        'J' => [0b001, 0b001, 0b001, 0b101, 0b010],
+        'K' => [0b101, 0b101, 0b110, 0b101, 0b101],
+        'L' => [0b100, 0b100, 0b100, 0b100, 0b111],
+        'M' => [0b101, 0b111, 0b111, 0b101, 0b101],
+        'N' => [0b110, 0b101, 0b101, 0b101, 0b101],
+        'O' => [0b010, 0b101, 0b101, 0b101, 0b010],
+        'P' => [0b110, 0b101, 0b110, 0b100, 0b100],
+        'Q' => [0b010, 0b101, 0b101, 0b110, 0b011],
+        'R' => [0b110, 0b101, 0b110, 0b101, 0b101],
+        'S' => [0b011, 0b100, 0b010, 0b001, 0b110],
+        'T' => [0b111, 0b010, 0b010, 0b010, 0b010],
+        'U' => [0b101, 0b101, 0b101, 0b101, 0b111],
+        'V' => [0b101, 0b101, 0b101, 0b101, 0b010],
+        'W' => [0b101, 0b101, 0b111, 0b111, 0b101],
+        'X' => [0b101, 0b101, 0b010, 0b101, 0b101],
+        'Y' => [0b101, 0b101, 0b010, 0b010, 0b010],
+        'Z' => [0b111, 0b001, 0b010, 0b100, 0b111],
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b110, 0b001, 0b010, 0b100, 0b111],
+        '3' => [0b110, 0b001, 0b010, 0b001, 0b110],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b110, 0b001, 0b110],
+        '6' => [0b011, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b010, 0b010, 0b010],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b110],
+        ' ' => [0b000, 0b000, 0b000, 0b000, 0b000],
+        '.' => [0b000, 0b000, 0b000, 0b000, 0b010],
+        ',' => [0b000, 0b000, 0b000, 0b010, 0b100],
+        ':' => [0b000, 0b010, 0b000, 0b010, 0b000],
+        '\'' => [0b010, 0b010, 0b000, 0b000, 0b000],
+        '-' => [0b000, 0b000, 0b111, 0b000, 0b000],
+        '+' => [0b000, 0b010, 0b111, 0b010, 0b000],
+        '=' => [0b000, 0b111, 0b000, 0b111, 0b000],
+        '_' => [0b000, 0b000, 0b000, 0b000, 0b111],
+        '*' => [0b000, 0b101, 0b010, 0b101, 0b000],
+        '#' => [0b101, 0b111, 0b101, 0b111, 0b101],
+        '/' => [0b001, 0b001, 0b010, 0b100, 0b100],
+        '%' => [0b101, 0b001, 0b010, 0b100, 0b101],
+        '<' => [0b001, 0b010, 0b100, 0b010, 0b001],
+        '>' => [0b100, 0b010, 0b001, 0b010, 0b100],
+        '(' => [0b010, 0b100, 0b100, 0b100, 0b010],
+        ')' => [0b010, 0b001, 0b001, 0b001, 0b010],
+        '[' => [0b110, 0b100, 0b100, 0b100, 0b110],
+        ']' => [0b011, 0b001, 0b001, 0b001, 0b011],
+        '!' => [0b010, 0b010, 0b010, 0b000, 0b010],
+        _ => [0b110, 0b001, 0b010, 0b000, 0b010] // '?'
+    }
+}
+
+pub fn text_width(text: &str, scale: u32) -> u32 {
+    let length = text.chars().count() as u32;
+    if length == 0 {
+        return 0;
+    }
+    (length * (GLYPH_WIDTH + SPACING) - SPACING) * scale
+}
+
+// Distance from one character to the next, for laying out columns
+pub fn char_advance(scale: u32) -> u32 {
+    (GLYPH_WIDTH + SPACING) * scale
+}
+
+pub fn text_height(scale: u32) -> u32 {
+    GLYPH_HEIGHT * scale
+}
+
+pub fn draw_text(canvas: &mut Canvas<Window>, text: &str, x: i32, y: i32, scale: u32, color: Color) {
+    let mut rects = Vec::new();
+    for (index, character) in text.chars().enumerate() {
+        let left = x + (index as u32 * (GLYPH_WIDTH + SPACING) * scale) as i32;
+        for (row, bits) in glyph(character).iter().enumerate() {
+            for column in 0..GLYPH_WIDTH {
+                if bits & (0b100 >> column) != 0 {
+                    let pixel_x = left + (column * scale) as i32;
+                    let pixel_y = y + (row as u32 * scale) as i32;
+                    rects.push(Rect::new(pixel_x, pixel_y, scale, scale));
+                }
+            }
+        }
+    }
+    canvas.set_draw_color(color);
+    let _ = canvas.fill_rects(&rects);
+}
+
+// Text on a dark box with a small margin, so it stays readable over the game
+pub fn draw_label(canvas: &mut Canvas<Window>, text: &str, x: i32, y: i32, scale: u32, color: Color) {
+    let margin = scale as i32 * 2;
+    let width = text_width(text, scale) + margin as u32 * 2;
+    let height = text_height(scale) + margin as u32 * 2;
+    canvas.set_draw_color(Color::RGB(0,0,0));
+    let _ = canvas.fill_rect(Rect::new(x, y, width, height));
+    draw_text(canvas, text, x + margin, y + margin, scale, color);
+}